@@ -0,0 +1,106 @@
+use std::{io::Write, path::{Path, PathBuf}};
+
+use lazy_static::lazy_static;
+
+use crate::{Protocol, Credentials, Configuration, management::Management};
+
+lazy_static! {
+    pub static ref HOME: PathBuf = home::home_dir().unwrap();
+}
+
+/// Where the protocol's service reads its config from.
+pub fn config_path(protocol: &Protocol) -> String {
+    format!("{}/.octovpn/{}/{}", HOME.to_str().unwrap(), protocol.to_string().to_lowercase(), if protocol == &Protocol::OpenVPN { "config.ovpn" } else { "wireguard.conf" })
+}
+
+/// Rewrites the config file with necessary additions.
+/// This should've been done API-side, but it is what it is.
+pub fn patch_config(protocol: &Protocol, mut config: String, credentials: Option<Credentials>, management: &Management) -> bool {
+    let path = config_path(protocol);
+
+    if protocol == &Protocol::OpenVPN {
+        // Patch config with credentials, certificate-only configs don't have any
+        if let Some(credentials) = credentials {
+            config.push_str(format!("\n\n<auth-user-pass>\n{}\n{}\n</auth-user-pass>", credentials.username, credentials.password.expose()).as_str());
+        }
+
+        // Windows-specific fix: remove these parameters as they break the CLI, for some reason
+        let parameters = vec!["route-method exe", "route-delay 2", "register-dns"];
+        for param in parameters {
+            config = config.replace(param, format!("# {}", param).as_str());
+        }
+
+        // Add management interface and reduce verbosity
+        let directive = match management.prepare() {
+            Some(directive) => directive,
+            None => return false
+        };
+        config.push_str(format!("\n\n{}\nverb 2", directive).as_str());
+    }
+
+    write_private(path, config).is_ok()
+}
+
+/// Writes a file only the user gets to read, for anything holding credentials or keys. Created that
+/// way so it's never readable by others, even briefly, and tightened if an older version created it.
+/// On Windows it inherits the profile directory's permissions, which only let the user, administrators
+/// and SYSTEM in.
+pub fn write_private<P: AsRef<Path>, C: AsRef<[u8]>>(path: P, contents: C) -> std::io::Result<()> {
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+
+    let mut file = options.open(path)?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        file.set_permissions(std::fs::Permissions::from_mode(0o600))?;
+    }
+
+    file.write_all(contents.as_ref())
+}
+
+pub fn write_config(config: &Configuration) {
+    let home = HOME.to_str().unwrap();
+    let path = format!("{}/.octovpn/config.json", home);
+
+    // Holds the credentials
+    write_private(path, serde_json::to_string(config).unwrap()).unwrap();
+}
+
+pub fn read_config() -> Configuration {
+    let home = HOME.to_str().unwrap();
+    let path = format!("{}/.octovpn/config.json", home);
+
+    if !std::path::Path::new(&path).exists() {
+        return Configuration::default();
+    }
+
+    let config = std::fs::read_to_string(path).unwrap();
+    serde_json::from_str(config.as_str()).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(unix)]
+    #[test]
+    fn writes_private_files_for_the_user_only() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let path = std::env::temp_dir().join(format!("octovpn-private-{:016x}", rand::random::<u64>()));
+        // Left readable by an older version
+        std::fs::write(&path, "old contents that are longer").unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o644)).unwrap();
+
+        write_private(&path, "secret").unwrap();
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        let contents = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(mode & 0o777, 0o600);
+        assert_eq!(contents, "secret");
+    }
+}
@@ -0,0 +1,305 @@
+use std::path::{Path, PathBuf};
+
+use serde::{Serialize, Deserialize};
+
+use crate::{Protocol, Credentials, Location, config::{HOME, write_private}};
+
+/// How an imported config authenticates against its server.
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum AuthMode {
+    /// Certificates or keys only, nothing to add
+    None,
+    /// Uses the OctoVPN account credentials
+    Account,
+    /// Uses credentials stored alongside the config
+    Custom { credentials: Credentials }
+}
+
+/// A user-imported OpenVPN or WireGuard config.
+/// The metadata lives in `~/.octovpn/profiles/{id}.json`, the config next to it.
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+pub struct CustomConfig {
+    pub id: String,
+    pub name: String,
+    pub protocol: Protocol,
+    pub source: String,
+    pub auth: AuthMode,
+    pub remote: Option<String>
+}
+
+#[derive(Debug, Serialize)]
+pub enum ImportError {
+    NotFound,
+    UnknownFormat,
+    InvalidConfig,
+    WriteFailed,
+}
+
+fn profiles_dir() -> PathBuf {
+    HOME.join(".octovpn").join("profiles")
+}
+
+fn extension(protocol: &Protocol) -> &'static str {
+    match protocol {
+        Protocol::OpenVPN => "ovpn",
+        Protocol::WireGuard => "conf"
+    }
+}
+
+/// Guesses the protocol from the file contents, falling back to the extension.
+/// OpenVPN configs are sometimes shipped as `.conf` too.
+fn detect_protocol(path: &Path, contents: &str) -> Option<Protocol> {
+    if contents.contains("[Interface]") && contents.contains("[Peer]") {
+        return Some(Protocol::WireGuard);
+    }
+
+    if contents.lines().any(|line| line.trim_start().starts_with("remote ")) {
+        return Some(Protocol::OpenVPN);
+    }
+
+    match path.extension().and_then(|ext| ext.to_str()) {
+        Some("ovpn") => Some(Protocol::OpenVPN),
+        _ => None
+    }
+}
+
+/// Finds the server address, used to show the config like an API location.
+fn detect_remote(protocol: &Protocol, contents: &str) -> Option<String> {
+    contents.lines()
+        .map(|line| line.trim())
+        .find_map(|line| match protocol {
+            Protocol::OpenVPN => line.strip_prefix("remote ")
+                .and_then(|remote| remote.split_whitespace().next()),
+            Protocol::WireGuard => line.strip_prefix("Endpoint")
+                .and_then(|endpoint| endpoint.trim_start().strip_prefix('='))
+                .map(|endpoint| endpoint.trim())
+                .map(|endpoint| endpoint.rsplit_once(':').map(|(host, _)| host).unwrap_or(endpoint))
+        })
+        .map(|remote| remote.trim_matches(|c| c == '[' || c == ']').to_string())
+}
+
+/// Inlines files referenced by relative path (`ca ca.crt`, `tls-auth ta.key 1`, ...),
+/// as the config is copied away from them.
+fn inline_files(contents: &str, dir: &Path) -> Result<String, ImportError> {
+    let directives = ["ca", "cert", "key", "tls-auth", "tls-crypt", "tls-crypt-v2"];
+    let mut config = String::new();
+
+    for line in contents.lines() {
+        let mut split = line.split_whitespace();
+        let directive = split.next().unwrap_or_default();
+        let file = split.next();
+
+        match file {
+            Some(file) if directives.contains(&directive) && file != "[inline]" => {
+                let inlined = std::fs::read_to_string(dir.join(file))
+                    .map_err(|_| ImportError::NotFound)?;
+
+                config.push_str(format!("<{}>\n{}\n</{}>\n", directive, inlined.trim(), directive).as_str());
+
+                // `tls-auth ta.key 1` keeps its direction as a separate directive
+                if let Some(direction) = split.next() {
+                    config.push_str(format!("key-direction {}\n", direction).as_str());
+                }
+            },
+            _ => {
+                config.push_str(line);
+                config.push('\n');
+            }
+        }
+    }
+
+    Ok(config)
+}
+
+/// Turns the name into a file-safe id, making sure it doesn't clash with another config.
+fn unique_id(name: &str) -> String {
+    let slug = name.to_lowercase()
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '-' })
+        .collect::<String>()
+        .trim_matches('-')
+        .to_string();
+    let slug = if slug.is_empty() { String::from("config") } else { slug };
+
+    let mut id = slug.clone();
+    let mut n = 2;
+    while profiles_dir().join(format!("{}.json", id)).exists() {
+        id = format!("{}-{}", slug, n);
+        n += 1;
+    }
+
+    id
+}
+
+/// Copies an `.ovpn` or `.conf` file into the profile store.
+/// If no credentials are given, OpenVPN configs asking for them use the account credentials.
+pub fn import(path: &str, name: Option<String>, credentials: Option<Credentials>) -> Result<CustomConfig, ImportError> {
+    let source = Path::new(path);
+    let contents = std::fs::read_to_string(source).map_err(|_| ImportError::NotFound)?;
+
+    let protocol = detect_protocol(source, &contents).ok_or(ImportError::UnknownFormat)?;
+    let remote = detect_remote(&protocol, &contents);
+    if remote.is_none() {
+        return Err(ImportError::InvalidConfig);
+    }
+
+    let contents = match protocol {
+        Protocol::OpenVPN => inline_files(&contents, source.parent().unwrap_or(Path::new(".")))?,
+        Protocol::WireGuard => contents
+    };
+
+    let needs_auth = protocol == Protocol::OpenVPN
+        && contents.lines().any(|line| line.trim_start().starts_with("auth-user-pass"));
+    let auth = match (needs_auth, credentials) {
+        (false, _) => AuthMode::None,
+        (true, Some(credentials)) => AuthMode::Custom { credentials },
+        (true, None) => AuthMode::Account
+    };
+
+    let name = name.unwrap_or_else(|| source.file_stem()
+        .map(|stem| stem.to_string_lossy().to_string())
+        .unwrap_or_default());
+
+    std::fs::create_dir_all(profiles_dir()).map_err(|_| ImportError::WriteFailed)?;
+
+    let config = CustomConfig {
+        id: unique_id(&name),
+        name,
+        protocol,
+        source: path.to_string(),
+        auth,
+        remote
+    };
+
+    let dir = profiles_dir();
+    // The config may hold inline keys, the metadata the credentials
+    write_private(dir.join(format!("{}.{}", config.id, extension(&protocol))), contents)
+        .map_err(|_| ImportError::WriteFailed)?;
+    write_private(dir.join(format!("{}.json", config.id)), serde_json::to_string(&config).unwrap())
+        .map_err(|_| ImportError::WriteFailed)?;
+
+    log::info!("Imported {} config {} from {}", config.protocol, config.id, config.source);
+
+    Ok(config)
+}
+
+/// Lists every imported config, skipping any with unreadable metadata.
+pub fn list() -> Vec<CustomConfig> {
+    let entries = match std::fs::read_dir(profiles_dir()) {
+        Ok(entries) => entries,
+        Err(_) => return vec![]
+    };
+
+    let mut configs = entries
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| path.extension().and_then(|ext| ext.to_str()) == Some("json"))
+        .filter_map(|path| std::fs::read_to_string(path).ok())
+        .filter_map(|metadata| serde_json::from_str::<CustomConfig>(&metadata).ok())
+        .collect::<Vec<CustomConfig>>();
+
+    configs.sort_by(|a, b| a.name.cmp(&b.name));
+    configs
+}
+
+pub fn get(id: &str) -> Option<CustomConfig> {
+    list().into_iter().find(|config| config.id == id)
+}
+
+/// Reads the stored config file, ready to go through `patch_config`.
+pub fn read(config: &CustomConfig) -> Option<String> {
+    std::fs::read_to_string(profiles_dir().join(format!("{}.{}", config.id, extension(&config.protocol)))).ok()
+}
+
+pub fn remove(id: &str) -> bool {
+    let config = match get(id) {
+        Some(config) => config,
+        None => return false
+    };
+
+    let dir = profiles_dir();
+    let _ = std::fs::remove_file(dir.join(format!("{}.{}", config.id, extension(&config.protocol))));
+    std::fs::remove_file(dir.join(format!("{}.json", config.id))).is_ok()
+}
+
+impl CustomConfig {
+    /// Resolves the credentials to patch into the config.
+    pub fn credentials(&self, account: Option<Credentials>) -> Option<Credentials> {
+        match &self.auth {
+            AuthMode::None => None,
+            AuthMode::Account => account,
+            AuthMode::Custom { credentials } => Some(credentials.clone())
+        }
+    }
+
+    /// Presents the config like an API location, so it can be listed alongside them.
+    pub fn location(&self) -> Location {
+        Location {
+            city: self.name.clone(),
+            config: None,
+            country: String::from("Custom"),
+            country_code: String::from("xx"),
+            wireguard: self.protocol == Protocol::WireGuard,
+            id: 0,
+            ip: self.remote.clone().unwrap_or_default(),
+            name: self.name.clone(),
+            status: true,
+            custom: Some(self.id.clone())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OPENVPN: &str = "client\ndev tun\nproto udp\nremote vpn.example.com 1194\nauth-user-pass\n";
+    const WIREGUARD: &str = "[Interface]\nPrivateKey = cHJpdmF0ZQ==\nAddress = 10.64.0.2/32\n\n[Peer]\nPublicKey = cHVibGlj\nEndpoint = [2001:db8::7]:51820\n";
+
+    #[test]
+    fn detects_the_protocol_from_the_contents() {
+        assert_eq!(detect_protocol(Path::new("server.ovpn"), OPENVPN), Some(Protocol::OpenVPN));
+        assert_eq!(detect_protocol(Path::new("server.conf"), OPENVPN), Some(Protocol::OpenVPN));
+        assert_eq!(detect_protocol(Path::new("wg0.conf"), WIREGUARD), Some(Protocol::WireGuard));
+
+        // Only the extension is left to go by
+        assert_eq!(detect_protocol(Path::new("server.ovpn"), "client\n"), Some(Protocol::OpenVPN));
+        assert_eq!(detect_protocol(Path::new("notes.conf"), "client\n"), None);
+    }
+
+    #[test]
+    fn detects_the_remote() {
+        assert_eq!(detect_remote(&Protocol::OpenVPN, OPENVPN), Some(String::from("vpn.example.com")));
+        assert_eq!(detect_remote(&Protocol::WireGuard, WIREGUARD), Some(String::from("2001:db8::7")));
+        assert_eq!(detect_remote(&Protocol::WireGuard, "[Peer]\nEndpoint=192.0.2.10:51820"), Some(String::from("192.0.2.10")));
+        assert_eq!(detect_remote(&Protocol::OpenVPN, "client\ndev tun\n"), None);
+    }
+
+    #[test]
+    fn inlines_referenced_files() {
+        let dir = std::env::temp_dir().join(format!("octovpn-custom-{:016x}", rand::random::<u64>()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("ca.crt"), "-----BEGIN CERTIFICATE-----\nca\n-----END CERTIFICATE-----\n").unwrap();
+        std::fs::write(dir.join("client.crt"), "cert\n").unwrap();
+        std::fs::write(dir.join("client.key"), "key\n").unwrap();
+        std::fs::write(dir.join("ta.key"), "tls-auth\n").unwrap();
+
+        let config = inline_files("client\nca ca.crt\ncert client.crt\nkey client.key\ntls-auth ta.key 1\n", &dir);
+        let missing = inline_files("client\nca missing.crt\n", &dir);
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(config.unwrap(), "client\n\
+            <ca>\n-----BEGIN CERTIFICATE-----\nca\n-----END CERTIFICATE-----\n</ca>\n\
+            <cert>\ncert\n</cert>\n\
+            <key>\nkey\n</key>\n\
+            <tls-auth>\ntls-auth\n</tls-auth>\nkey-direction 1\n");
+        assert!(matches!(missing, Err(ImportError::NotFound)));
+    }
+
+    #[test]
+    fn keeps_inline_blocks() {
+        let config = "ca [inline]\n<ca>\nca\n</ca>\n";
+        assert_eq!(inline_files(config, Path::new("/nonexistent")).unwrap(), config);
+    }
+}
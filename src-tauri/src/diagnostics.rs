@@ -0,0 +1,210 @@
+use std::{fs::File, io::{Read, Seek, SeekFrom}};
+
+use serde::Serialize;
+use serde_repr::Serialize_repr;
+
+use crate::config::HOME;
+
+// Reads the OpenVPN log to explain why a connection failed,
+// since the management interface is gone by the time we'd ask it.
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize_repr)]
+#[repr(u8)]
+pub enum Cause {
+    AuthFailed,
+    TlsHandshake,
+    TapMissing,
+    CipherMismatch,
+    ResolveFailed
+}
+
+/// A known failure found in the log.
+#[derive(Debug, PartialEq, Eq, Clone, Serialize)]
+pub struct Diagnostic {
+    pub cause: Cause,
+    pub message: String,
+    pub fix: String,
+    /// The log line it was found in
+    pub line: String
+}
+
+/// Handshakes failing in a row after which UDP is assumed to be blocked.
+const FALLBACK_AFTER: u32 = 2;
+
+/// Counts the TLS handshakes timing out in a row, to fall back to TCP 443 once UDP seems blocked.
+#[derive(Debug, Default)]
+pub struct Handshakes {
+    failed: u32
+}
+
+impl Handshakes {
+    /// Follows a log line, returning true once when it's time to fall back.
+    pub fn fall_back(&mut self, line: &str) -> bool {
+        if line.contains("Initialization Sequence Completed") {
+            self.failed = 0;
+        } else if line.contains("TLS handshake failed") {
+            self.failed += 1;
+            return self.failed == FALLBACK_AFTER;
+        }
+
+        false
+    }
+}
+
+/// Both the Windows service (`log_dir`) and the systemd unit (`--log`) write here.
+pub fn log_path() -> String {
+    format!("{}/.octovpn/openvpn/logs/config.log", HOME.to_str().unwrap())
+}
+
+/// Matches a log line against the failures we know about.
+pub fn classify(line: &str) -> Option<Diagnostic> {
+    let (cause, message, fix) = if line.contains("AUTH_FAILED") {
+        (Cause::AuthFailed, "The server rejected your credentials", "Check your username and password, or log out and back in")
+    } else if line.contains("TLS handshake failed") || line.contains("TLS key negotiation failed") {
+        (Cause::TlsHandshake, "The server didn't answer the TLS handshake", "Try another location, or switch to TCP if UDP is blocked on this network")
+    } else if line.contains("There are no TAP-Windows adapters") || line.contains("All TAP-Windows adapters") || line.contains("Cannot open TUN/TAP dev") {
+        (Cause::TapMissing, "No network adapter is available for the tunnel", if cfg!(windows) {
+            "Repair the OpenVPN service in the settings to reinstall the TAP adapter"
+        } else {
+            "Make sure the `tun` kernel module is loaded (`modprobe tun`)"
+        })
+    } else if line.contains("failed to negotiate cipher") || line.contains("cipher final failed") || line.contains("no shared cipher") {
+        (Cause::CipherMismatch, "The client and server couldn't agree on a cipher", "Update OpenVPN, or re-download the config if it was imported")
+    } else if line.contains("Cannot resolve host address") {
+        (Cause::ResolveFailed, "The server's address couldn't be resolved", "Check your internet connection and DNS settings")
+    } else {
+        return None;
+    };
+
+    Some(Diagnostic {
+        cause,
+        message: message.to_string(),
+        fix: fix.to_string(),
+        line: line.trim().to_string()
+    })
+}
+
+/// Follows a log file, like `tail -f`.
+/// OpenVPN truncates it on every start, so that starts over from the beginning.
+pub struct Tail {
+    path: String,
+    position: u64,
+    /// The end of a line that isn't fully written yet
+    partial: String
+}
+
+impl Tail {
+    /// Starts at the current end of the file, an old session isn't worth reporting.
+    pub fn new(path: String) -> Self {
+        let position = std::fs::metadata(&path).map(|metadata| metadata.len()).unwrap_or(0);
+        Tail { path, position, partial: String::new() }
+    }
+
+    /// Whether the file started over since the last read.
+    pub fn truncated(&self) -> bool {
+        std::fs::metadata(&self.path).map(|metadata| metadata.len() < self.position).unwrap_or(false)
+    }
+
+    /// Returns the lines written since the last call.
+    pub fn read(&mut self) -> Vec<String> {
+        if self.truncated() {
+            self.position = 0;
+            self.partial.clear();
+        }
+
+        let mut file = match File::open(&self.path) {
+            Ok(file) => file,
+            Err(_) => return vec![]
+        };
+
+        let mut bytes = vec![];
+        if file.seek(SeekFrom::Start(self.position)).is_err() || file.read_to_end(&mut bytes).is_err() {
+            return vec![];
+        }
+        self.position += bytes.len() as u64;
+
+        self.partial.push_str(&String::from_utf8_lossy(&bytes));
+        let complete = match self.partial.rfind('\n') {
+            Some(end) => self.partial.drain(..=end).collect::<String>(),
+            None => return vec![]
+        };
+
+        complete.lines().map(|line| line.to_string()).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY_NEGOTIATION: &str = "2024-01-01 12:00:00 TLS Error: TLS key negotiation failed to occur within 60 seconds (check your network connectivity)";
+    const HANDSHAKE: &str = "2024-01-01 12:00:00 TLS Error: TLS handshake failed";
+
+    fn cause(line: &str) -> Option<Cause> {
+        classify(line).map(|diagnostic| diagnostic.cause)
+    }
+
+    #[test]
+    fn classifies_rejected_credentials() {
+        let diagnostic = classify("2024-01-01 12:00:00 AUTH: Received control message: AUTH_FAILED\n").unwrap();
+
+        assert_eq!(diagnostic.cause, Cause::AuthFailed);
+        assert_eq!(diagnostic.line, "2024-01-01 12:00:00 AUTH: Received control message: AUTH_FAILED");
+    }
+
+    #[test]
+    fn classifies_failed_handshakes() {
+        assert_eq!(cause(KEY_NEGOTIATION), Some(Cause::TlsHandshake));
+        assert_eq!(cause(HANDSHAKE), Some(Cause::TlsHandshake));
+    }
+
+    #[test]
+    fn classifies_missing_adapters() {
+        assert_eq!(cause("2024-01-01 12:00:00 There are no TAP-Windows adapters on this system."), Some(Cause::TapMissing));
+        assert_eq!(cause("2024-01-01 12:00:00 All TAP-Windows adapters on this system are currently in use."), Some(Cause::TapMissing));
+        assert_eq!(cause("2024-01-01 12:00:00 ERROR: Cannot open TUN/TAP dev /dev/net/tun: No such file or directory (errno=2)"), Some(Cause::TapMissing));
+    }
+
+    #[test]
+    fn classifies_cipher_mismatches() {
+        assert_eq!(cause("2024-01-01 12:00:00 ERROR: failed to negotiate cipher with server."), Some(Cause::CipherMismatch));
+        assert_eq!(cause("2024-01-01 12:00:00 OpenSSL: error:0A0000C1:SSL routines::no shared cipher"), Some(Cause::CipherMismatch));
+    }
+
+    #[test]
+    fn classifies_unresolved_hosts() {
+        assert_eq!(
+            cause("2024-01-01 12:00:00 RESOLVE: Cannot resolve host address: vpn.example.com:1194 (Name or service not known)"),
+            Some(Cause::ResolveFailed)
+        );
+    }
+
+    #[test]
+    fn ignores_other_lines() {
+        assert_eq!(cause("2024-01-01 12:01:00 Initialization Sequence Completed"), None);
+        assert_eq!(cause("2024-01-01 12:00:02 SIGUSR1[soft,tls-error] received, process restarting"), None);
+    }
+
+    #[test]
+    fn falls_back_once_after_repeated_handshake_failures() {
+        let mut handshakes = Handshakes::default();
+
+        assert!(!handshakes.fall_back(KEY_NEGOTIATION));
+        assert!(!handshakes.fall_back(HANDSHAKE));
+        assert!(!handshakes.fall_back("2024-01-01 12:00:02 SIGUSR1[soft,tls-error] received, process restarting"));
+        assert!(handshakes.fall_back(HANDSHAKE));
+
+        // Already falling back, more failures don't ask again
+        assert!(!handshakes.fall_back(HANDSHAKE));
+    }
+
+    #[test]
+    fn connecting_resets_the_count() {
+        let mut handshakes = Handshakes::default();
+
+        assert!(!handshakes.fall_back(HANDSHAKE));
+        assert!(!handshakes.fall_back("2024-01-01 12:01:00 Initialization Sequence Completed"));
+        assert!(!handshakes.fall_back(HANDSHAKE));
+        assert!(handshakes.fall_back(HANDSHAKE));
+    }
+}
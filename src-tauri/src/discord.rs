@@ -0,0 +1,504 @@
+use std::{any::Any, path::PathBuf, sync::{Mutex, mpsc::{channel, Receiver, RecvTimeoutError, Sender}}, thread, time::{Duration, Instant, SystemTime, UNIX_EPOCH}};
+
+use lazy_static::lazy_static;
+use serde::{Serialize, Deserialize};
+use serde_repr::{Serialize_repr, Deserialize_repr};
+use time::{OffsetDateTime, macros::format_description};
+
+use crate::{Configuration, CONFIGURATION, DESTINATION, PROTOCOL_CONNECTED, Destination, Protocol, Status, discord_ipc::{self, Connection}, events::{Event, EventSink, OpenVPNStatus}};
+
+/// Discord only accepts an activity update every 15 seconds.
+const RATE_LIMIT: Duration = Duration::from_secs(15);
+
+lazy_static! {
+    static ref UPDATES: Mutex<Option<Sender<Update>>> = Mutex::new(None);
+}
+
+/// How much of the connection the presence gives away.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize_repr, Deserialize_repr)]
+#[repr(u8)]
+pub enum Privacy {
+    Full,
+    /// Location, city and country are left out
+    HideLocation,
+    /// Only ever shows "Protected", ignoring the templates
+    ProtectedOnly
+}
+
+/// Templates for the presence lines. Placeholders are `{location}`, `{city}`, `{country}`,
+/// `{protocol}` and `{since}`, the time the connection started (UTC).
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PresenceConfig {
+    /// Discord application id, a string since it doesn't fit in a JavaScript number
+    #[serde(rename = "clientId")]
+    pub client_id: String,
+    pub details: String,
+    pub state: String,
+    /// Shown when hovering the icon
+    #[serde(rename = "largeText")]
+    pub large_text: String,
+    /// Replaces the details while not connected
+    pub disconnected: String,
+    pub privacy: Privacy,
+    /// Shows the time elapsed since connecting
+    #[serde(rename = "showElapsed")]
+    pub show_elapsed: bool
+}
+
+impl Default for PresenceConfig {
+    fn default() -> Self {
+        PresenceConfig {
+            client_id: String::from("743953368518492190"),
+            details: String::from("Connected to {location}"),
+            state: String::from("via {protocol}"),
+            large_text: String::from("{city}, {country}"),
+            disconnected: String::from("Not connected"),
+            privacy: Privacy::Full,
+            show_elapsed: true
+        }
+    }
+}
+
+/// What the presence is rendered from.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Context {
+    pub status: Status,
+    pub protocol: Option<Protocol>,
+    pub destination: Option<Destination>,
+    /// Unix timestamp of when the connection started
+    pub since: Option<u64>
+}
+
+/// A rendered presence, ready to be sent.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Activity {
+    pub details: String,
+    pub state: Option<String>,
+    pub large_text: Option<String>,
+    pub start: Option<u64>
+}
+
+/// Sent to the Discord thread when something shown in the presence changed.
+#[derive(Debug, Clone)]
+pub enum Update {
+    Status(Status),
+    /// The connection start reported by OpenVPN, more accurate than when we saw it
+    Since(u64),
+    /// The settings changed
+    Settings {
+        enabled: bool,
+        presence: PresenceConfig
+    },
+    /// Clears the presence and stops the thread
+    Shutdown
+}
+
+/// How the Discord thread finds Discord, and how patient it is with it.
+pub struct Options {
+    /// Sockets to try, in order
+    pub paths: Vec<PathBuf>,
+    pub rate_limit: Duration,
+    /// First delay between connection attempts, doubled after every failure
+    pub backoff: Duration,
+    pub max_backoff: Duration,
+    /// How often an idle connection is checked, so a Discord restart is noticed
+    pub ping_interval: Duration
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            paths: discord_ipc::socket_paths(),
+            rate_limit: RATE_LIMIT,
+            backoff: Duration::from_secs(2),
+            max_backoff: Duration::from_secs(60),
+            ping_interval: Duration::from_secs(30)
+        }
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+}
+
+fn fill(template: &str, context: &Context, privacy: Privacy) -> String {
+    let hidden = privacy != Privacy::Full;
+    let destination = context.destination.as_ref();
+
+    let location = destination.map(|d| d.name.as_str()).filter(|_| !hidden).unwrap_or("a private location");
+    let city = destination.and_then(|d| d.city.as_deref()).filter(|_| !hidden).unwrap_or("Somewhere");
+    let country = destination.map(|d| d.country.as_str()).filter(|_| !hidden).unwrap_or("Earth");
+    let protocol = context.protocol.map(|p| p.to_string()).unwrap_or_default();
+    let since = context.since
+        .and_then(|since| OffsetDateTime::from_unix_timestamp(since as i64).ok())
+        .and_then(|since| since.format(format_description!("[hour]:[minute]")).ok())
+        .unwrap_or_default();
+
+    template
+        .replace("{location}", location)
+        .replace("{city}", city)
+        .replace("{country}", country)
+        .replace("{protocol}", &protocol)
+        .replace("{since}", &since)
+}
+
+/// Renders the templates for the current connection.
+pub fn render(config: &PresenceConfig, context: &Context) -> Activity {
+    let start = context.since.filter(|_| config.show_elapsed && context.status == Status::Connected);
+
+    if context.status != Status::Connected {
+        let details = match context.status {
+            Status::Connecting => String::from("Connecting..."),
+            Status::Disconnecting => String::from("Disconnecting..."),
+            _ => config.disconnected.clone()
+        };
+
+        return Activity { details, state: None, large_text: None, start: None };
+    }
+
+    if config.privacy == Privacy::ProtectedOnly {
+        return Activity { details: String::from("Protected"), state: None, large_text: None, start };
+    }
+
+    // Discord rejects empty lines, so those are left out
+    let line = |template: &str| Some(fill(template, context, config.privacy)).filter(|line| !line.trim().is_empty());
+
+    Activity {
+        details: line(&config.details).unwrap_or(String::from("Protected")),
+        state: line(&config.state),
+        large_text: line(&config.large_text),
+        start
+    }
+}
+
+/// Sends an update to the Discord thread, if it runs.
+pub fn notify(update: Update) {
+    if let Some(sender) = UPDATES.lock().unwrap().as_ref() {
+        let _ = sender.send(update);
+    }
+}
+
+/// Sends the current settings to the Discord thread.
+pub fn configure(config: &Configuration) {
+    notify(Update::Settings { enabled: config.discord_rpc, presence: config.discord_presence.clone() });
+}
+
+/// Forwards the events the presence depends on.
+#[derive(Clone, Copy)]
+pub struct Presence;
+
+impl EventSink for Presence {
+    fn emit<E: Event>(&self, event: E) {
+        let event = &event as &dyn Any;
+
+        if let Some(status) = event.downcast_ref::<Status>() {
+            notify(Update::Status(*status));
+        } else if let Some(OpenVPNStatus(Some(state))) = event.downcast_ref::<OpenVPNStatus>() {
+            // Reconnecting isn't when the connection started
+            if state.connected {
+                notify(Update::Since(state.start));
+            }
+        }
+    }
+}
+
+/// State of the Discord thread. The connection only exists while enabled and Discord runs.
+struct Rpc {
+    options: Options,
+    enabled: bool,
+    presence: PresenceConfig,
+    context: Context,
+    connection: Option<Connection>,
+    /// When to try connecting again
+    retry_at: Instant,
+    backoff: Duration,
+    /// The presence changed since it was last sent
+    pending: bool,
+    last_sent: Option<Instant>,
+    /// Last time Discord answered
+    last_seen: Instant
+}
+
+impl Rpc {
+    fn new(options: Options) -> Self {
+        Rpc {
+            backoff: options.backoff,
+            options,
+            enabled: false,
+            presence: PresenceConfig::default(),
+            context: Context { status: Status::Disconnected, protocol: None, destination: None, since: None },
+            connection: None,
+            retry_at: Instant::now(),
+            pending: true,
+            last_sent: None,
+            last_seen: Instant::now()
+        }
+    }
+
+    fn apply(&mut self, update: Update) {
+        match update {
+            Update::Status(status) => {
+                match status {
+                    Status::Connected if self.context.since.is_none() => self.context.since = Some(now()),
+                    Status::Disconnected => self.context.since = None,
+                    _ => {}
+                }
+                self.context.status = status;
+            },
+            // Only while connected, OpenVPN keeps reporting it until it is gone
+            Update::Since(since) => {
+                if self.context.status != Status::Connected || self.context.since == Some(since) {
+                    return;
+                }
+                self.context.since = Some(since);
+            },
+            Update::Settings { enabled, presence } => {
+                // The presence belongs to the application, so a new one needs a new connection
+                if presence.client_id != self.presence.client_id {
+                    self.disconnect();
+                }
+
+                if enabled && !self.enabled {
+                    self.retry_at = Instant::now();
+                    self.backoff = self.options.backoff;
+                }
+
+                self.enabled = enabled;
+                self.presence = presence;
+            },
+            Update::Shutdown => {}
+        }
+
+        self.pending = true;
+    }
+
+    /// How long until the rate limit lets the next activity through.
+    fn send_in(&self) -> Duration {
+        self.last_sent.map_or(Duration::ZERO, |sent| self.options.rate_limit.saturating_sub(sent.elapsed()))
+    }
+
+    /// How long to wait for an update before there is something to do.
+    fn timeout(&self) -> Duration {
+        // Disabled, there is nothing to do once the connection is closed
+        if !self.enabled {
+            return if self.connection.is_some() { Duration::ZERO } else { Duration::from_secs(3600) };
+        }
+
+        if self.connection.is_none() {
+            return self.retry_at.saturating_duration_since(Instant::now());
+        }
+
+        let ping = self.options.ping_interval.saturating_sub(self.last_seen.elapsed());
+        if self.pending { ping.min(self.send_in()) } else { ping }
+    }
+
+    fn step(&mut self) {
+        if !self.enabled {
+            self.disconnect();
+            return;
+        }
+
+        if self.connection.is_none() {
+            if Instant::now() < self.retry_at {
+                return;
+            }
+
+            match Connection::open(&self.options.paths, &self.presence.client_id) {
+                Ok(connection) => {
+                    log::info!("Connected to Discord");
+                    self.connection = Some(connection);
+                    self.backoff = self.options.backoff;
+                    self.pending = true;
+                    self.last_sent = None;
+                    self.last_seen = Instant::now();
+                },
+                Err(e) => {
+                    log::debug!("Discord is not available ({}), retrying in {:?}", e, self.backoff);
+                    self.retry_at = Instant::now() + self.backoff;
+                    self.backoff = (self.backoff * 2).min(self.options.max_backoff);
+                    return;
+                }
+            }
+        }
+
+        let result = if self.pending && self.send_in().is_zero() {
+            self.context.protocol = *PROTOCOL_CONNECTED.lock().unwrap();
+            self.context.destination = DESTINATION.lock().unwrap().clone();
+            let activity = render(&self.presence, &self.context);
+
+            let connection = self.connection.as_mut().unwrap();
+            connection.set_activity(Some(&activity)).map(|_| {
+                self.pending = false;
+                self.last_sent = Some(Instant::now());
+            })
+        } else if self.last_seen.elapsed() >= self.options.ping_interval {
+            self.connection.as_mut().unwrap().ping()
+        } else {
+            return;
+        };
+
+        match result {
+            Ok(()) => self.last_seen = Instant::now(),
+            Err(e) => {
+                // Discord quit or restarted, the activity is sent again once it's back
+                log::warn!("Lost the connection to Discord: {}", e);
+                self.connection = None;
+                self.retry_at = Instant::now();
+                self.pending = true;
+            }
+        }
+    }
+
+    /// Clears the presence and closes the connection.
+    fn disconnect(&mut self) {
+        if let Some(mut connection) = self.connection.take() {
+            let _ = connection.set_activity(None);
+            connection.close();
+            log::info!("Disconnected from Discord");
+        }
+    }
+}
+
+fn run(updates: Receiver<Update>, options: Options) {
+    let mut rpc = Rpc::new(options);
+
+    loop {
+        // Wait for something to change, or until a send, reconnect or ping is due
+        match updates.recv_timeout(rpc.timeout()) {
+            Ok(Update::Shutdown) | Err(RecvTimeoutError::Disconnected) => break,
+            Ok(update) => {
+                rpc.apply(update);
+                continue;
+            },
+            Err(RecvTimeoutError::Timeout) => {}
+        }
+
+        rpc.step();
+    }
+
+    rpc.disconnect();
+}
+
+/// Spawns a Discord thread, which does nothing until it gets the settings.
+pub fn spawn(options: Options) -> (Sender<Update>, thread::JoinHandle<()>) {
+    let (sender, receiver) = channel();
+    let handle = thread::spawn(move || run(receiver, options));
+
+    (sender, handle)
+}
+
+/// Starts the Discord thread, which updates the presence whenever it's notified.
+pub fn start() -> thread::JoinHandle<()> {
+    let (sender, handle) = spawn(Options::default());
+
+    if PROTOCOL_CONNECTED.lock().unwrap().is_some() {
+        let _ = sender.send(Update::Status(Status::Connected));
+    }
+
+    *UPDATES.lock().unwrap() = Some(sender);
+    configure(&CONFIGURATION.lock().unwrap());
+
+    handle
+}
+
+/// Clears the presence and waits for the thread to stop.
+pub fn stop(handle: thread::JoinHandle<()>) {
+    notify(Update::Shutdown);
+    let _ = handle.join();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn connected() -> Context {
+        Context {
+            status: Status::Connected,
+            protocol: Some(Protocol::WireGuard),
+            destination: Some(Destination {
+                name: String::from("Amsterdam #3"),
+                city: Some(String::from("Amsterdam")),
+                country: String::from("Netherlands"),
+                ip: Some(String::from("198.51.100.7")),
+                country_code: Some(String::from("NL"))
+            }),
+            // 2023-11-14 22:13:20 UTC
+            since: Some(1700000000)
+        }
+    }
+
+    fn config(privacy: Privacy) -> PresenceConfig {
+        PresenceConfig {
+            details: String::from("{location} ({city}, {country})"),
+            state: String::from("{protocol} since {since}"),
+            privacy,
+            ..PresenceConfig::default()
+        }
+    }
+
+    /// Every line of the activity, to check nothing shows up anywhere.
+    fn shown(activity: &Activity) -> String {
+        format!("{}\n{}\n{}", activity.details, activity.state.as_deref().unwrap_or_default(), activity.large_text.as_deref().unwrap_or_default())
+    }
+
+    #[test]
+    fn fills_placeholders() {
+        let activity = render(&config(Privacy::Full), &connected());
+
+        assert_eq!(activity, Activity {
+            details: String::from("Amsterdam #3 (Amsterdam, Netherlands)"),
+            state: Some(String::from("WireGuard since 22:13")),
+            large_text: Some(String::from("Amsterdam, Netherlands")),
+            start: Some(1700000000)
+        });
+    }
+
+    #[test]
+    fn keeps_unknown_placeholders_as_written() {
+        let config = PresenceConfig { details: String::from("{location} at {ip}"), state: String::from("{Protocol}"), ..config(Privacy::Full) };
+        let activity = render(&config, &connected());
+
+        // The server address has no placeholder, it's never filled in
+        assert_eq!(activity.details, "Amsterdam #3 at {ip}");
+        assert_eq!(activity.state.as_deref(), Some("{Protocol}"));
+    }
+
+    #[test]
+    fn leaves_out_empty_lines() {
+        let config = PresenceConfig { details: String::from(" "), state: String::from("{since}"), ..config(Privacy::Full) };
+        let activity = render(&config, &Context { since: None, ..connected() });
+
+        assert_eq!(activity.details, "Protected");
+        assert_eq!(activity.state, None);
+        assert_eq!(activity.start, None);
+    }
+
+    #[test]
+    fn hides_what_each_privacy_level_claims() {
+        let context = connected();
+        let hidden = ["Amsterdam", "Netherlands", "198.51.100.7", "NL"];
+
+        let full = shown(&render(&config(Privacy::Full), &context));
+        assert!(full.contains("Amsterdam #3") && full.contains("WireGuard"));
+
+        // The location goes, the protocol stays
+        let activity = render(&config(Privacy::HideLocation), &context);
+        let text = shown(&activity);
+        assert!(hidden.iter().all(|hidden| !text.contains(hidden)), "{}", text);
+        assert_eq!(activity.details, "a private location (Somewhere, Earth)");
+        assert!(text.contains("WireGuard"));
+
+        // Only ever "Protected", whatever the templates say
+        let activity = render(&config(Privacy::ProtectedOnly), &context);
+        assert_eq!(activity, Activity { details: String::from("Protected"), state: None, large_text: None, start: Some(1700000000) });
+    }
+
+    #[test]
+    fn ignores_templates_while_not_connected() {
+        for (status, details) in [(Status::Connecting, "Connecting..."), (Status::Disconnecting, "Disconnecting..."), (Status::Disconnected, "Not connected")] {
+            let activity = render(&config(Privacy::Full), &Context { status, ..connected() });
+            assert_eq!(activity, Activity { details: String::from(details), state: None, large_text: None, start: None });
+        }
+    }
+}
@@ -0,0 +1,270 @@
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+#[cfg(windows)]
+use std::{fs::File, sync::{Arc, mpsc}, thread};
+use std::{io::{self, ErrorKind, Read, Write}, path::PathBuf};
+
+use serde_json::{json, Value};
+
+use crate::discord::Activity;
+
+// Minimal client for Discord's local RPC socket, see
+// https://discord.com/developers/docs/topics/rpc. Every message is a frame:
+// opcode (u32 LE), payload length (u32 LE), then the JSON payload.
+
+pub const HANDSHAKE: u32 = 0;
+pub const FRAME: u32 = 1;
+pub const CLOSE: u32 = 2;
+pub const PING: u32 = 3;
+pub const PONG: u32 = 4;
+
+/// Longest Discord takes to answer before we consider it gone.
+const TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
+
+#[cfg(unix)]
+type Stream = UnixStream;
+#[cfg(windows)]
+type Stream = Pipe;
+
+/// A named pipe with timeouts, which Windows only has for overlapped I/O.
+/// Each read and write runs in its own thread that's given up on after `TIMEOUT`.
+#[cfg(windows)]
+pub struct Pipe {
+    file: Arc<File>,
+    /// A thread is still stuck on the pipe, anything else would wait behind it
+    timed_out: bool
+}
+
+#[cfg(windows)]
+impl Pipe {
+    fn timed<T, F>(&mut self, io: F) -> io::Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&File) -> io::Result<T> + Send + 'static
+    {
+        if self.timed_out {
+            return Err(ErrorKind::TimedOut.into());
+        }
+
+        // The thread keeps the handle until Discord answers or goes away, the connection is dropped either way
+        let file = self.file.clone();
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            let _ = sender.send(io(&file));
+        });
+
+        match receiver.recv_timeout(TIMEOUT) {
+            Ok(result) => result,
+            Err(_) => {
+                self.timed_out = true;
+                Err(ErrorKind::TimedOut.into())
+            }
+        }
+    }
+}
+
+#[cfg(windows)]
+impl Read for Pipe {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        let length = buffer.len();
+        let data = self.timed(move |mut file: &File| {
+            let mut data = vec![0u8; length];
+            let read = file.read(&mut data)?;
+            data.truncate(read);
+            Ok(data)
+        })?;
+
+        buffer[..data.len()].copy_from_slice(&data);
+        Ok(data.len())
+    }
+}
+
+#[cfg(windows)]
+impl Write for Pipe {
+    fn write(&mut self, buffer: &[u8]) -> io::Result<usize> {
+        let data = buffer.to_vec();
+        self.timed(move |mut file: &File| file.write(&data))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+pub struct Connection {
+    stream: Stream,
+    nonce: u64
+}
+
+/// Where Discord may be listening, in order. It takes the first free `discord-ipc-{n}`.
+#[cfg(unix)]
+pub fn socket_paths() -> Vec<PathBuf> {
+    let dirs = ["XDG_RUNTIME_DIR", "TMPDIR", "TMP", "TEMP"].iter()
+        .filter_map(|var| std::env::var(var).ok())
+        .chain([String::from("/tmp")])
+        .map(PathBuf::from)
+        // Flatpak and Snap installs put the socket in their own directory
+        .flat_map(|dir| [dir.clone(), dir.join("app/com.discordapp.Discord"), dir.join("snap.discord")])
+        .collect::<Vec<PathBuf>>();
+
+    (0..10)
+        .flat_map(|n| dirs.iter().map(move |dir| dir.join(format!("discord-ipc-{}", n))))
+        .collect()
+}
+
+#[cfg(windows)]
+pub fn socket_paths() -> Vec<PathBuf> {
+    (0..10).map(|n| PathBuf::from(format!(r"\\?\pipe\discord-ipc-{}", n))).collect()
+}
+
+pub fn write_frame<W: Write>(stream: &mut W, opcode: u32, payload: &Value) -> io::Result<()> {
+    let payload = payload.to_string();
+
+    let mut frame = Vec::with_capacity(8 + payload.len());
+    frame.extend(opcode.to_le_bytes());
+    frame.extend((payload.len() as u32).to_le_bytes());
+    frame.extend(payload.as_bytes());
+
+    stream.write_all(&frame)
+}
+
+pub fn read_frame<R: Read>(stream: &mut R) -> io::Result<(u32, Value)> {
+    let mut header = [0u8; 8];
+    stream.read_exact(&mut header)?;
+
+    let opcode = u32::from_le_bytes(header[0..4].try_into().unwrap());
+    let length = u32::from_le_bytes(header[4..8].try_into().unwrap()) as usize;
+
+    let mut payload = vec![0u8; length];
+    stream.read_exact(&mut payload)?;
+
+    let payload = serde_json::from_slice(&payload).map_err(|_| io::Error::from(ErrorKind::InvalidData))?;
+    Ok((opcode, payload))
+}
+
+fn activity_json(activity: &Activity) -> Value {
+    let mut json = json!({
+        "details": activity.details,
+        "assets": { "large_image": "icon" }
+    });
+
+    if let Some(state) = &activity.state {
+        json["state"] = json!(state);
+    }
+
+    if let Some(text) = &activity.large_text {
+        json["assets"]["large_text"] = json!(text);
+    }
+
+    if let Some(start) = activity.start {
+        json["timestamps"] = json!({ "start": start });
+    }
+
+    json
+}
+
+#[cfg(unix)]
+fn connect(path: &PathBuf) -> io::Result<Stream> {
+    let stream = UnixStream::connect(path)?;
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.set_write_timeout(Some(TIMEOUT))?;
+    Ok(stream)
+}
+
+#[cfg(windows)]
+fn connect(path: &PathBuf) -> io::Result<Stream> {
+    let file = std::fs::OpenOptions::new().read(true).write(true).open(path)?;
+    Ok(Pipe { file: Arc::new(file), timed_out: false })
+}
+
+impl Connection {
+    /// Connects to the first socket that answers the handshake.
+    pub fn open(paths: &[PathBuf], client_id: &str) -> io::Result<Connection> {
+        let mut error = io::Error::from(ErrorKind::NotFound);
+
+        for path in paths {
+            let mut stream = match connect(path) {
+                Ok(stream) => stream,
+                Err(e) => {
+                    error = e;
+                    continue;
+                }
+            };
+
+            // Discord answers with a READY dispatch, or closes with the reason. A socket left by a
+            // crashed client may accept and then fail, the next one can still be Discord.
+            let handshake = write_frame(&mut stream, HANDSHAKE, &json!({ "v": 1, "client_id": client_id }))
+                .and_then(|_| read_frame(&mut stream));
+
+            match handshake {
+                Ok((FRAME, payload)) if payload["evt"] == "READY" => {
+                    log::debug!("Connected to Discord at {}", path.display());
+                    return Ok(Connection { stream, nonce: 0 });
+                },
+                Ok((_, payload)) => {
+                    log::warn!("Discord refused the handshake: {}", payload["message"]);
+                    error = io::Error::from(ErrorKind::ConnectionRefused);
+                },
+                Err(e) => {
+                    log::debug!("No handshake at {}: {}", path.display(), e);
+                    error = e;
+                }
+            }
+        }
+
+        Err(error)
+    }
+
+    /// Sends a command and waits for its reply.
+    fn command(&mut self, command: &str, args: Value) -> io::Result<Value> {
+        self.nonce += 1;
+        write_frame(&mut self.stream, FRAME, &json!({
+            "cmd": command,
+            "args": args,
+            "nonce": self.nonce.to_string()
+        }))?;
+
+        loop {
+            match read_frame(&mut self.stream)? {
+                (CLOSE, _) => return Err(ErrorKind::ConnectionAborted.into()),
+                (PING, payload) => write_frame(&mut self.stream, PONG, &payload)?,
+                (FRAME, payload) if payload["nonce"] == json!(self.nonce.to_string()) => return Ok(payload),
+                // Something we didn't subscribe to
+                _ => {}
+            }
+        }
+    }
+
+    /// Shows the activity, or clears it with `None`.
+    pub fn set_activity(&mut self, activity: Option<&Activity>) -> io::Result<()> {
+        let reply = self.command("SET_ACTIVITY", json!({
+            "pid": std::process::id(),
+            "activity": activity.map(activity_json)
+        }))?;
+
+        // Rejected, but still connected, so sending it again won't help
+        if reply["evt"] == "ERROR" {
+            log::error!("Discord rejected the activity: {}", reply["data"]["message"]);
+        }
+
+        Ok(())
+    }
+
+    /// Checks that Discord is still there.
+    pub fn ping(&mut self) -> io::Result<()> {
+        write_frame(&mut self.stream, PING, &json!({}))?;
+
+        loop {
+            match read_frame(&mut self.stream)? {
+                (PONG, _) => return Ok(()),
+                (CLOSE, _) => return Err(ErrorKind::ConnectionAborted.into()),
+                _ => {}
+            }
+        }
+    }
+
+    /// Tells Discord we're leaving, so it drops the presence right away.
+    pub fn close(mut self) {
+        let _ = write_frame(&mut self.stream, CLOSE, &json!({}));
+    }
+}
@@ -0,0 +1,161 @@
+#[cfg(test)]
+use std::sync::mpsc::{channel, Receiver, Sender};
+
+use serde::Serialize;
+use tauri::Window;
+
+use crate::{Configuration, Status, diagnostics::Diagnostic, management::{OpenVPNState, Bytecount}, multihop::Hops, profile::{Ipv6Mode, Target}, secret::Secret, verify::VerifiedExit, wireguard::PeerStats};
+
+/// A typed payload, sent under its event name.
+pub trait Event: Serialize + Clone + Send + 'static {
+    const NAME: &'static str;
+}
+
+impl Event for Status {
+    const NAME: &'static str = "status";
+}
+
+/// The latest OpenVPN state, `None` when the management interface is unreachable.
+#[derive(Debug, Clone, Serialize)]
+#[serde(transparent)]
+pub struct OpenVPNStatus(pub Option<OpenVPNState>);
+
+impl Event for OpenVPNStatus {
+    const NAME: &'static str = "openvpn_status";
+}
+
+impl Event for Bytecount {
+    const NAME: &'static str = "openvpn_bytecount";
+}
+
+impl Event for Diagnostic {
+    const NAME: &'static str = "diagnostic";
+}
+
+/// How the connection handles IPv6, `None` when disconnected, left to the server or if it couldn't be blocked.
+#[derive(Debug, Clone, Serialize)]
+#[serde(transparent)]
+pub struct Ipv6Status(pub Option<Ipv6Mode>);
+
+impl Event for Ipv6Status {
+    const NAME: &'static str = "ipv6_status";
+}
+
+/// Both hops of a multi-hop connection, `None` once it's gone.
+#[derive(Debug, Clone, Serialize)]
+#[serde(transparent)]
+pub struct HopStatus(pub Option<Hops>);
+
+impl Event for HopStatus {
+    const NAME: &'static str = "hop_status";
+}
+
+/// Asks the window to connect, so the tray and auto-connect go through the same steps as the connect button.
+#[derive(Debug, Clone, Serialize)]
+#[serde(transparent)]
+pub struct ConnectTo(pub Target);
+
+impl Event for ConnectTo {
+    const NAME: &'static str = "connect_to";
+}
+
+/// Where traffic leaves, checked once connected.
+impl Event for VerifiedExit {
+    const NAME: &'static str = "verified_exit";
+}
+
+/// The config changed outside the window, i.e. from the tray.
+/// Only made with `new`, so the password never leaves the core.
+#[derive(Debug, Clone, Serialize)]
+#[serde(transparent)]
+pub struct ConfigChanged(Configuration);
+
+impl ConfigChanged {
+    pub fn new(config: &Configuration) -> Self {
+        ConfigChanged(config.public())
+    }
+}
+
+impl Event for ConfigChanged {
+    const NAME: &'static str = "config";
+}
+
+/// A renewed API token, replacing the window's.
+#[derive(Debug, Clone, Serialize)]
+#[serde(transparent)]
+pub struct SessionToken(pub Secret<String>);
+
+impl Event for SessionToken {
+    const NAME: &'static str = "session";
+}
+
+/// The stored credentials were refused, the window has to ask for new ones.
+#[derive(Debug, Clone, Serialize)]
+pub struct SessionExpired;
+
+impl Event for SessionExpired {
+    const NAME: &'static str = "session_expired";
+}
+
+/// The first peer of the WireGuard tunnel, `None` when it's not running.
+#[derive(Debug, Clone, Serialize)]
+#[serde(transparent)]
+pub struct WireGuardStatus(pub Option<PeerStats>);
+
+impl Event for WireGuardStatus {
+    const NAME: &'static str = "wireguard_status";
+}
+
+/// Anything the connection logic can report its events to.
+pub trait EventSink: Send + Sync + Clone + 'static {
+    fn emit<E: Event>(&self, event: E);
+}
+
+impl EventSink for Window {
+    fn emit<E: Event>(&self, event: E) {
+        let _ = Window::emit(self, E::NAME, event);
+    }
+}
+
+/// Sends every event to both sinks, i.e. the window and the Discord presence.
+#[derive(Clone)]
+pub struct Tee<A, B>(pub A, pub B);
+
+impl<A: EventSink, B: EventSink> EventSink for Tee<A, B> {
+    fn emit<E: Event>(&self, event: E) {
+        self.0.emit(event.clone());
+        self.1.emit(event);
+    }
+}
+
+/// Prints every event as a line of JSON, for headless use.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct StdoutSink;
+
+impl EventSink for StdoutSink {
+    fn emit<E: Event>(&self, event: E) {
+        println!("{}", serde_json::json!({ "event": E::NAME, "payload": event }));
+    }
+}
+
+/// Sends every event down a channel, to assert on them in tests.
+#[cfg(test)]
+#[derive(Debug, Clone)]
+pub struct ChannelSink {
+    sender: Sender<(&'static str, serde_json::Value)>
+}
+
+#[cfg(test)]
+impl ChannelSink {
+    pub fn new() -> (Self, Receiver<(&'static str, serde_json::Value)>) {
+        let (sender, receiver) = channel();
+        (ChannelSink { sender }, receiver)
+    }
+}
+
+#[cfg(test)]
+impl EventSink for ChannelSink {
+    fn emit<E: Event>(&self, event: E) {
+        let _ = self.sender.send((E::NAME, serde_json::to_value(event).unwrap()));
+    }
+}
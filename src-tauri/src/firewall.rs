@@ -0,0 +1,348 @@
+use std::net::{IpAddr, Ipv6Addr};
+#[cfg(target_os = "linux")]
+use std::{io::Write, process::Stdio};
+use std::process::Command;
+
+use crate::{Protocol, multihop, notifications, profile::Ipv6Mode};
+
+// Keeps IPv6 from going around the tunnel. Most servers only carry IPv4, and on a
+// dual-stack network anything reachable over IPv6 would still be reached directly,
+// so unless the profile tunnels it, it's rejected while connected.
+//
+// The kill switch goes further and only lets traffic out through the tunnels, to the
+// servers and to the local network. It stays in place when a tunnel drops, and is only
+// lifted when disconnecting, so nothing leaks while it's down or reconnecting.
+
+/// Addresses past the local network: global unicast and unique local.
+/// Loopback stays reachable, the management interface may listen on `::1`, and so does link-local, which neighbour discovery needs.
+const BLOCKED: [(Ipv6Addr, u8); 2] = [
+    (Ipv6Addr::new(0x2000, 0, 0, 0, 0, 0, 0, 0), 3),
+    (Ipv6Addr::new(0xfc00, 0, 0, 0, 0, 0, 0, 0), 7)
+];
+
+#[cfg(target_os = "linux")]
+const TABLE: &str = "octovpn";
+#[cfg(target_os = "linux")]
+const LOCK_TABLE: &str = "octovpn_lock";
+/// Interfaces the tunnels come up on: OpenVPN's, `wg-quick`'s named after the config, the multi-hop entry's and the userspace backend's
+#[cfg(target_os = "linux")]
+const TUNNELS: [&str; 4] = ["tun*", "wireguard", multihop::TUNNEL, "octovpn0"];
+/// The local network, along with what DHCP and neighbour discovery need
+#[cfg(target_os = "linux")]
+const LOCAL_IPV4: &str = "10.0.0.0/8, 172.16.0.0/12, 192.168.0.0/16, 169.254.0.0/16, 224.0.0.0/4, 255.255.255.255";
+#[cfg(target_os = "linux")]
+const LOCAL_IPV6: &str = "fe80::/10, ff00::/8";
+#[cfg(windows)]
+const RULE: &str = "OctoVPN IPv6";
+
+/// Servers written as IPv6 addresses in the config, which have to stay reachable.
+/// Host names aren't resolved, rejecting quickly makes OpenVPN move on to their IPv4 address.
+pub fn endpoints(config: &str) -> Vec<Ipv6Addr> {
+    config.lines()
+        .filter_map(|line| {
+            let line = line.trim();
+
+            if let Some(remote) = line.strip_prefix("remote ") {
+                return remote.split_whitespace().next()?.parse().ok();
+            }
+
+            let (key, value) = line.split_once('=')?;
+            if key.trim() != "Endpoint" {
+                return None;
+            }
+
+            // `[2001:db8::1]:51820`
+            let (host, _) = value.trim().rsplit_once(':')?;
+            host.trim_start_matches('[').trim_end_matches(']').parse().ok()
+        })
+        .collect()
+}
+
+/// The blocked ranges, inclusive, with the endpoints cut out of them.
+pub fn ranges(exempt: &[Ipv6Addr]) -> Vec<(Ipv6Addr, Ipv6Addr)> {
+    let mut ranges = BLOCKED.iter()
+        .map(|(network, prefix)| (u128::from(*network), u128::from(*network) | (u128::MAX >> prefix)))
+        .collect::<Vec<(u128, u128)>>();
+
+    for address in exempt.iter().map(|address| u128::from(*address)) {
+        ranges = ranges.into_iter()
+            .flat_map(|(start, end)| {
+                if address < start || address > end {
+                    return vec![(start, end)];
+                }
+
+                let mut split = vec![];
+                if address > start {
+                    split.push((start, address - 1));
+                }
+                if address < end {
+                    split.push((address + 1, end));
+                }
+                split
+            })
+            .collect();
+    }
+
+    ranges.into_iter()
+        .map(|(start, end)| (Ipv6Addr::from(start), Ipv6Addr::from(end)))
+        .collect()
+}
+
+fn format_ranges(exempt: &[Ipv6Addr], separator: &str) -> String {
+    ranges(exempt).iter()
+        .map(|(start, end)| format!("{}-{}", start, end))
+        .collect::<Vec<String>>()
+        .join(separator)
+}
+
+/// Creates the table if it's missing, so deleting it always succeeds.
+#[cfg(target_os = "linux")]
+fn reset() -> String {
+    format!("table ip6 {table}\ndelete table ip6 {table}\n", table = TABLE)
+}
+
+/// The nftables ruleset blocking IPv6, replacing any previous one atomically.
+/// Rejecting instead of dropping lets applications fall back to IPv4 right away.
+#[cfg(target_os = "linux")]
+pub fn nftables(exempt: &[Ipv6Addr]) -> String {
+    format!(
+        "{}table ip6 {} {{\n    chain output {{\n        type filter hook output priority 0; policy accept;\n        ip6 daddr {{ {} }} reject with icmpv6 type admin-prohibited\n    }}\n}}\n",
+        reset(), TABLE, format_ranges(exempt, ", ")
+    )
+}
+
+#[cfg(target_os = "linux")]
+fn nft(ruleset: &str) -> bool {
+    let child = Command::new("nft")
+        .args(["-f", "-"])
+        .stdin(Stdio::piped())
+        .spawn();
+
+    let mut child = match child {
+        Ok(child) => child,
+        Err(e) => {
+            log::error!("Failed to run nft: {}", e);
+            return false;
+        }
+    };
+
+    if let Some(mut stdin) = child.stdin.take() {
+        let _ = stdin.write_all(ruleset.as_bytes());
+    }
+
+    child.wait().map(|status| status.success()).unwrap_or(false)
+}
+
+/// Rejects IPv6 traffic leaving the local network, other than to the servers.
+#[cfg(target_os = "linux")]
+pub fn block(exempt: &[Ipv6Addr]) -> bool {
+    nft(&nftables(exempt))
+}
+
+#[cfg(target_os = "linux")]
+pub fn unblock() -> bool {
+    nft(&reset())
+}
+
+/// The nftables ruleset of the kill switch, rejecting anything that isn't going through a tunnel, to a server or to the local network.
+#[cfg(target_os = "linux")]
+pub fn lock_ruleset(servers: &[IpAddr]) -> String {
+    let mut rules = vec![String::from("oifname \"lo\" accept")];
+    rules.extend(TUNNELS.iter().map(|tunnel| format!("oifname \"{}\" accept", tunnel)));
+
+    let ipv4 = servers.iter().filter(|server| server.is_ipv4()).map(IpAddr::to_string).collect::<Vec<String>>();
+    let ipv6 = servers.iter().filter(|server| server.is_ipv6()).map(IpAddr::to_string).collect::<Vec<String>>();
+    if !ipv4.is_empty() {
+        rules.push(format!("ip daddr {{ {} }} accept", ipv4.join(", ")));
+    }
+    if !ipv6.is_empty() {
+        rules.push(format!("ip6 daddr {{ {} }} accept", ipv6.join(", ")));
+    }
+
+    rules.push(format!("ip daddr {{ {} }} accept", LOCAL_IPV4));
+    rules.push(format!("ip6 daddr {{ {} }} accept", LOCAL_IPV6));
+    rules.push(String::from("reject with icmpx type admin-prohibited"));
+
+    format!(
+        "table inet {table}\ndelete table inet {table}\ntable inet {table} {{\n    chain output {{\n        type filter hook output priority 0; policy accept;\n{}\n    }}\n}}\n",
+        rules.iter().map(|rule| format!("        {}", rule)).collect::<Vec<String>>().join("\n"),
+        table = LOCK_TABLE
+    )
+}
+
+/// Turns the kill switch on, letting traffic out only through the tunnels and to the servers.
+#[cfg(target_os = "linux")]
+pub fn lock(_protocol: &Protocol, servers: &[IpAddr]) -> bool {
+    nft(&lock_ruleset(servers))
+}
+
+#[cfg(target_os = "linux")]
+pub fn unlock() -> bool {
+    nft(&format!("table inet {table}\ndelete table inet {table}\n", table = LOCK_TABLE))
+}
+
+/// Arguments to `netsh` adding the rule blocking IPv6.
+/// Block rules win over any allow rule, so the servers are left out of its ranges instead.
+#[cfg(windows)]
+pub fn netsh_rule(exempt: &[Ipv6Addr]) -> Vec<String> {
+    vec![
+        String::from("advfirewall"),
+        String::from("firewall"),
+        String::from("add"),
+        String::from("rule"),
+        format!("name={}", RULE),
+        String::from("dir=out"),
+        String::from("action=block"),
+        String::from("protocol=any"),
+        format!("remoteip={}", format_ranges(exempt, ","))
+    ]
+}
+
+#[cfg(windows)]
+fn netsh<S: AsRef<std::ffi::OsStr>>(args: &[S]) -> bool {
+    Command::new("netsh")
+        .args(args)
+        .output()
+        .map(|output| output.status.success())
+        .unwrap_or(false)
+}
+
+/// Rejects IPv6 traffic leaving the local network, other than to the servers.
+#[cfg(windows)]
+pub fn block(exempt: &[Ipv6Addr]) -> bool {
+    unblock();
+    netsh(&netsh_rule(exempt))
+}
+
+/// Fails when there was no rule, which is fine too.
+#[cfg(windows)]
+pub fn unblock() -> bool {
+    netsh(&["advfirewall", "firewall", "delete", "rule", &format!("name={}", RULE)])
+}
+
+/// `wireguard.exe` blocks anything going around full tunnels on its own. The firewall can't tell
+/// OpenVPN's adapter apart from the others, so there's no kill switch for it.
+#[cfg(windows)]
+pub fn lock(protocol: &Protocol, _servers: &[IpAddr]) -> bool {
+    protocol == &Protocol::WireGuard
+}
+
+#[cfg(windows)]
+pub fn unlock() -> bool {
+    true
+}
+
+/// Turns the kill switch on before the tunnel comes up, for the servers in `config`.
+pub fn apply_kill_switch(protocol: &Protocol, config: &str) -> bool {
+    let servers = multihop::resolve(&multihop::hosts(config));
+    if lock(protocol, &servers) {
+        return true;
+    }
+
+    log::error!("Failed to turn the kill switch on");
+    notifications::action_failed("Kill switch not enabled", "Not connecting, traffic would go around the VPN if the connection dropped.");
+    false
+}
+
+/// Sets up the IPv6 handling before the tunnel comes up, returning what's in effect.
+/// `None` leaves IPv6 to the server's config, when no profile chose a mode or it could not be blocked.
+pub fn apply(mode: Option<Ipv6Mode>, config: &str) -> Option<Ipv6Mode> {
+    match mode {
+        Some(Ipv6Mode::Block) if block(&endpoints(config)) => Some(Ipv6Mode::Block),
+        Some(Ipv6Mode::Block) => {
+            log::error!("Failed to block IPv6");
+            notifications::action_failed("IPv6 not blocked", "IPv6 traffic may go around the VPN.");
+            None
+        },
+        mode => {
+            // Left over from a connection that dropped
+            unblock();
+            mode
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn address(address: &str) -> Ipv6Addr {
+        address.parse().unwrap()
+    }
+
+    #[test]
+    fn finds_ipv6_endpoints() {
+        let openvpn = "client\nremote 2001:db8::7 1194\nremote vpn.example.com 443\nremote 198.51.100.7 1194";
+        assert_eq!(endpoints(openvpn), vec![address("2001:db8::7")]);
+
+        let wireguard = "[Peer]\nEndpoint = [2001:db8::9]:51820\nAllowedIPs = 0.0.0.0/0\n";
+        assert_eq!(endpoints(wireguard), vec![address("2001:db8::9")]);
+
+        assert!(endpoints("[Peer]\nEndpoint = 198.51.100.7:51820").is_empty());
+    }
+
+    #[test]
+    fn blocks_global_and_unique_local_ranges() {
+        assert_eq!(ranges(&[]), vec![
+            (address("2000::"), address("3fff:ffff:ffff:ffff:ffff:ffff:ffff:ffff")),
+            (address("fc00::"), address("fdff:ffff:ffff:ffff:ffff:ffff:ffff:ffff"))
+        ]);
+
+        // Loopback and link-local are outside of them, and stay reachable
+        assert!(ranges(&[]).iter().all(|(start, end)| !(start..=end).contains(&&Ipv6Addr::LOCALHOST)));
+        assert!(ranges(&[]).iter().all(|(start, end)| !(start..=end).contains(&&address("fe80::1"))));
+    }
+
+    #[test]
+    fn cuts_endpoints_out_of_ranges() {
+        assert_eq!(ranges(&[address("2001:db8::7"), address("fe80::1")]), vec![
+            (address("2000::"), address("2001:db8::6")),
+            (address("2001:db8::8"), address("3fff:ffff:ffff:ffff:ffff:ffff:ffff:ffff")),
+            (address("fc00::"), address("fdff:ffff:ffff:ffff:ffff:ffff:ffff:ffff"))
+        ]);
+
+        // At the edge of a range, nothing is left on that side
+        assert_eq!(ranges(&[address("2000::")])[0].0, address("2000::1"));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn generates_nftables_ruleset() {
+        let ruleset = nftables(&[address("2001:db8::7")]);
+
+        // Replaces the previous table, even if there was none
+        assert!(ruleset.starts_with("table ip6 octovpn\ndelete table ip6 octovpn\ntable ip6 octovpn {\n"));
+        assert!(ruleset.contains("type filter hook output priority 0; policy accept;"));
+        assert!(ruleset.contains(
+            "ip6 daddr { 2000::-2001:db8::6, 2001:db8::8-3fff:ffff:ffff:ffff:ffff:ffff:ffff:ffff, fc00::-fdff:ffff:ffff:ffff:ffff:ffff:ffff:ffff } reject with icmpv6 type admin-prohibited"
+        ));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn generates_kill_switch_ruleset() {
+        let ruleset = lock_ruleset(&["198.51.100.7".parse().unwrap(), "2001:db8::7".parse().unwrap()]);
+
+        assert!(ruleset.starts_with("table inet octovpn_lock\ndelete table inet octovpn_lock\ntable inet octovpn_lock {\n"));
+        assert!(ruleset.contains("        oifname \"lo\" accept\n        oifname \"tun*\" accept\n        oifname \"wireguard\" accept\n        oifname \"hop\" accept\n"));
+        assert!(ruleset.contains("        ip daddr { 198.51.100.7 } accept\n        ip6 daddr { 2001:db8::7 } accept\n"));
+        assert!(ruleset.contains("ip daddr { 10.0.0.0/8, 172.16.0.0/12, 192.168.0.0/16"));
+
+        // Anything else is rejected last
+        assert!(ruleset.ends_with("        reject with icmpx type admin-prohibited\n    }\n}\n"));
+
+        // No empty sets, nft refuses them
+        assert!(!lock_ruleset(&[]).contains("{  }"));
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn generates_netsh_rule() {
+        let rule = netsh_rule(&[]);
+
+        assert_eq!(rule[..4], ["advfirewall", "firewall", "add", "rule"]);
+        assert!(rule.contains(&String::from("name=OctoVPN IPv6")));
+        assert!(rule.contains(&String::from("dir=out")));
+        assert_eq!(rule.last().unwrap(), "remoteip=2000::-3fff:ffff:ffff:ffff:ffff:ffff:ffff:ffff,fc00::-fdff:ffff:ffff:ffff:ffff:ffff:ffff:ffff");
+    }
+}
@@ -0,0 +1,284 @@
+use std::{path::Path, process::Command};
+
+use crate::{CONFIGURATION, CONNECTED, Protocol, PreflightError, ServiceReport, config::HOME};
+
+// Linux counterpart of `windows.rs`, built on systemd units.
+// The functions are kept `unsafe` so both platforms share the same call sites.
+
+const UNIT_DIR: &str = "/etc/systemd/system";
+/// Runs the multi-hop entry's tunnel, next to the protocol's own unit
+const HOP_UNIT: &str = "octovpn-hop.service";
+
+fn unit_name(protocol: &Protocol) -> String {
+    format!("octovpn-{}.service", protocol.to_string().to_lowercase())
+}
+
+fn unit_path(protocol: &Protocol) -> String {
+    format!("{}/{}", UNIT_DIR, unit_name(protocol))
+}
+
+fn executable(protocol: &Protocol) -> &'static str {
+    match protocol {
+        Protocol::OpenVPN => "openvpn",
+        Protocol::WireGuard => "wg-quick"
+    }
+}
+
+/// Looks up an executable in `PATH`.
+fn which(name: &str) -> Option<String> {
+    let path = std::env::var("PATH").ok()?;
+
+    path.split(':')
+        .map(|dir| format!("{}/{}", dir, name))
+        .find(|candidate| Path::new(candidate).is_file())
+}
+
+/// Generates the systemd unit running `binary` for the given protocol, with the configs in `home`.
+/// OpenVPN runs in the foreground, WireGuard is brought up and down by `wg-quick`.
+fn unit_contents(protocol: &Protocol, binary: &str, home: &str) -> String {
+    let service = match protocol {
+        Protocol::OpenVPN => format!(
+            "Type=simple\nExecStart={} --config {}/.octovpn/openvpn/config.ovpn --log {}/.octovpn/openvpn/logs/config.log",
+            binary, home, home
+        ),
+        Protocol::WireGuard => format!(
+            "Type=oneshot\nRemainAfterExit=yes\nExecStart={} up {}/.octovpn/wireguard/wireguard.conf\nExecStop={} down {}/.octovpn/wireguard/wireguard.conf",
+            binary, home, binary, home
+        )
+    };
+
+    format!(
+        "# Generated by OctoVPN, do not edit.\n[Unit]\nDescription=OctoVPN Service ({})\nAfter=network-online.target\nWants=network-online.target\n\n[Service]\n{}\n\n[Install]\nWantedBy=multi-user.target\n",
+        protocol, service
+    )
+}
+
+fn systemctl(args: &[&str]) -> bool {
+    Command::new("systemctl")
+        .args(args)
+        .status()
+        .map(|status| status.success())
+        .unwrap_or(false)
+}
+
+fn ensure_paths(protocol: &Protocol) -> Result<(), PreflightError> {
+    let home = HOME.to_str().unwrap();
+    let protocol_dir = format!("{}/.octovpn/{}", home, protocol.to_string().to_lowercase());
+
+    std::fs::create_dir_all(protocol_dir).map_err(|_| PreflightError::FilesMissing)?;
+
+    if protocol == &Protocol::OpenVPN {
+        let logs_dir = format!("{}/.octovpn/openvpn/logs", home);
+        std::fs::create_dir_all(logs_dir).map_err(|_| PreflightError::FilesMissing)?;
+    }
+
+    Ok(())
+}
+
+/// Checks the unit for the given protocol is installed, without changing anything.
+/// Installing is left to `install`, and to `start_service` when connecting.
+pub unsafe fn preflight(protocol: &Protocol) -> Result<(), PreflightError> {
+    match Path::new(&unit_path(protocol)).exists() {
+        true => Ok(()),
+        false => Err(PreflightError::ServiceNotInstalled)
+    }
+}
+
+/// Makes sure the program has everything it needs to correctly start,
+/// installing the unit if it's missing.
+unsafe fn prepare(protocol: &Protocol) -> Result<(), PreflightError> {
+    ensure_paths(protocol)?;
+
+    if preflight(protocol).is_err() {
+        install(protocol)?;
+    }
+
+    Ok(())
+}
+
+pub unsafe fn is_service_started(protocol: &Protocol) -> bool {
+    systemctl(&["is-active", "--quiet", &unit_name(protocol)])
+}
+
+pub unsafe fn start_service(protocol: &Protocol) -> bool {
+    // Ensure everything is set up correctly, connecting installs the unit if needed
+    let prepared = prepare(protocol);
+    if prepared.is_err() {
+        log::error!("Failed preflight check: {:?}", prepared);
+        return false;
+    }
+
+    if !systemctl(&["start", &unit_name(protocol)]) {
+        log::error!("Failed to start service");
+        return false;
+    }
+
+    *CONNECTED.lock().unwrap() = true;
+    true
+}
+
+pub unsafe fn stop_service(protocol: &Protocol) -> bool {
+    let status = systemctl(&["stop", &unit_name(protocol)]);
+
+    *CONNECTED.lock().unwrap() = !status;
+    status
+}
+
+/// Whether this is the service to start with the system, only the selected protocol's is.
+fn starts_on_boot(protocol: &Protocol) -> bool {
+    let configuration = CONFIGURATION.lock().unwrap();
+    configuration.auto_connect.boot && configuration.protocol == *protocol
+}
+
+/// Enables or disables the unit to follow the start on boot option.
+pub unsafe fn set_start_on_boot(protocol: &Protocol) -> bool {
+    // Installed, and enabled if needed, on the first connection, there's no config to start before that
+    if !Path::new(&unit_path(protocol)).exists() {
+        return true;
+    }
+
+    systemctl(&[if starts_on_boot(protocol) { "enable" } else { "disable" }, &unit_name(protocol)])
+}
+
+/// Writes the multi-hop entry's unit, if it changed, and starts it.
+pub unsafe fn start_hop(config: &str) -> bool {
+    let binary = match which("wg-quick") {
+        Some(binary) => binary,
+        None => {
+            log::error!("wg-quick is needed for multi-hop");
+            return false;
+        }
+    };
+
+    let path = format!("{}/{}", UNIT_DIR, HOP_UNIT);
+    let contents = format!(
+        "# Generated by OctoVPN, do not edit.\n[Unit]\nDescription=OctoVPN Service (multi-hop entry)\nAfter=network-online.target\nWants=network-online.target\n\n[Service]\nType=oneshot\nRemainAfterExit=yes\nExecStart={} up {}\nExecStop={} down {}\n",
+        binary, config, binary, config
+    );
+
+    if std::fs::read_to_string(&path).ok().as_deref() != Some(contents.as_str())
+        && (std::fs::write(&path, contents).is_err() || !systemctl(&["daemon-reload"]))
+    {
+        log::error!("Failed to install {}", HOP_UNIT);
+        return false;
+    }
+
+    systemctl(&["start", HOP_UNIT])
+}
+
+pub unsafe fn stop_hop() -> bool {
+    systemctl(&["stop", HOP_UNIT])
+}
+
+pub unsafe fn is_hop_started() -> bool {
+    systemctl(&["is-active", "--quiet", HOP_UNIT])
+}
+
+/// Writes the systemd unit for the given protocol.
+/// Requires root privileges.
+pub unsafe fn install(protocol: &Protocol) -> Result<ServiceReport, PreflightError> {
+    let mut report = ServiceReport::new(*protocol);
+
+    ensure_paths(protocol)?;
+
+    let path = unit_path(protocol);
+    let binary = which(executable(protocol)).ok_or(PreflightError::FilesMissing)?;
+    let contents = unit_contents(protocol, &binary, HOME.to_str().unwrap());
+
+    if std::fs::read_to_string(&path).ok().as_deref() == Some(contents.as_str()) {
+        report.push(format!("Unit {} is already installed", path));
+        return Ok(report);
+    }
+
+    std::fs::write(&path, contents).map_err(|_| PreflightError::ServiceNotInstalled)?;
+    report.push(format!("Wrote unit {}", path));
+
+    if !systemctl(&["daemon-reload"]) {
+        return Err(PreflightError::ServiceNotInstalled);
+    }
+    report.push("Reloaded systemd");
+
+    if starts_on_boot(protocol) && systemctl(&["enable", &unit_name(protocol)]) {
+        report.push(format!("Enabled {} on boot", unit_name(protocol)));
+    }
+
+    Ok(report)
+}
+
+/// Stops and removes the systemd unit for the given protocol.
+/// Requires root privileges.
+pub unsafe fn uninstall(protocol: &Protocol) -> Result<ServiceReport, PreflightError> {
+    let mut report = ServiceReport::new(*protocol);
+
+    let path = unit_path(protocol);
+    if !Path::new(&path).exists() {
+        report.push(format!("Unit {} is not installed", path));
+        return Ok(report);
+    }
+
+    if is_service_started(protocol) && stop_service(protocol) {
+        report.push(format!("Stopped {}", unit_name(protocol)));
+    }
+
+    // Leaves no dangling link behind
+    if systemctl(&["disable", "--quiet", &unit_name(protocol)]) {
+        report.push(format!("Disabled {}", unit_name(protocol)));
+    }
+
+    std::fs::remove_file(&path).map_err(|_| PreflightError::ServiceNotRemoved)?;
+    report.push(format!("Removed unit {}", path));
+
+    if !systemctl(&["daemon-reload"]) {
+        return Err(PreflightError::ServiceNotRemoved);
+    }
+    report.push("Reloaded systemd");
+
+    Ok(report)
+}
+
+/// Reinstalls the systemd unit for the given protocol from scratch.
+/// Requires root privileges.
+pub unsafe fn repair(protocol: &Protocol) -> Result<ServiceReport, PreflightError> {
+    let mut report = uninstall(protocol)?;
+    report.extend(install(protocol)?);
+
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn line<'a>(unit: &'a str, key: &str) -> Vec<&'a str> {
+        unit.lines().filter_map(|line| line.strip_prefix(key)).collect()
+    }
+
+    #[test]
+    fn runs_openvpn_in_the_foreground_with_its_config() {
+        let unit = unit_contents(&Protocol::OpenVPN, "/usr/sbin/openvpn", "/home/octo");
+
+        assert_eq!(line(&unit, "Type="), vec!["simple"]);
+        assert_eq!(line(&unit, "ExecStart="), vec![
+            "/usr/sbin/openvpn --config /home/octo/.octovpn/openvpn/config.ovpn --log /home/octo/.octovpn/openvpn/logs/config.log"
+        ]);
+        assert!(line(&unit, "ExecStop=").is_empty());
+        assert_eq!(line(&unit, "WantedBy="), vec!["multi-user.target"]);
+    }
+
+    #[test]
+    fn brings_wireguard_up_and_down_with_wg_quick() {
+        let unit = unit_contents(&Protocol::WireGuard, "/usr/bin/wg-quick", "/home/octo");
+
+        assert_eq!(line(&unit, "Type="), vec!["oneshot"]);
+        assert_eq!(line(&unit, "RemainAfterExit="), vec!["yes"]);
+        assert_eq!(line(&unit, "ExecStart="), vec!["/usr/bin/wg-quick up /home/octo/.octovpn/wireguard/wireguard.conf"]);
+        assert_eq!(line(&unit, "ExecStop="), vec!["/usr/bin/wg-quick down /home/octo/.octovpn/wireguard/wireguard.conf"]);
+        assert_eq!(line(&unit, "WantedBy="), vec!["multi-user.target"]);
+    }
+
+    #[test]
+    fn names_units_after_the_protocol() {
+        assert_eq!(unit_path(&Protocol::OpenVPN), "/etc/systemd/system/octovpn-openvpn.service");
+        assert_eq!(unit_path(&Protocol::WireGuard), "/etc/systemd/system/octovpn-wireguard.service");
+    }
+}
@@ -0,0 +1,340 @@
+use std::{path::PathBuf, sync::Mutex, time::{Duration, SystemTime, UNIX_EPOCH}};
+
+use lazy_static::lazy_static;
+use reqwest::{StatusCode, header};
+use serde::{Serialize, Deserialize, Deserializer, de};
+
+use crate::{Location, config::HOME, session::ApiError};
+
+// The API's locations, cached on disk so the tray and the CLI have them without the
+// window, and so they're there right away on the next start. They're fetched again once
+// older than the TTL, the ETag saves downloading them when nothing changed.
+
+pub const BASE_URL: &str = "https://617069.6f63746f76706e.com:8443/api/v1";
+
+/// Reads a location's id, which the API sends as a number or as a string of one.
+pub fn deserialize_id<'de, D: Deserializer<'de>>(deserializer: D) -> Result<i32, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Id {
+        Number(i32),
+        Text(String)
+    }
+
+    match Id::deserialize(deserializer)? {
+        Id::Number(id) => Ok(id),
+        Id::Text(id) => id.trim().parse().map_err(de::Error::custom)
+    }
+}
+
+const TTL: Duration = Duration::from_secs(10 * 60);
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+lazy_static! {
+    static ref CACHE: Mutex<Option<Cache>> = Mutex::new(None);
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+pub struct Cache {
+    pub etag: Option<String>,
+    /// Unix time of the API's last answer
+    pub fetched: u64,
+    pub locations: Vec<Location>
+}
+
+impl Cache {
+    pub fn is_fresh(&self, now: u64) -> bool {
+        now.saturating_sub(self.fetched) < TTL.as_secs()
+    }
+}
+
+#[derive(Deserialize)]
+struct Response {
+    success: bool,
+    data: Option<Vec<Location>>
+}
+
+/// Which locations to list, all of them by default.
+#[derive(Debug, PartialEq, Eq, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Filter {
+    /// Country name or code, whatever the case
+    pub country: Option<String>,
+    /// Only online locations, or only offline ones
+    pub online: Option<bool>,
+    /// Only locations with a WireGuard config, or only without
+    pub wireguard: Option<bool>,
+    pub favourites: bool
+}
+
+impl Filter {
+    pub fn matches(&self, location: &Location, favourites: &[i32]) -> bool {
+        let country = self.country.as_ref().is_none_or(|country| {
+            location.country.eq_ignore_ascii_case(country) || location.country_code.eq_ignore_ascii_case(country)
+        });
+
+        country
+            && self.online.is_none_or(|online| location.status == online)
+            && self.wireguard.is_none_or(|wireguard| location.wireguard == wireguard)
+            && (!self.favourites || favourites.contains(&location.id))
+    }
+
+    /// Reads the CLI's flags, i.e. `--country NL --online --wireguard`.
+    pub fn from_args(args: &[String]) -> Result<Filter, String> {
+        let mut filter = Filter::default();
+        let mut args = args.iter();
+
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--country" => filter.country = Some(args.next().ok_or("--country needs a name or code")?.clone()),
+                "--online" => filter.online = Some(true),
+                "--offline" => filter.online = Some(false),
+                "--wireguard" => filter.wireguard = Some(true),
+                "--favourites" => filter.favourites = true,
+                arg => return Err(format!("Unknown option {}", arg))
+            }
+        }
+
+        Ok(filter)
+    }
+}
+
+pub fn filter(locations: &[Location], filter: &Filter, favourites: &[i32]) -> Vec<Location> {
+    locations.iter()
+        .filter(|location| filter.matches(location, favourites))
+        .cloned()
+        .collect()
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|now| now.as_secs()).unwrap_or_default()
+}
+
+fn cache_path() -> PathBuf {
+    HOME.join(".octovpn").join("locations.json")
+}
+
+/// The cached locations, read from disk on first use.
+pub fn cached() -> Option<Cache> {
+    let mut cache = CACHE.lock().unwrap();
+
+    if cache.is_none() {
+        *cache = std::fs::read_to_string(cache_path()).ok()
+            .and_then(|cached| serde_json::from_str(&cached).ok());
+    }
+
+    cache.clone()
+}
+
+fn store(cache: Cache) {
+    if let Err(e) = std::fs::write(cache_path(), serde_json::to_string(&cache).unwrap()) {
+        log::warn!("Failed to cache the locations: {}", e);
+    }
+
+    *CACHE.lock().unwrap() = Some(cache);
+}
+
+/// Asks the API for the locations, unless they didn't change since `cached`.
+pub fn fetch(url: &str, token: &str, cached: Option<&Cache>) -> Result<Cache, ApiError> {
+    let client = reqwest::blocking::Client::builder()
+        .timeout(TIMEOUT)
+        .build()
+        .map_err(|e| ApiError::Failed(e.to_string()))?;
+
+    let mut request = client.get(url).header(header::AUTHORIZATION, token);
+    if let Some(etag) = cached.and_then(|cache| cache.etag.as_ref()) {
+        request = request.header(header::IF_NONE_MATCH, etag);
+    }
+
+    let response = request.send().map_err(|e| ApiError::Failed(e.to_string()))?;
+    if response.status() == StatusCode::UNAUTHORIZED {
+        return Err(ApiError::Unauthorized);
+    }
+
+    let response = response.error_for_status().map_err(|e| ApiError::Failed(e.to_string()))?;
+
+    if let Some(cached) = cached.filter(|_| response.status() == StatusCode::NOT_MODIFIED) {
+        return Ok(Cache { fetched: now(), ..cached.clone() });
+    }
+
+    let etag = response.headers().get(header::ETAG)
+        .and_then(|etag| etag.to_str().ok())
+        .map(String::from);
+    let body = response.text().map_err(|e| ApiError::Failed(e.to_string()))?;
+
+    match serde_json::from_str::<Response>(&body) {
+        Ok(Response { success: true, data: Some(locations) }) => Ok(Cache { etag, fetched: now(), locations }),
+        Ok(_) => Err(ApiError::Failed(String::from("The API refused to list the locations"))),
+        Err(e) => Err(ApiError::Failed(format!("Unexpected answer from the API: {}", e)))
+    }
+}
+
+/// The locations, from the cache while it's fresh, else from the API.
+/// Stale ones still beat nothing, when the API can't be reached or there is no token.
+/// A refused token is an error still, so it can be renewed.
+pub fn get(token: Option<&str>, refresh: bool) -> Result<Vec<Location>, ApiError> {
+    let cached = cached();
+    if let Some(cache) = cached.as_ref().filter(|cache| !refresh && cache.is_fresh(now())) {
+        return Ok(cache.locations.clone());
+    }
+
+    let fetched = match token {
+        Some(token) => fetch(&format!("{}/locations", BASE_URL), token, cached.as_ref()),
+        None => Err(ApiError::Failed(String::from("Not logged in")))
+    };
+
+    match (fetched, cached) {
+        (Ok(cache), _) => {
+            let locations = cache.locations.clone();
+            store(cache);
+            Ok(locations)
+        },
+        (Err(ApiError::Unauthorized), _) => Err(ApiError::Unauthorized),
+        (Err(e), Some(cache)) => {
+            log::warn!("Failed to refresh the locations, using the cached ones: {}", e);
+            Ok(cache.locations)
+        },
+        (Err(e), None) => {
+            log::error!("Failed to fetch the locations: {}", e);
+            Err(e)
+        }
+    }
+}
+
+/// Forgets the cached locations, i.e. when logging out.
+pub fn clear() {
+    *CACHE.lock().unwrap() = None;
+
+    match std::fs::remove_file(cache_path()) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => log::warn!("Failed to remove the cached locations: {}", e),
+        _ => {}
+    }
+}
+
+/// The location's name, if it's cached.
+pub fn name(id: i32) -> Option<String> {
+    cached()?.locations.into_iter()
+        .find(|location| location.id == id)
+        .map(|location| location.name)
+}
+
+/// Adds or removes the location from the favourites, returning whether it's one now.
+pub fn toggle_favourite(favourites: &mut Vec<i32>, id: i32) -> bool {
+    if favourites.contains(&id) {
+        favourites.retain(|favourite| *favourite != id);
+        false
+    } else {
+        favourites.push(id);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_http::{response, serve};
+
+    fn location(id: i32, country: &str, code: &str, status: bool, wireguard: bool) -> Location {
+        Location {
+            city: String::from("City"),
+            config: None,
+            country: country.to_string(),
+            country_code: code.to_string(),
+            wireguard,
+            id,
+            ip: format!("198.51.100.{}", id),
+            name: format!("{} #{}", country, id),
+            status,
+            custom: None
+        }
+    }
+
+    fn locations() -> Vec<Location> {
+        vec![
+            location(1, "Netherlands", "nl", true, true),
+            location(2, "Netherlands", "nl", false, true),
+            location(3, "Germany", "de", true, false)
+        ]
+    }
+
+    fn ids(locations: Vec<Location>) -> Vec<i32> {
+        locations.iter().map(|location| location.id).collect()
+    }
+
+    /// Answers the first request with the locations and an ETag, then only whether they changed.
+    /// The last id is a string, as the API sends some of them.
+    fn serve_locations() -> String {
+        let mut data = serde_json::to_value(locations()).unwrap();
+        data[2]["id"] = serde_json::json!("3");
+        let body = serde_json::json!({ "success": true, "data": data }).to_string();
+
+        serve("/locations", move |request| match request.to_lowercase().contains("if-none-match: \"v1\"") {
+            true => response("304 Not Modified", &["ETag: \"v1\""], ""),
+            false => response("200 OK", &["ETag: \"v1\""], &body)
+        })
+    }
+
+    #[test]
+    fn filters_by_country_status_and_wireguard() {
+        let all = locations();
+
+        assert_eq!(ids(filter(&all, &Filter::default(), &[])), vec![1, 2, 3]);
+        assert_eq!(ids(filter(&all, &Filter { country: Some(String::from("NL")), ..Filter::default() }, &[])), vec![1, 2]);
+        assert_eq!(ids(filter(&all, &Filter { country: Some(String::from("germany")), ..Filter::default() }, &[])), vec![3]);
+        assert_eq!(ids(filter(&all, &Filter { online: Some(true), wireguard: Some(true), ..Filter::default() }, &[])), vec![1]);
+        assert_eq!(ids(filter(&all, &Filter { favourites: true, ..Filter::default() }, &[3])), vec![3]);
+    }
+
+    #[test]
+    fn reads_cli_flags() {
+        let args = ["--country", "nl", "--online", "--favourites"].map(String::from);
+        assert_eq!(Filter::from_args(&args), Ok(Filter { country: Some(String::from("nl")), online: Some(true), wireguard: None, favourites: true }));
+
+        assert!(Filter::from_args(&[String::from("--country")]).is_err());
+        assert!(Filter::from_args(&[String::from("--fast")]).is_err());
+    }
+
+    #[test]
+    fn toggles_favourites() {
+        let mut favourites = vec![1];
+
+        assert!(toggle_favourite(&mut favourites, 3));
+        assert!(!toggle_favourite(&mut favourites, 1));
+        assert_eq!(favourites, vec![3]);
+    }
+
+    #[test]
+    fn expires_after_the_ttl() {
+        let cache = Cache { etag: None, fetched: 1000, locations: vec![] };
+
+        assert!(cache.is_fresh(1000 + TTL.as_secs() - 1));
+        assert!(!cache.is_fresh(1000 + TTL.as_secs()));
+    }
+
+    #[test]
+    fn fetches_only_when_changed() {
+        let url = serve_locations();
+
+        let cache = fetch(&url, "token", None).unwrap();
+        assert_eq!(cache.etag, Some(String::from("\"v1\"")));
+        assert_eq!(cache.locations, locations());
+
+        // Not modified, the cached locations are kept
+        let stale = Cache { fetched: 0, ..cache };
+        let refreshed = fetch(&url, "token", Some(&stale)).unwrap();
+        assert_eq!(refreshed.locations, locations());
+        assert!(refreshed.is_fresh(now()));
+    }
+
+    #[test]
+    fn reads_ids_as_numbers_or_strings() {
+        let parse = |id: serde_json::Value| serde_json::from_value::<Location>(serde_json::json!({
+            "city": "City", "config": null, "country": "Germany", "country_code": "de",
+            "hasWireGuardConfig": false, "id": id, "ip": "198.51.100.3", "name": "Germany #3", "status": true
+        }));
+
+        assert_eq!(parse(serde_json::json!(3)).unwrap().id, 3);
+        assert_eq!(parse(serde_json::json!("3")).unwrap().id, 3);
+        assert!(parse(serde_json::json!("three")).is_err());
+    }
+}
@@ -0,0 +1,111 @@
+use std::{str::FromStr, thread, time::Duration};
+
+use log::LevelFilter;
+use serde_repr::{Serialize_repr, Deserialize_repr};
+use tauri::{plugin::TauriPlugin, Wry};
+use tauri_plugin_log::{Builder, LogTarget, RotationStrategy};
+use time::{OffsetDateTime, format_description::well_known::Rfc3339, macros::format_description};
+
+use crate::{Configuration, config::HOME, secret::scrub};
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize_repr, Deserialize_repr)]
+#[repr(u8)]
+pub enum LogFormat {
+    Text,
+    /// One JSON object per line
+    Json
+}
+
+/// How often the files split off while running are pruned.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+pub fn log_dir() -> String {
+    format!("{}/.octovpn/logs", HOME.to_str().unwrap())
+}
+
+/// Parses the configured level, i.e. `debug`, falling back to `info`.
+pub fn level(config: &Configuration) -> LevelFilter {
+    LevelFilter::from_str(&config.log_level).unwrap_or(LevelFilter::Info)
+}
+
+/// Deletes the oldest logs, keeping `retention` files.
+/// Also covers the old `octovpn.{n}.log` names.
+fn prune(retention: usize) {
+    let files = match std::fs::read_dir(log_dir()) {
+        Ok(files) => files,
+        Err(_) => return
+    };
+
+    let mut logs = files
+        .filter_map(|file| file.ok())
+        .filter(|file| file.file_name().to_string_lossy().starts_with("octovpn"))
+        .filter_map(|file| Some((file.metadata().ok()?.modified().ok()?, file.path())))
+        .collect::<Vec<_>>();
+
+    // Newest first
+    logs.sort_by(|a, b| b.0.cmp(&a.0));
+
+    for (_, path) in logs.into_iter().skip(retention) {
+        if let Err(e) = std::fs::remove_file(&path) {
+            eprintln!("Failed to remove old log {:?}: {}", path, e);
+        }
+    }
+}
+
+/// Builds the log plugin. Each run gets its own file, named after when it started,
+/// which is split once it reaches the configured size.
+pub fn plugin(config: &Configuration) -> TauriPlugin<Wry> {
+    let _ = std::fs::create_dir_all(log_dir());
+
+    // Leave room for the file about to be created
+    prune(config.log_retention.saturating_sub(1));
+
+    // Splitting keeps every part, the oldest are deleted here as new ones come
+    let retention = config.log_retention;
+    thread::spawn(move || loop {
+        thread::sleep(PRUNE_INTERVAL);
+        prune(retention);
+    });
+
+    let started = OffsetDateTime::now_utc()
+        .format(format_description!("[year]-[month]-[day]_[hour]-[minute]-[second]"))
+        .unwrap_or_default();
+
+    let format = config.log_format;
+
+    Builder::new()
+        .targets([
+            LogTarget::Stdout,
+            LogTarget::Webview,
+            LogTarget::Folder(log_dir().into())
+        ])
+        .log_name(format!("octovpn-{}", started))
+        .max_file_size(config.log_max_size as u128)
+        .rotation_strategy(RotationStrategy::KeepAll)
+        // Everything goes through, the level is set by `log::set_max_level` so it can change at runtime
+        .level(LevelFilter::Trace)
+        .filter(|metadata| metadata.target().starts_with("octovpn"))
+        // Every line is scrubbed, whatever the target, so secrets never reach the webview or disk
+        .format(move |out, message, record| {
+            let now = OffsetDateTime::now_utc();
+            let message = message.to_string();
+            let message = scrub(&message);
+
+            match format {
+                LogFormat::Text => out.finish(format_args!(
+                    "{}[{}][{}] {}",
+                    now.format(format_description!("[[[year]-[month]-[day]][[[hour]:[minute]:[second]]")).unwrap_or_default(),
+                    record.target(),
+                    record.level(),
+                    message
+                )),
+                LogFormat::Json => out.finish(format_args!("{}", serde_json::json!({
+                    "time": now.format(&Rfc3339).unwrap_or_default(),
+                    "level": record.level().as_str(),
+                    "target": record.target(),
+                    "message": message
+                })))
+            }
+        })
+        .build()
+}
@@ -3,22 +3,73 @@
 
 use serde::{Serialize, Deserialize};
 use serde_repr::{Serialize_repr, Deserialize_repr};
-use tauri::{Manager, Window};
+use tauri::{Manager, Window, WindowEvent};
 use window_shadows::set_shadow;
-use tauri_plugin_log::{Builder, LogTarget};
-use discord_presence::Client;
 use lazy_static::lazy_static;
-use windows::{is_service_started, patch_config, write_config, read_config};
-use std::{sync::Mutex, fmt::{Display, Formatter}, io::{Write, Read}, thread, net::TcpStream, time::Duration};
-
-use crate::windows::{preflight, start_service, stop_service};
-
+use config::{patch_config, write_config, read_config};
+use profile::{Ipv6Mode, Profile, Target, Transport};
+use wireguard::PeerStats;
+use events::{ConfigChanged, ConnectTo, EventSink, HopStatus, Ipv6Status, OpenVPNStatus, WireGuardStatus, StdoutSink, Tee};
+use discord::{Presence, PresenceConfig};
+use tray::{Recent, Tray};
+use notifications::{NotificationConfig, Notifier};
+use network::AutoConnect;
+use verify::{VerifyConfig, Verifier};
+use multihop::{Hop, Hops, MultiHop};
+use locations::Filter;
+use mtu::MtuConfig;
+use diagnostics::{Cause, Handshakes, Tail};
+use logging::LogFormat;
+use secret::Secret;
+use management::Management;
+use platform::{is_service_started, preflight, set_start_on_boot, start_service, stop_service};
+use std::{sync::{Mutex, Once}, fmt::{Display, Formatter}, thread, time::Duration, str::FromStr};
+
+mod config;
+mod events;
+mod diagnostics;
+mod logging;
+mod bundle;
+mod secret;
+mod discord;
+mod discord_ipc;
+#[cfg(all(test, unix))]
+mod mock_discord;
+mod tray;
+mod notifications;
+mod network;
+mod monitor;
+mod verify;
+mod firewall;
+mod multihop;
+mod locations;
+mod session;
+mod mtu;
+mod management;
+#[cfg(test)]
+mod mock_management;
+#[cfg(test)]
+mod mock_http;
+mod custom;
+mod profile;
+mod wireguard;
+mod uapi;
+#[cfg(all(feature = "userspace-wireguard", unix))]
+mod userspace;
+#[cfg(windows)]
 mod windows;
+#[cfg(target_os = "linux")]
+mod linux;
+
+#[cfg(windows)]
+use crate::windows as platform;
+#[cfg(target_os = "linux")]
+use crate::linux as platform;
 
 #[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
 pub struct Credentials {
     username: String,
-    password: String
+    password: Secret<String>
 }
 
 #[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
@@ -26,12 +77,46 @@ pub struct Location {
     city: String,
     config: Option<String>,
     country: String,
+    #[serde(default)]
+    country_code: String,
     #[serde(rename = "hasWireGuardConfig")]
     wireguard: bool,
+    #[serde(deserialize_with = "locations::deserialize_id")]
     id: i32,
     ip: String,
     name: String,
-    status: bool
+    status: bool,
+    /// Id of the imported config, if this isn't an API location
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    custom: Option<String>
+}
+
+/// Where the tunnel goes, as shown to the user.
+/// The frontend sends its `Location`, only these fields are kept.
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+pub struct Destination {
+    name: String,
+    #[serde(default)]
+    city: Option<String>,
+    country: String,
+    /// Server address, to compare the exit with
+    #[serde(default)]
+    ip: Option<String>,
+    #[serde(default)]
+    country_code: Option<String>
+}
+
+impl From<&Location> for Destination {
+    fn from(location: &Location) -> Self {
+        Destination {
+            name: location.name.clone(),
+            city: Some(location.city.clone()),
+            country: location.country.clone(),
+            ip: Some(location.ip.clone()).filter(|ip| !ip.is_empty()),
+            // Imported configs have no country
+            country_code: Some(location.country_code.clone()).filter(|code| !code.is_empty() && code != "xx")
+        }
+    }
 }
 
 #[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize_repr, Deserialize_repr)]
@@ -50,7 +135,20 @@ impl Display for Protocol {
     }
 }
 
+impl FromStr for Protocol {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "openvpn" => Ok(Protocol::OpenVPN),
+            "wireguard" => Ok(Protocol::WireGuard),
+            _ => Err(format!("Unknown protocol: {}", s))
+        }
+    }
+}
+
 impl Protocol {
+    #[cfg(windows)]
     fn executable(&self) -> &'static str {
         match self {
             Protocol::OpenVPN => "openvpnserv2.exe",
@@ -58,6 +156,7 @@ impl Protocol {
         }
     }
 
+    #[cfg(windows)]
     fn service_name(&self) -> &'static str {
         match self {
             Protocol::OpenVPN => "OctoVPNService$OpenVPN",
@@ -66,21 +165,80 @@ impl Protocol {
     }
 }
 
+#[derive(Debug, Serialize)]
+pub enum PreflightError {
+    ServiceNotInstalled,
+    ServiceNotRemoved,
+    FilesMissing,
+    InvalidHash,
+}
+
+impl Display for PreflightError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PreflightError::ServiceNotInstalled => write!(f, "The service is not installed"),
+            PreflightError::ServiceNotRemoved => write!(f, "The service could not be removed"),
+            PreflightError::FilesMissing => write!(f, "Files the service needs are missing"),
+            PreflightError::InvalidHash => write!(f, "A service file does not match its expected hash")
+        }
+    }
+}
+
+/// Summary of what a service install, uninstall or repair changed on the system.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ServiceReport {
+    protocol: Protocol,
+    changes: Vec<String>
+}
+
+impl ServiceReport {
+    pub fn new(protocol: Protocol) -> Self {
+        ServiceReport { protocol, changes: vec![] }
+    }
+
+    pub fn push(&mut self, change: impl Into<String>) {
+        let change = change.into();
+        log::info!("{}: {}", self.protocol, change);
+        self.changes.push(change);
+    }
+
+    pub fn extend(&mut self, other: ServiceReport) {
+        self.changes.extend(other.changes);
+    }
+}
+
 lazy_static! {
-    static ref DISCORD_RPC_CLIENT: Mutex<Client> = Mutex::new(Client::new(743953368518492190));
     static ref CONFIGURATION: Mutex<Configuration> = Mutex::new(Configuration::default());
     
     static ref CONNECTED: Mutex<bool> = Mutex::new(false);
     static ref PROTOCOL_CONNECTED: Mutex<Option<Protocol>> = Mutex::new(None);
     static ref TAURI_WINDOW: Mutex<Option<Window>> = Mutex::new(None);
     static ref STATUS: Mutex<Status> = Mutex::new(Status::Disconnected);
+    /// What we're connected, or connecting, to
+    static ref DESTINATION: Mutex<Option<Destination>> = Mutex::new(None);
+    /// How the next connection handles IPv6, set along with the destination
+    static ref IPV6: Mutex<Option<Ipv6Mode>> = Mutex::new(None);
+    /// Whether the next connection turns the kill switch on, set along with the destination
+    static ref KILL_SWITCH: Mutex<bool> = Mutex::new(false);
+    /// Whether the next connection may move to TCP 443 when UDP seems blocked, set along with the destination.
+    /// Only API locations on the default transport, a chosen one is kept
+    static ref TCP_FALLBACK: Mutex<bool> = Mutex::new(false);
+    /// Entry of a multi-hop connection, set along with the destination
+    static ref HOP: Mutex<Option<Hop>> = Mutex::new(None);
+    /// Management interface of the running OpenVPN
+    static ref MANAGEMENT: Mutex<Option<Management>> = Mutex::new(None);
+    /// Commands for OpenVPN, sent by the running management session
+    static ref MANAGEMENT_COMMANDS: Mutex<Vec<String>> = Mutex::new(vec![]);
 
     static ref OPENVPN_TIMEOUT_WAITING: Mutex<bool> = Mutex::new(false);
+    static ref WIREGUARD_TIMEOUT_WAITING: Mutex<bool> = Mutex::new(false);
 }
 
 #[tauri::command]
 fn toggle_discord_rpc(enable: bool) {
-    (*CONFIGURATION.lock().unwrap()).discord_rpc = enable;
+    let mut configuration = CONFIGURATION.lock().unwrap();
+    configuration.discord_rpc = enable;
+    discord::configure(&configuration);
     log::info!("Discord RPC enabled: {}", enable);
 }
 
@@ -93,33 +251,128 @@ enum Status {
     Disconnecting
 }
 
+/// `hop` is the WireGuard config of the entry location, for multi-hop.
+/// Connecting commands are async so they run off the main thread, probing the MTU takes a few seconds.
 #[tauri::command]
-fn toggle_connection(state: bool, config: Option<String>, credentials: Option<Credentials>, window: Window) -> bool {
-    let protocol = CONFIGURATION.lock().unwrap().protocol;
+async fn toggle_connection(state: bool, config: Option<String>, credentials: Option<Credentials>, location: Option<Destination>, hop: Option<String>, hop_location: Option<Destination>, window: Window) -> bool {
+    let (protocol, transport, port) = {
+        let configuration = CONFIGURATION.lock().unwrap();
+        (configuration.protocol, configuration.transport, configuration.port)
+    };
+
+    // API configs always authenticate with the account credentials. They're fetched for the
+    // chosen transport already, only a port override is left to apply
+    let config = config.filter(|_| credentials.is_some())
+        .map(|config| match protocol {
+            Protocol::OpenVPN if port.is_some() => profile::set_transport(&config, transport, port),
+            _ => config
+        });
+
+    // The selected API location, shown in the tray afterwards
+    let recent = match (state, CONFIGURATION.lock().unwrap().location, &location) {
+        (true, Some(id), Some(destination)) => Some(Recent { target: Target::Location(id), name: destination.name.clone() }),
+        _ => None
+    };
+
+    if state {
+        *DESTINATION.lock().unwrap() = location;
+        *IPV6.lock().unwrap() = None;
+        *KILL_SWITCH.lock().unwrap() = false;
+        *TCP_FALLBACK.lock().unwrap() = transport == Transport::default() && port.is_none();
+        *HOP.lock().unwrap() = hop.map(|config| Hop { config, destination: hop_location });
+    }
+
+    let result = set_connection(state, protocol, config, credentials, app_sink(window.clone()));
+    if let Some(recent) = recent.filter(|_| result) {
+        tray::remember(&window, recent);
+    }
+
+    result
+}
+
+/// Everything that follows the connection besides the window.
+type Followers = Tee<Presence, Tee<Tray, Tee<Notifier, Verifier<Window>>>>;
+
+/// Events go to the window, and to the Discord presence, tray, notifications and exit verification which follow the connection.
+fn app_sink(window: Window) -> Tee<Window, Followers> {
+    let app = window.app_handle();
+    Tee(window.clone(), Tee(Presence, Tee(Tray(app), Tee(Notifier, Verifier(window)))))
+}
+
+/// Sets the status, then tells everything following it, which may read it back.
+fn report_status<E: EventSink>(sink: &E, status: Status) {
+    *STATUS.lock().unwrap() = status;
+    sink.emit(status);
+}
+
+/// Lifts what connecting set up around the tunnel, the IPv6 block, the kill switch and the
+/// multi-hop entry, once it's gone or never came up. Nothing would lift them later otherwise.
+fn tear_down<E: EventSink>(sink: &E) {
+    firewall::unblock();
+    firewall::unlock();
+    sink.emit(Ipv6Status(None));
+
+    if !multihop::stop() {
+        log::error!("Failed to stop the multi-hop entry");
+    }
+    *HOP.lock().unwrap() = None;
+}
+
+/// Brings the tunnel up or down. API locations and imported configs
+/// both go through here, so they share the same patching and services.
+fn set_connection<E: EventSink>(state: bool, protocol: Protocol, config: Option<String>, credentials: Option<Credentials>, sink: E) -> bool {
     let protocol_connected = *PROTOCOL_CONNECTED.lock().unwrap();
+    let protocol = if state { protocol } else { protocol_connected.unwrap_or(protocol) };
 
     let status = match state {
         true => Status::Connecting,
         false => Status::Disconnecting
     };
-    let _ = window.emit("status", status);
-    *STATUS.lock().unwrap() = status;
+    report_status(&sink, status);
+
+    // Meant for the session that's going away, a signal would restart the next one
+    MANAGEMENT_COMMANDS.lock().unwrap().clear();
 
+    // A new management interface for every connection, disconnecting watches the current one
+    let management = match state {
+        true => Management::generate(),
+        false => MANAGEMENT.lock().unwrap().clone().unwrap_or_else(Management::generate)
+    };
+
+    let mut ipv6 = None;
     let result;
     unsafe {
         result = if state {
-            config.and_then(|cfg| credentials.map(|creds| {
-                let patch = patch_config(&protocol, cfg, creds);
-                let service = if !is_service_started(&protocol) { start_service(&protocol) } else { true };
+            config.map(|cfg| {
+                // Multi-hop goes through the entry first, the exit's config is fitted inside it
+                // Only the entry meets the network then, the exit is fitted to it
+                let hop = HOP.lock().unwrap().clone();
+                let (cfg, entry) = match hop.map(|hop| multihop::prepare(&protocol, &cfg, &mtu::tune(&Protocol::WireGuard, &hop.config))) {
+                    Some(Some((exit, entry))) => (exit, Some(entry)),
+                    Some(None) => return false,
+                    None => (mtu::tune(&protocol, &cfg), None)
+                };
 
-                log::debug!("Patch: {:?}, Service: {:?}", patch, service);
+                // Blocked before the tunnels come up, so nothing slips out while connecting
+                let endpoints = format!("{}\n{}", cfg, entry.as_deref().unwrap_or_default());
+                ipv6 = firewall::apply(*IPV6.lock().unwrap(), &endpoints);
+                // Asked for, so connecting without it would leak once the tunnel drops
+                if *KILL_SWITCH.lock().unwrap() && !firewall::apply_kill_switch(&protocol, &endpoints) {
+                    return false;
+                }
+
+                let hop = entry.is_none_or(|entry| multihop::start(&entry));
+                let patch = patch_config(&protocol, cfg, credentials, &management);
+                let service = if !is_tunnel_started(&protocol) { start_tunnel(&protocol) } else { true };
+
+                log::debug!("Hop: {:?}, Patch: {:?}, Service: {:?}", hop, patch, service);
 
-                patch && service
-            })).unwrap_or(false)
+                hop && patch && service
+            }).unwrap_or(false)
         } else {
             if let Some(protocol) = protocol_connected {
-                if is_service_started(&protocol) {
-                    stop_service(&protocol)
+                if is_tunnel_started(&protocol) {
+                    stop_tunnel(&protocol)
                 } else {
                     true
                 }
@@ -131,31 +384,211 @@ fn toggle_connection(state: bool, config: Option<String>, credentials: Option<Cr
 
     if result {
         *PROTOCOL_CONNECTED.lock().unwrap() = if state { Some(protocol) } else { None };
-        
+
+        if state && protocol == Protocol::OpenVPN {
+            *MANAGEMENT.lock().unwrap() = Some(management.clone());
+        }
+
         log::info!("Successfully {}" , if state { "connected" } else { "disconnected" })
     } else {
         log::info!("Failed to {}" , if state { "connect" } else { "disconnect" })
     }
 
-    if CONFIGURATION.lock().unwrap().protocol == Protocol::WireGuard {
-        let status = if state {
-            if result { Status::Connected } else { Status::Disconnected }
+    // Lifted once disconnected, or if the tunnel never came up
+    if state && result {
+        sink.emit(Ipv6Status(ipv6));
+    } else if state || result {
+        tear_down(&sink);
+    }
+
+    if protocol == Protocol::WireGuard {
+        if state && result {
+            // Only connected once the server actually answered
+            wireguard_timeout(sink, protocol)
         } else {
-            if result { Status::Disconnected } else { Status::Connected }
-        };
+            let status = if state {
+                Status::Disconnected
+            } else {
+                if result { Status::Disconnected } else { Status::Connected }
+            };
 
-        let _ = window.emit("status", status);
+            report_status(&sink, status);
+        }
     } else {
         // Initialize 15 seconds
-        openvpn_timeout(window, state, protocol)
+        let _ = openvpn_timeout(sink, management, state, protocol);
     }
 
     return result
 }
 
+/// Starts the protocol's service, or the in-process WireGuard
+/// tunnel when built with `userspace-wireguard`.
+unsafe fn start_tunnel(protocol: &Protocol) -> bool {
+    #[cfg(all(feature = "userspace-wireguard", unix))]
+    if protocol == &Protocol::WireGuard {
+        return match wireguard::WireGuardConfig::load() {
+            Ok(config) => userspace::start(&config)
+                .map_err(|e| log::error!("Failed to start userspace WireGuard: {:?}", e))
+                .is_ok(),
+            Err(e) => {
+                log::error!("Failed to parse WireGuard config: {:?}", e);
+                false
+            }
+        };
+    }
+
+    start_service(protocol)
+}
+
+unsafe fn stop_tunnel(protocol: &Protocol) -> bool {
+    #[cfg(all(feature = "userspace-wireguard", unix))]
+    if protocol == &Protocol::WireGuard {
+        return userspace::stop();
+    }
+
+    stop_service(protocol)
+}
+
+unsafe fn is_tunnel_started(protocol: &Protocol) -> bool {
+    #[cfg(all(feature = "userspace-wireguard", unix))]
+    if protocol == &Protocol::WireGuard {
+        return userspace::is_running();
+    }
+
+    is_service_started(protocol)
+}
+
+/// The protocol of the running tunnel, or the configured one if there's none.
+fn active_protocol() -> Protocol {
+    let protocol_connected = *PROTOCOL_CONNECTED.lock().unwrap();
+    protocol_connected.unwrap_or_else(|| CONFIGURATION.lock().unwrap().protocol)
+}
+
+#[tauri::command]
+fn import_config(path: String, name: Option<String>, credentials: Option<Credentials>) -> Result<custom::CustomConfig, custom::ImportError> {
+    custom::import(&path, name, credentials)
+}
+
+#[tauri::command]
+fn list_custom_configs() -> Vec<custom::CustomConfig> {
+    custom::list()
+}
+
+/// Logs in, returning the API token.
+#[tauri::command]
+fn login(credentials: Credentials, window: Window) -> Result<String, String> {
+    session::login(credentials, &window)
+        .map(|token| token.expose().clone())
+        .map_err(|e| e.to_string())
+}
+
+/// A valid API token, logging in again with the stored credentials if needed.
+#[tauri::command]
+fn session_token(window: Window) -> Result<String, String> {
+    session::token(&window)
+        .map(|token| token.expose().clone())
+        .map_err(|e| e.to_string())
+}
+
+/// The API's address, so the window talks to the same one as the core.
+#[tauri::command]
+fn api_url() -> &'static str {
+    locations::BASE_URL
+}
+
+/// The API refused the token, returns a renewed one.
+#[tauri::command]
+fn session_rejected(token: String, window: Window) -> Result<String, String> {
+    session::rejected(&token, &window)
+        .map(|token| token.expose().clone())
+        .map_err(|e| e.to_string())
+}
+
+/// Disconnects, then forgets the session, the credentials and the configs.
+#[tauri::command]
+fn logout(window: Window) {
+    if *STATUS.lock().unwrap() != Status::Disconnected {
+        let protocol = CONFIGURATION.lock().unwrap().protocol;
+        set_connection(false, protocol, None, None, app_sink(window.clone()));
+    }
+
+    session::logout(&window);
+    tray::refresh(&window.app_handle());
+}
+
+/// API locations matching the filter, from the cache unless it's stale or `refresh` is set.
+#[tauri::command]
+fn query_locations(filter: Option<Filter>, refresh: bool, window: Window) -> Result<Vec<Location>, String> {
+    let all = session::authorized(&window, |token| locations::get(Some(token), refresh))
+        // Not logged in, or the API can't be reached
+        .or_else(|_| locations::get(None, false))
+        .map_err(|e| e.to_string())?;
+    let favourites = CONFIGURATION.lock().unwrap().favourites.clone();
+
+    // Names the favourites in the tray
+    tray::refresh(&window.app_handle());
+
+    Ok(locations::filter(&all, &filter.unwrap_or_default(), &favourites))
+}
+
+/// Adds or removes a favourite location, returning whether it's one now.
+#[tauri::command]
+fn toggle_favourite(id: i32, window: Window) -> bool {
+    let (favourite, config) = {
+        let mut configuration = CONFIGURATION.lock().unwrap();
+        let favourite = locations::toggle_favourite(&mut configuration.favourites, id);
+        write_config(&configuration);
+        (favourite, configuration.clone())
+    };
+
+    // The window saves its own copy of the config, which would drop the change otherwise
+    EventSink::emit(&window, ConfigChanged::new(&config));
+    tray::refresh(&window.app_handle());
+    favourite
+}
+
+/// Imported configs, shaped like API locations.
+#[tauri::command]
+fn custom_locations() -> Vec<Location> {
+    custom::list().iter().map(|config| config.location()).collect()
+}
+
+#[tauri::command]
+fn remove_custom_config(id: String) -> bool {
+    custom::remove(&id)
+}
+
+#[tauri::command]
+async fn connect_custom(id: String, window: Window) -> bool {
+    let config = match custom::get(&id) {
+        Some(config) => config,
+        None => {
+            log::error!("Custom config {} does not exist", id);
+            return false;
+        }
+    };
+
+    let account = CONFIGURATION.lock().unwrap().credentials.clone();
+    let credentials = config.credentials(account);
+
+    *DESTINATION.lock().unwrap() = Some(Destination::from(&config.location()));
+    *IPV6.lock().unwrap() = None;
+    *KILL_SWITCH.lock().unwrap() = false;
+    *TCP_FALLBACK.lock().unwrap() = false;
+    *HOP.lock().unwrap() = None;
+
+    let result = set_connection(true, config.protocol, custom::read(&config), credentials, app_sink(window.clone()));
+    if result {
+        tray::remember(&window, Recent { target: Target::Custom(id), name: config.name });
+    }
+
+    result
+}
+
 #[tauri::command]
 fn is_connected(window: Window) -> Option<Protocol> {
-    let _ = window.emit("status", match *PROTOCOL_CONNECTED.lock().unwrap() {
+    app_sink(window).emit(match *PROTOCOL_CONNECTED.lock().unwrap() {
         Some(_) => Status::Connected,
         None => Status::Disconnected
     });
@@ -164,14 +597,128 @@ fn is_connected(window: Window) -> Option<Protocol> {
 
 #[tauri::command]
 fn preflight_check() {
-    // Set logging var
-    std::env::set_var("RUST_LOG", "info");
-    
     unsafe {
         let protocol = CONFIGURATION.lock().unwrap().protocol;
         let preflight = preflight(&protocol);
         log::info!("Preflight check: {:?}", preflight);
+
+        if let Err(e) = preflight {
+            notifications::failure(&format!("{} is not set up", protocol), &format!("{}, connecting installs it, or repair it in the settings.", e));
+        }
+    }
+}
+
+/// Notifies when installing, removing or repairing a service failed.
+fn report_service<T>(action: &str, protocol: Protocol, result: &Result<T, PreflightError>) {
+    if let Err(e) = result {
+        notifications::action_failed(&format!("Failed to {} {}", action, protocol), &e.to_string());
+    }
+}
+
+/// Zips the logs, redacted configs and system state, for bug reports.
+#[tauri::command]
+fn export_diagnostics(path: String) -> Result<(), bundle::ExportError> {
+    log::info!("Exporting diagnostics to {}", path);
+    bundle::collect().write(std::path::Path::new(&path))
+}
+
+#[tauri::command]
+fn install_service(protocol: Protocol) -> Result<ServiceReport, PreflightError> {
+    let result = unsafe { platform::install(&protocol) };
+    report_service("install", protocol, &result);
+    result
+}
+
+#[tauri::command]
+fn uninstall_service(protocol: Protocol) -> Result<ServiceReport, PreflightError> {
+    let result = unsafe { platform::uninstall(&protocol) };
+    report_service("remove", protocol, &result);
+    let report = result?;
+
+    let mut protocol_connected = PROTOCOL_CONNECTED.lock().unwrap();
+    if *protocol_connected == Some(protocol) {
+        *protocol_connected = None;
     }
+
+    Ok(report)
+}
+
+#[tauri::command]
+fn repair_service(protocol: Protocol) -> Result<ServiceReport, PreflightError> {
+    let result = unsafe { platform::repair(&protocol) };
+    report_service("repair", protocol, &result);
+    result
+}
+
+/// Handles the headless commands, i.e. `octovpn install [openvpn|wireguard]` or `octovpn watch`.
+/// Returns the exit code if the arguments were a command, `None` to start the app.
+fn run_cli(args: &[String]) -> Option<i32> {
+    let command = args.first()?;
+
+    // Follows the tunnel, printing every event as a line of JSON
+    if command == "watch" {
+        *CONFIGURATION.lock().unwrap() = read_config();
+        detect_connected();
+
+        let _ = wireguard_thread(StdoutSink);
+        let _ = diagnostics_thread(StdoutSink);
+        let _ = openvpn_thread(StdoutSink).join();
+        return Some(0);
+    }
+
+    // Prints the cached locations as JSON, i.e. `octovpn locations --country NL --online`
+    if command == "locations" {
+        *CONFIGURATION.lock().unwrap() = read_config();
+
+        let filter = match Filter::from_args(&args[1..]) {
+            Ok(filter) => filter,
+            Err(e) => {
+                eprintln!("{}", e);
+                return Some(2);
+            }
+        };
+
+        return match locations::get(None, false) {
+            Ok(all) => {
+                let favourites = CONFIGURATION.lock().unwrap().favourites.clone();
+                println!("{}", serde_json::to_string_pretty(&locations::filter(&all, &filter, &favourites)).unwrap());
+                Some(0)
+            },
+            Err(e) => {
+                eprintln!("{}", e);
+                Some(1)
+            }
+        };
+    }
+
+    let operation: unsafe fn(&Protocol) -> Result<ServiceReport, PreflightError> = match command.as_str() {
+        "install" => platform::install,
+        "uninstall" => platform::uninstall,
+        "repair" => platform::repair,
+        _ => return None
+    };
+
+    let protocols = match args.get(1).map(|p| Protocol::from_str(p)) {
+        Some(Ok(protocol)) => vec![protocol],
+        Some(Err(e)) => {
+            eprintln!("{}", e);
+            return Some(2);
+        },
+        None => vec![Protocol::OpenVPN, Protocol::WireGuard]
+    };
+
+    let mut code = 0;
+    for protocol in protocols {
+        match unsafe { operation(&protocol) } {
+            Ok(report) => println!("{}", serde_json::to_string_pretty(&report).unwrap()),
+            Err(e) => {
+                eprintln!("Failed to {} {}: {:?}", command, protocol, e);
+                code = 1;
+            }
+        }
+    }
+
+    Some(code)
 }
 
 #[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
@@ -180,7 +727,77 @@ pub struct Configuration {
     theme: String,
     #[serde(rename = "discordRPC")]
     discord_rpc: bool,
-    protocol: Protocol
+    #[serde(default, rename = "discordPresence")]
+    discord_presence: PresenceConfig,
+    protocol: Protocol,
+    /// OpenVPN transport for API locations, profiles have their own
+    #[serde(default)]
+    transport: Transport,
+    /// Overrides the OpenVPN server port
+    #[serde(default)]
+    port: Option<u16>,
+    /// Last selected API location
+    #[serde(default)]
+    location: Option<i32>,
+    #[serde(default)]
+    profiles: Vec<Profile>,
+    /// Locations last connected to, newest first
+    #[serde(default)]
+    recent: Vec<Recent>,
+    /// Ids of favourite API locations
+    #[serde(default)]
+    favourites: Vec<i32>,
+    #[serde(default)]
+    notifications: NotificationConfig,
+    #[serde(default, rename = "autoConnect")]
+    auto_connect: AutoConnect,
+    /// Where traffic leaves is checked after connecting
+    #[serde(default)]
+    verification: VerifyConfig,
+    #[serde(default, rename = "multiHop")]
+    multi_hop: MultiHop,
+    /// Tunnel MTU, probed per network or set manually
+    #[serde(default)]
+    mtu: MtuConfig,
+    /// `error`, `warn`, `info`, `debug` or `trace`
+    #[serde(default = "default_log_level", rename = "logLevel")]
+    log_level: String,
+    #[serde(default = "default_log_format", rename = "logFormat")]
+    log_format: LogFormat,
+    /// Size in bytes after which a log file is split
+    #[serde(default = "default_log_max_size", rename = "logMaxSize")]
+    log_max_size: u64,
+    /// How many log files to keep
+    #[serde(default = "default_log_retention", rename = "logRetention")]
+    log_retention: usize
+}
+
+fn default_log_level() -> String {
+    String::from("info")
+}
+
+fn default_log_format() -> LogFormat {
+    LogFormat::Text
+}
+
+fn default_log_max_size() -> u64 {
+    5 * 1024 * 1024
+}
+
+fn default_log_retention() -> usize {
+    10
+}
+
+impl Configuration {
+    /// The config as the window gets it, without the password. The core keeps the credentials.
+    fn public(&self) -> Configuration {
+        let credentials = self.credentials.as_ref().map(|credentials| Credentials {
+            username: credentials.username.clone(),
+            password: Secret::default()
+        });
+
+        Configuration { credentials, ..self.clone() }
+    }
 }
 
 impl Default for Configuration {
@@ -189,89 +806,301 @@ impl Default for Configuration {
             credentials: None,
             theme: "dark".to_string(),
             discord_rpc: false,
-            protocol: Protocol::OpenVPN
+            discord_presence: PresenceConfig::default(),
+            protocol: Protocol::OpenVPN,
+            transport: Transport::default(),
+            port: None,
+            location: None,
+            profiles: vec![],
+            recent: vec![],
+            favourites: vec![],
+            notifications: NotificationConfig::default(),
+            auto_connect: AutoConnect::default(),
+            verification: VerifyConfig::default(),
+            multi_hop: MultiHop::default(),
+            mtu: MtuConfig::default(),
+            log_level: default_log_level(),
+            log_format: default_log_format(),
+            log_max_size: default_log_max_size(),
+            log_retention: default_log_retention()
         }
     }
 }
 
 #[tauri::command]
-fn save_config(config: Configuration) {
+fn save_config(mut config: Configuration) {
+    // The window never has the password, only the core changes the credentials
+    config.credentials = CONFIGURATION.lock().unwrap().credentials.clone();
+
+    log::set_max_level(logging::level(&config));
     write_config(&config);
-    *CONFIGURATION.lock().unwrap() = config;
+    discord::configure(&config);
+
+    let previous = std::mem::replace(&mut *CONFIGURATION.lock().unwrap(), config.clone());
+
+    // Only the selected protocol's service starts with the system
+    if previous.auto_connect.boot != config.auto_connect.boot || previous.protocol != config.protocol {
+        for protocol in [Protocol::OpenVPN, Protocol::WireGuard] {
+            if !unsafe { set_start_on_boot(&protocol) } {
+                log::error!("Failed to change whether {} starts on boot", protocol);
+            }
+        }
+    }
+
+    // Otherwise only learned on the next disconnect
+    if previous.verification != config.verification && *STATUS.lock().unwrap() == Status::Disconnected {
+        thread::spawn(verify::learn_home);
+    }
+}
+
+/// Runs the auto-connect options, once the window is logged in and can connect.
+/// Only the first call does anything, logging in again doesn't reconnect.
+#[tauri::command]
+fn auto_connect(window: Window) {
+    static STARTED: Once = Once::new();
+
+    STARTED.call_once(|| {
+        let target = {
+            let configuration = CONFIGURATION.lock().unwrap();
+            let idle = *STATUS.lock().unwrap() == Status::Disconnected;
+            configuration.recent.first().map(|recent| recent.target.clone()).filter(|_| configuration.auto_connect.launch && idle)
+        };
+
+        if let Some(target) = target {
+            log::info!("Connecting on launch");
+            EventSink::emit(&window, ConnectTo(target));
+        }
+
+        let _ = network_thread(window);
+    });
 }
 
 #[tauri::command]
 fn load_config() -> Configuration {
     let config = read_config();
     *CONFIGURATION.lock().unwrap() = config.clone();
-    return config
+    config.public()
 }
 
-fn main() {
-    // Start the Discord RPC thread
-    let _discord = discord_thread();
+#[tauri::command]
+fn list_profiles() -> Vec<Profile> {
+    CONFIGURATION.lock().unwrap().profiles.clone()
+}
 
-    // Check if one of the protocols is already started
+#[tauri::command]
+fn create_profile(mut profile: Profile) -> Profile {
+    profile.id = Profile::new_id();
+
+    let mut config = CONFIGURATION.lock().unwrap();
+    config.profiles.push(profile.clone());
+    write_config(&config);
+
+    profile
+}
+
+#[tauri::command]
+fn update_profile(profile: Profile) -> bool {
+    let mut config = CONFIGURATION.lock().unwrap();
+
+    match config.profiles.iter_mut().find(|p| p.id == profile.id) {
+        Some(existing) => *existing = profile,
+        None => return false
+    }
+
+    write_config(&config);
+    true
+}
+
+#[tauri::command]
+fn delete_profile(id: String) -> bool {
+    let mut config = CONFIGURATION.lock().unwrap();
+
+    let count = config.profiles.len();
+    config.profiles.retain(|p| p.id != id);
+    if config.profiles.len() == count {
+        return false;
+    }
+
+    write_config(&config);
+    true
+}
+
+/// Connects using a profile's target and options.
+/// API locations need their config fetched by the webview first, using the profile's transport.
+#[tauri::command]
+async fn connect_profile(id: String, config: Option<String>, location: Option<Destination>, window: Window) -> bool {
+    let (profile, account) = {
+        let configuration = CONFIGURATION.lock().unwrap();
+        (configuration.profiles.iter().find(|p| p.id == id).cloned(), configuration.credentials.clone())
+    };
+
+    let profile = match profile {
+        Some(profile) => profile,
+        None => {
+            log::error!("Profile {} does not exist", id);
+            return false;
+        }
+    };
+
+    // Imported configs are tied to their own protocol
+    let (config, credentials, protocol) = match &profile.target {
+        Target::Location(id) => {
+            let mut configuration = CONFIGURATION.lock().unwrap();
+            configuration.location = Some(*id);
+            write_config(&configuration);
+
+            *DESTINATION.lock().unwrap() = location;
+            (config, account, profile.protocol)
+        },
+        Target::Custom(custom) => match custom::get(custom) {
+            Some(custom) => {
+                *DESTINATION.lock().unwrap() = Some(Destination::from(&custom.location()));
+                (custom::read(&custom), custom.credentials(account), custom.protocol)
+            },
+            None => {
+                log::error!("Custom config {} does not exist", custom);
+                return false;
+            }
+        }
+    };
+
+    log::info!("Connecting with profile {}", profile.name);
+    let ipv6 = config.as_deref().map_or(profile.ipv6, |config| profile.ipv6_mode(&protocol, config));
+    if ipv6 != profile.ipv6 {
+        log::warn!("Not blocking IPv6, the config has no IPv4 address to keep");
+    }
+    *IPV6.lock().unwrap() = ipv6;
+    *KILL_SWITCH.lock().unwrap() = profile.kill_switch;
+    *TCP_FALLBACK.lock().unwrap() = matches!(profile.target, Target::Location(_)) && profile.transport == Transport::default() && profile.port.is_none();
+    *HOP.lock().unwrap() = None;
+
+    let config = config.map(|config| profile.apply(&protocol, config));
+    let result = set_connection(true, protocol, config, credentials, app_sink(window.clone()));
+
+    if result {
+        let name = DESTINATION.lock().unwrap().as_ref().map_or(profile.name, |destination| destination.name.clone());
+        tray::remember(&window, Recent { target: profile.target, name });
+    }
+
+    result
+}
+
+fn detect_connected() {
     unsafe {
         let openvpn = is_service_started(&Protocol::OpenVPN);
         let wireguard = is_service_started(&Protocol::WireGuard);
 
         if openvpn {
             *PROTOCOL_CONNECTED.lock().unwrap() = Some(Protocol::OpenVPN);
+            *MANAGEMENT.lock().unwrap() = Management::load();
         } else if wireguard {
             *PROTOCOL_CONNECTED.lock().unwrap() = Some(Protocol::WireGuard);
         }
+
+        if openvpn || wireguard {
+            *STATUS.lock().unwrap() = Status::Connected;
+        } else {
+            // Left on by a connection that's gone, nothing would get out until the next disconnect
+            firewall::unblock();
+            firewall::unlock();
+        }
     }
+}
 
-    let home = home::home_dir().unwrap();
-    let _ = std::fs::create_dir_all(format!("{}/{}/{}", home.to_str().unwrap(), ".octovpn", "logs"));
+fn main() {
+    // Headless commands run without the UI
+    let args = std::env::args().skip(1).collect::<Vec<String>>();
+    if let Some(code) = run_cli(&args) {
+        std::process::exit(code);
+    }
 
-    let log_dir = format!("{}/{}/{}", home.to_str().unwrap(), ".octovpn", "logs");
-    let log_files = std::fs::read_dir(log_dir.clone()).unwrap();
-    let log_name = format!("octovpn.{}", log_files.count());
+    // The log and presence settings are needed before the UI loads the config
+    let config = read_config();
+    let log_level = logging::level(&config);
+    *CONFIGURATION.lock().unwrap() = config.clone();
+
+    // Check if one of the protocols is already started
+    detect_connected();
+
+    // Start the Discord RPC thread, stopped on exit so the presence doesn't linger
+    let mut discord_thread = Some(discord::start());
 
     tauri::Builder::default()
-        .setup(|app| {
+        .setup(move |app| {
+            log::set_max_level(log_level);
+
+            notifications::init(app.config().tauri.bundle.identifier.clone());
+
             let window = app.get_window("main").unwrap();
             let _ = set_shadow(&window, true);
-            let _ = openvpn_thread(window);
+            let _ = openvpn_thread(app_sink(window.clone()));
+            let _ = diagnostics_thread(Tee(window.clone(), Notifier));
+            let _ = hop_thread(window.clone());
+            let _ = wireguard_thread(app_sink(window));
+            let _ = monitor_thread();
+
+            // Known before connecting, to recognize it if traffic leaks
+            if *STATUS.lock().unwrap() == Status::Disconnected {
+                thread::spawn(verify::learn_home);
+            }
 
             Ok(())
         })
-        .plugin(
-            Builder::new()
-                .targets([
-                    LogTarget::Stdout,
-                    LogTarget::Webview,
-                    LogTarget::Folder(log_dir.into())
-                ])
-                .log_name(log_name)
-                .filter(|metadata| metadata.target().starts_with("octovpn")) 
-                .build(),
-        )
+        .system_tray(tray::build(&config))
+        .on_system_tray_event(tray::handle)
+        // Closing only hides the window, the tray keeps control of the connection
+        .on_window_event(|event| {
+            if let WindowEvent::CloseRequested { api, .. } = event.event() {
+                let _ = event.window().hide();
+                api.prevent_close();
+            }
+        })
+        .plugin(logging::plugin(&config))
         .invoke_handler(tauri::generate_handler![
             toggle_discord_rpc,
             preflight_check,
+            export_diagnostics,
             toggle_connection,
             is_connected,
             save_config,
-            load_config
+            load_config,
+            install_service,
+            uninstall_service,
+            repair_service,
+            import_config,
+            list_custom_configs,
+            login,
+            api_url,
+            session_token,
+            session_rejected,
+            logout,
+            query_locations,
+            toggle_favourite,
+            custom_locations,
+            remove_custom_config,
+            connect_custom,
+            list_profiles,
+            create_profile,
+            update_profile,
+            delete_profile,
+            connect_profile,
+            auto_connect
         ])
-        .run(tauri::generate_context!())
-        .expect("error while running tauri application");
-}
+        .build(tauri::generate_context!())
+        .expect("error while running tauri application")
+        .run(move |_, event| {
+            if let tauri::RunEvent::Exit = event {
+                if let Some(handle) = discord_thread.take() {
+                    discord::stop(handle);
+                }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
-pub struct OpenVPNState {
-    start: u64,
-    connected: bool,
-    state: String,
-    local_ip: String,
-    remote_ip: String,
-    port: u16,
+                // Nothing would lift the block once the app is gone
+                firewall::unblock();
+            }
+        });
 }
 
-fn openvpn_timeout(window: Window, expected_success: bool, protocol: Protocol) -> () {
+fn openvpn_timeout<E: EventSink>(sink: E, management: Management, expected_success: bool, protocol: Protocol) -> thread::JoinHandle<()> {
     // Lock the timeout mutex, this is now in charge of the timeout
     *OPENVPN_TIMEOUT_WAITING.lock().unwrap() = true;
 
@@ -290,19 +1119,26 @@ fn openvpn_timeout(window: Window, expected_success: bool, protocol: Protocol) -
             // If we've tried 10 times, we're done and we failed
             if failed_tries >= max_failed_tries {
                 log::error!("Failed to connect to OpenVPN management interface");
-                let _ = window.emit("status", opposite);
+                report_status(&sink, opposite);
 
-                if expected_success { unsafe { stop_service(&protocol) } } else { unsafe { start_service(&protocol) } };
+                if expected_success {
+                    unsafe { stop_service(&protocol) };
+                    *PROTOCOL_CONNECTED.lock().unwrap() = None;
+                    tear_down(&sink);
+                } else {
+                    unsafe { start_service(&protocol) };
+                }
                 break;
             }
 
-            let client = TcpStream::connect("::1:7505");
+            // Only checks that it's listening, no need to authenticate
+            let client = management.address.connect();
 
             if (client.is_ok() && expected_success)
                 || (client.is_err() && !expected_success)
             {
                 if let Ok(c) = client {
-                    c.shutdown(std::net::Shutdown::Both).ok();
+                    c.shutdown().ok();
                 }
 
                 successful_tries += 1;
@@ -314,10 +1150,10 @@ fn openvpn_timeout(window: Window, expected_success: bool, protocol: Protocol) -
 
                     // Expected disconnect, we got one so update immediately
                     if !expected_success {
-                        let _ = window.emit::<Option<OpenVPNState>>("openvpn_status", None);
+                        sink.emit(OpenVPNStatus(None));
                     }
 
-                    let _ = window.emit("status", status);
+                    report_status(&sink, status);
                     break;
                 } else {
                     // Otherwise, wait a second and try again
@@ -335,14 +1171,72 @@ fn openvpn_timeout(window: Window, expected_success: bool, protocol: Protocol) -
 
         // Set the timeout to false
         *OPENVPN_TIMEOUT_WAITING.lock().unwrap() = false;
-    });
+    })
 }
 
-fn openvpn_thread(window: Window) -> thread::JoinHandle<()> {
+/// Follows the network for the untrusted network option.
+fn network_thread<E: EventSink>(sink: E) -> thread::JoinHandle<()> {
+    let state = || (CONFIGURATION.lock().unwrap().clone(), *STATUS.lock().unwrap());
+
+    thread::spawn(move || {
+        #[cfg(target_os = "linux")]
+        match network::NetworkManager::new() {
+            Ok(source) => network::watch(source, sink, state),
+            Err(e) => log::warn!("Not following the network, NetworkManager is unavailable: {}", e)
+        }
+
+        #[cfg(windows)]
+        network::watch(network::Netsh::new(), sink, state);
+    })
+}
+
+/// Re-establishes the tunnel when the network changes under it, or the system wakes up.
+fn monitor_thread() -> thread::JoinHandle<()> {
+    thread::spawn(|| {
+        #[cfg(target_os = "linux")]
+        let source = match monitor::Netlink::open() {
+            Ok(source) => source,
+            Err(e) => {
+                log::warn!("Not following interface changes, netlink is unavailable: {}", e);
+                return;
+            }
+        };
+
+        #[cfg(windows)]
+        let source = monitor::Polling::new();
+
+        monitor::run(source, reestablish);
+    })
+}
+
+fn reestablish(trigger: monitor::Trigger) {
+    // Still connecting, which goes through the new network anyway
+    if *OPENVPN_TIMEOUT_WAITING.lock().unwrap() || *WIREGUARD_TIMEOUT_WAITING.lock().unwrap() {
+        return;
+    }
+
+    let protocol = *PROTOCOL_CONNECTED.lock().unwrap();
+    match protocol {
+        // Restarts the connection without tearing the tunnel down
+        Some(Protocol::OpenVPN) => {
+            log::info!("{}, restarting the OpenVPN connection", trigger);
+            MANAGEMENT_COMMANDS.lock().unwrap().push(String::from("signal SIGUSR1"));
+        },
+        Some(Protocol::WireGuard) => {
+            log::info!("{}, resolving the WireGuard endpoint again", trigger);
+            if let Err(e) = wireguard::reresolve() {
+                log::error!("Failed to update the WireGuard endpoint: {:?}", e);
+            }
+        },
+        None => {}
+    }
+}
+
+fn openvpn_thread<E: EventSink>(sink: E) -> thread::JoinHandle<()> {
     thread::spawn(move || {
         loop {
             let is_timeout_active = *OPENVPN_TIMEOUT_WAITING.lock().unwrap();
-            let is_openvpn = CONFIGURATION.lock().unwrap().protocol == Protocol::OpenVPN;
+            let is_openvpn = active_protocol() == Protocol::OpenVPN;
 
             // Something else is in charge of the timeout, so we'll just wait
             if is_timeout_active || !is_openvpn {
@@ -350,152 +1244,216 @@ fn openvpn_thread(window: Window) -> thread::JoinHandle<()> {
                 continue;
             }
 
-            let client = TcpStream::connect("::1:7505");
+            let management = MANAGEMENT.lock().unwrap().clone();
+            let client = match management.map(|management| management.connect()) {
+                Some(Ok(client)) => client,
+                _ => {
+                    sink.emit(OpenVPNStatus(None));
 
-            if client.is_err() {
-                // let _ = window.emit("status", Status::Disconnected);
-                let _ = window.emit::<Option<OpenVPNState>>("openvpn_status", None);
+                    thread::sleep(Duration::from_millis(500));
+                    continue;
+                }
+            };
 
-                thread::sleep(Duration::from_millis(500));
+            // Try to get status every half-second, until the connection drops
+            if let Err(e) = management::session(client, &sink, &MANAGEMENT_COMMANDS, Duration::from_millis(500)) {
+                log::debug!("OpenVPN management session ended: {}", e);
+            }
+
+            // Don't update the status if we're waiting for a timeout
+            if *OPENVPN_TIMEOUT_WAITING.lock().unwrap() || active_protocol() != Protocol::OpenVPN {
                 continue;
             }
+            
+            sink.emit(OpenVPNStatus(None));
 
-            let mut client = client.unwrap();
-            loop {
-                let write = client.write(b"state\n");
-                if write.is_err() {
-                    // log::error!("Failed to write to OpenVPN management interface");
-                    break;
-                }
+            thread::sleep(Duration::from_millis(500));
+        }
+    })
+}
 
-                let mut buffer: [u8; 1024] = [0; 1024];
-                if client.read(&mut buffer).is_err() {
-                    // log::error!("Failed to read from OpenVPN management interface");
-                    break;
-                }
+/// Follows the OpenVPN log, reporting each known failure once per session.
+fn diagnostics_thread<E: EventSink>(sink: E) -> thread::JoinHandle<()> {
+    thread::spawn(move || {
+        let mut tail = Tail::new(diagnostics::log_path());
+        let mut reported: Vec<Cause> = vec![];
+        let mut handshakes = Handshakes::default();
 
-                // Replace final null bytes
-                let buffer = String::from_utf8_lossy(&buffer);
-                let buffer = buffer.replace("\u{0}", "");
+        loop {
+            // OpenVPN started again, it may fail the same way
+            if tail.truncated() {
+                reported.clear();
+                handshakes = Handshakes::default();
+            }
 
-                if buffer.trim().len() == 0 {
-                    // log::error!("OpenVPN management interface returned empty response");
-                    break;
+            for line in tail.read() {
+                if handshakes.fall_back(&line) {
+                    fall_back_to_tcp();
                 }
 
-                // Ignore if status is not yet available (i.e. starts with "INFO: ")
-                if buffer.starts_with(">INFO") {
-                    // log::warn!("OpenVPN management interface returned INFO response");
+                let diagnostic = match diagnostics::classify(&line) {
+                    Some(diagnostic) => diagnostic,
+                    None => continue
+                };
+
+                if reported.contains(&diagnostic.cause) {
                     continue;
                 }
 
-                // Parse into the struct
-                let split = buffer.split(',').collect::<Vec<&str>>();
-                if split.len() < 6 {
-                    // log::error!("OpenVPN management interface returned invalid response: {}", buffer);
-                    break;
-                }
+                log::warn!("{} ({})", diagnostic.message, diagnostic.line);
+                reported.push(diagnostic.cause);
+                sink.emit(diagnostic);
+            }
 
-                let start = split[0].parse::<u64>();
-                let connected = split[1].to_string() == "CONNECTED";
-                let state = split[2].to_string();
-                let local_ip = split[3].to_string();
-                let remote_ip = split[4].to_string();
-                let port = split[5].parse::<u16>();
+            thread::sleep(Duration::from_millis(500));
+        }
+    })
+}
 
-                if start.is_err() || port.is_err() {
-                    // log::error!("OpenVPN management interface returned invalid response: {}", buffer);
-                    break;
-                }
+/// Moves the running OpenVPN to TCP 443 once its handshakes keep timing out, which usually means UDP is blocked.
+/// The config is rewritten in place, OpenVPN reads it again when restarted with `SIGHUP`.
+fn fall_back_to_tcp() {
+    if active_protocol() != Protocol::OpenVPN {
+        return;
+    }
 
-                let state = OpenVPNState {
-                    start: start.unwrap(),
-                    connected,
-                    state,
-                    local_ip,
-                    remote_ip,
-                    port: port.unwrap()
-                };
+    if !*TCP_FALLBACK.lock().unwrap() {
+        log::debug!("Not falling back to TCP 443, the connection's transport was chosen");
+        return;
+    }
 
-                // log::info!("OpenVPN management interface says {:?}", state);
-                let _ = window.emit("openvpn_status", Some(state));
+    let path = config::config_path(&Protocol::OpenVPN);
+    let config = match std::fs::read_to_string(&path) {
+        Ok(config) => config,
+        Err(e) => {
+            log::error!("Failed to read the OpenVPN config: {}", e);
+            return;
+        }
+    };
 
-                // If not in an inbetween state, update the status
-                if *STATUS.lock().unwrap() != Status::Disconnecting && CONFIGURATION.lock().unwrap().protocol == Protocol::OpenVPN {
-                    // let _ = window.emit("status", Status::Connected);
-                }
+    let fallback = profile::set_transport(&config, Transport::Tcp, Some(443));
+    if fallback == config {
+        log::debug!("Already on TCP 443, not falling back");
+        return;
+    }
 
-                // Try to get status every half-second
-                thread::sleep(Duration::from_millis(500));
+    log::warn!("TLS handshakes keep failing, falling back to TCP 443");
+
+    if let Err(e) = config::write_private(&path, fallback) {
+        log::error!("Failed to write the OpenVPN config: {}", e);
+        return;
+    }
+
+    MANAGEMENT_COMMANDS.lock().unwrap().push(String::from("signal SIGHUP"));
+}
+
+/// A handshake older than this means the server stopped answering.
+/// WireGuard rekeys every 2 minutes, so an active tunnel never gets close.
+const WIREGUARD_HANDSHAKE_TIMEOUT: u64 = 180;
+
+fn has_recent_handshake(peers: &[PeerStats]) -> bool {
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+
+    peers.iter().any(|peer| peer.last_handshake.is_some_and(|time| now.saturating_sub(time) < WIREGUARD_HANDSHAKE_TIMEOUT))
+}
+
+fn wireguard_timeout<E: EventSink>(sink: E, protocol: Protocol) -> () {
+    // Lock the timeout mutex, this is now in charge of the timeout
+    *WIREGUARD_TIMEOUT_WAITING.lock().unwrap() = true;
+
+    // The first handshake happens on the first packet, give it some time
+    let max_tries = 15;
+
+    thread::spawn(move || {
+        for tries in 1..=max_tries {
+            match uapi::get() {
+                Ok(peers) if has_recent_handshake(&peers) => {
+                    log::debug!("WireGuard handshake after {} tries, we're done", tries);
+
+                    sink.emit(WireGuardStatus(peers.first().cloned()));
+                    report_status(&sink, Status::Connected);
+                    break;
+                },
+                result => log::debug!("No WireGuard handshake yet ({}/{}): {:?}", tries, max_tries, result.err())
             }
 
-            // Don't update the status if we're waiting for a timeout
-            if *OPENVPN_TIMEOUT_WAITING.lock().unwrap() || CONFIGURATION.lock().unwrap().protocol != Protocol::OpenVPN {
+            if tries == max_tries {
+                log::error!("No handshake from the WireGuard server");
+                report_status(&sink, Status::Disconnected);
+
+                unsafe { stop_tunnel(&protocol) };
+                *PROTOCOL_CONNECTED.lock().unwrap() = None;
+                tear_down(&sink);
+                break;
+            }
+
+            thread::sleep(Duration::from_secs(1));
+        }
+
+        // Set the timeout to false
+        *WIREGUARD_TIMEOUT_WAITING.lock().unwrap() = false;
+    });
+}
+
+fn wireguard_thread<E: EventSink>(sink: E) -> thread::JoinHandle<()> {
+    thread::spawn(move || {
+        let mut stale = false;
+
+        loop {
+            let is_timeout_active = *WIREGUARD_TIMEOUT_WAITING.lock().unwrap();
+            let is_wireguard = active_protocol() == Protocol::WireGuard;
+
+            // Something else is in charge of the timeout, so we'll just wait
+            if is_timeout_active || !is_wireguard {
+                thread::sleep(Duration::from_millis(500));
                 continue;
             }
-            
-            let _ = window.emit::<Option<OpenVPNState>>("openvpn_status", None);
 
-            if !vec![Status::Connecting, Status::Disconnecting].contains(&*STATUS.lock().unwrap())
-                && CONFIGURATION.lock().unwrap().protocol == Protocol::OpenVPN
-            {
-                // let _ = window.emit("status", Status::Disconnected);
+            let peers = uapi::get().unwrap_or_default();
+            sink.emit(WireGuardStatus(peers.first().cloned()));
+
+            // The server stopped (or started again) answering while the tunnel is up
+            let recent = has_recent_handshake(&peers);
+            if peers.is_empty() {
+                stale = false;
+            } else if recent == stale {
+                stale = !recent;
+
+                if stale {
+                    log::warn!("WireGuard handshake is older than {} seconds", WIREGUARD_HANDSHAKE_TIMEOUT);
+                }
+                report_status(&sink, if stale { Status::Connecting } else { Status::Connected });
             }
 
-            thread::sleep(Duration::from_millis(500));
+            thread::sleep(Duration::from_secs(1));
         }
     })
 }
 
-fn discord_thread() -> thread::JoinHandle<()> {
-    // Start a new background thread for Discord
+/// Reports both hops of a multi-hop connection whenever they change, and once more when it's gone.
+fn hop_thread<E: EventSink>(sink: E) -> thread::JoinHandle<()> {
     thread::spawn(move || {
-        // Get the current timestamp
-        let timestamp = std::time::SystemTime::now()
-            .duration_since(std::time::UNIX_EPOCH)
-            .unwrap()
-            .as_secs();
-
-        // Initialize the Discord RPC client
-        let mut client = DISCORD_RPC_CLIENT.lock().unwrap();
-
-        // Start the client
-        let _ = client.start();
+        let mut shown = None;
 
         loop {
-            let enabled = CONFIGURATION.lock().unwrap().discord_rpc;
-            let protocol_connected = *PROTOCOL_CONNECTED.lock().unwrap();
-
-            if enabled {
-                let res = client.set_activity(|activity| {
-                    activity
-                        .details("zeph's private VPN")
-                        .state(if protocol_connected.is_some() { "Connected" } else { "Disconnected" })
-                        .timestamps(|timestamps| {
-                            if protocol_connected.is_some() { timestamps.start(timestamp) } else { timestamps }
-                        })
-                        .assets(|assets| {
-                            let mut assets = assets
-                                .large_image("icon");
-
-                            if let Some(p) = protocol_connected {
-                                assets = assets.large_text(format!("Koharu (via {})", p))
-                            }
-
-                            assets
-                        })
-                });
-
-                if res.is_err() {
-                    log::error!("Failed to set Discord RPC activity!");
-                }
-            } else {
-                let _ = client.clear_activity();
+            let entry = HOP.lock().unwrap().as_ref().map(|hop| hop.destination.clone());
+            let connected = *STATUS.lock().unwrap() != Status::Disconnected;
+
+            let hops = entry.filter(|_| connected).map(|entry| Hops {
+                entry,
+                entry_peer: uapi::get_hop().ok().and_then(|peers| peers.first().cloned()),
+                exit: DESTINATION.lock().unwrap().clone()
+            });
+
+            if hops != shown {
+                sink.emit(HopStatus(hops.clone()));
+                shown = hops;
             }
 
-            // 15 seconds is the actual rate limit for Discord RPC.
-            // We'll sleep for 16 seconds to be safe.
-            thread::sleep(Duration::from_secs(16));
+            thread::sleep(Duration::from_secs(1));
         }
     })
-}
\ No newline at end of file
+}
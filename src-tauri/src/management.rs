@@ -0,0 +1,396 @@
+#[cfg(unix)]
+use std::{os::unix::net::UnixStream, path::PathBuf};
+use std::{io::{self, BufRead, BufReader, ErrorKind, Read, Write}, net::{SocketAddr, TcpStream, ToSocketAddrs}, sync::Mutex, thread, time::Duration};
+
+use serde::{Serialize, Deserialize};
+
+use crate::{config::HOME, events::{EventSink, OpenVPNStatus}, secret::Secret};
+
+/// Where the management interface listens.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum Address {
+    Tcp(SocketAddr),
+    #[cfg(unix)]
+    Unix(PathBuf)
+}
+
+/// A connection to the management interface, over either kind of address.
+#[derive(Debug)]
+pub enum Stream {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream)
+}
+
+/// The management interface of one connection, so other local processes
+/// can't drive OpenVPN and several clients don't fight over the same port.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Management {
+    pub address: Address,
+    /// Asked for before anything else, `None` if OpenVPN doesn't require one
+    pub password: Option<Secret<String>>
+}
+
+impl Address {
+    pub fn connect(&self) -> io::Result<Stream> {
+        match self {
+            Address::Tcp(address) => TcpStream::connect(address).map(Stream::Tcp),
+            #[cfg(unix)]
+            Address::Unix(path) => UnixStream::connect(path).map(Stream::Unix)
+        }
+    }
+}
+
+impl Stream {
+    pub fn try_clone(&self) -> io::Result<Stream> {
+        match self {
+            Stream::Tcp(stream) => stream.try_clone().map(Stream::Tcp),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.try_clone().map(Stream::Unix)
+        }
+    }
+
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.set_read_timeout(timeout),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.set_read_timeout(timeout)
+        }
+    }
+
+    pub fn shutdown(&self) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.shutdown(std::net::Shutdown::Both),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.shutdown(std::net::Shutdown::Both)
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => stream.read(buf),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.read(buf)
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => stream.write(buf),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.write(buf)
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.flush(),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.flush()
+        }
+    }
+}
+
+fn openvpn_dir() -> String {
+    format!("{}/.octovpn/openvpn", HOME.to_str().unwrap())
+}
+
+/// Paths in the config are quoted, with forward slashes so Windows ones aren't read as escapes.
+fn quote(path: &str) -> String {
+    format!("\"{}\"", path.replace('\\', "/"))
+}
+
+/// Splits a config line into its arguments, keeping quoted ones together.
+fn arguments(line: &str) -> Vec<String> {
+    let mut arguments = vec![];
+    let mut current = String::new();
+    let mut quoted = false;
+
+    for c in line.chars() {
+        match c {
+            '"' => quoted = !quoted,
+            c if c.is_whitespace() && !quoted => {
+                if !current.is_empty() {
+                    arguments.push(std::mem::take(&mut current));
+                }
+            },
+            c => current.push(c)
+        }
+    }
+
+    if !current.is_empty() {
+        arguments.push(current);
+    }
+
+    arguments
+}
+
+/// Reads until the received bytes end with `pattern`.
+/// Byte by byte, so nothing meant for the session gets buffered away.
+fn read_until(stream: &mut Stream, pattern: &[u8]) -> io::Result<String> {
+    let mut received = vec![];
+    let mut byte = [0u8; 1];
+
+    while !received.ends_with(pattern) {
+        if stream.read(&mut byte)? == 0 {
+            return Err(ErrorKind::UnexpectedEof.into());
+        }
+        received.push(byte[0]);
+    }
+
+    Ok(String::from_utf8_lossy(&received).to_string())
+}
+
+impl Management {
+    /// Picks a new address and password, a Unix socket on Linux
+    /// and a free local port elsewhere.
+    pub fn generate() -> Self {
+        // A fresh name each time, so a socket still held by an older OpenVPN is never reused
+        #[cfg(target_os = "linux")]
+        let address = Address::Unix(PathBuf::from(format!("{}/management-{:08x}.sock", openvpn_dir(), rand::random::<u32>())));
+
+        // The port is released right away, OpenVPN binds it again a moment later. Something
+        // else may take it in between, then OpenVPN fails to start and the connection times
+        // out like any other failed start, the next attempt picking another port.
+        #[cfg(not(target_os = "linux"))]
+        let address = Address::Tcp(std::net::TcpListener::bind("127.0.0.1:0")
+            .and_then(|listener| listener.local_addr())
+            .unwrap_or_else(|_| SocketAddr::from(([127, 0, 0, 1], 7505))));
+
+        Management {
+            address,
+            password: Some(Secret::new(format!("{:016x}{:016x}", rand::random::<u64>(), rand::random::<u64>())))
+        }
+    }
+
+    fn password_path() -> String {
+        format!("{}/management.pwd", openvpn_dir())
+    }
+
+    /// Writes the password file and returns the directives pointing OpenVPN to it.
+    pub fn prepare(&self) -> Option<String> {
+        let mut directive = match &self.address {
+            Address::Tcp(address) => format!("management {} {}", address.ip(), address.port()),
+            #[cfg(unix)]
+            Address::Unix(path) => format!("management {} unix", quote(path.to_str()?))
+        };
+
+        if let Some(password) = &self.password {
+            let mut options = std::fs::OpenOptions::new();
+            options.write(true).create(true).truncate(true);
+            #[cfg(unix)]
+            std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+
+            options.open(Self::password_path())
+                .and_then(|mut file| file.write_all(password.expose().as_bytes()))
+                .ok()?;
+
+            directive.push_str(&format!(" {}", quote(&Self::password_path())));
+        }
+
+        #[cfg(unix)]
+        if let Address::Unix(path) = &self.address {
+            // Sockets left behind by crashes, nothing answers on them anymore
+            let stale = std::fs::read_dir(openvpn_dir()).into_iter().flatten().flatten()
+                .map(|entry| entry.path())
+                .filter(|other| other != path && other.file_name().and_then(|name| name.to_str())
+                    .is_some_and(|name| name.starts_with("management-") && name.ends_with(".sock")));
+            for other in stale {
+                if UnixStream::connect(&other).is_err() {
+                    let _ = std::fs::remove_file(other);
+                }
+            }
+
+            // OpenVPN runs as root, only let the user running the app in
+            if let Ok(user) = std::env::var("USER") {
+                directive.push_str(&format!("\nmanagement-client-user {}", user));
+            }
+        }
+
+        Some(directive)
+    }
+
+    /// Reads back the directive written by `patch_config`, to find an OpenVPN started earlier.
+    pub fn load() -> Option<Self> {
+        Self::from_config(&std::fs::read_to_string(format!("{}/config.ovpn", openvpn_dir())).ok()?)
+    }
+
+    pub fn from_config(config: &str) -> Option<Self> {
+        let arguments = config.lines()
+            .rev()
+            .map(|line| arguments(line.trim()))
+            .find(|arguments| arguments.first().map(|a| a.as_str()) == Some("management"))?;
+
+        let address = match (arguments.get(1)?, arguments.get(2)?) {
+            #[cfg(unix)]
+            (path, unix) if unix == "unix" => Address::Unix(PathBuf::from(path)),
+            (host, port) => Address::Tcp((host.as_str(), port.parse::<u16>().ok()?).to_socket_addrs().ok()?.next()?)
+        };
+
+        let password = match arguments.get(3) {
+            Some(path) => Some(Secret::new(std::fs::read_to_string(path).ok()?.lines().next()?.to_string())),
+            None => None
+        };
+
+        Some(Management { address, password })
+    }
+
+    /// Connects and authenticates, the stream is then ready for `session`.
+    pub fn connect(&self) -> io::Result<Stream> {
+        let mut stream = self.address.connect()?;
+
+        if let Some(password) = &self.password {
+            stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+
+            // The prompt doesn't end with a newline
+            read_until(&mut stream, b"ENTER PASSWORD:")?;
+            stream.write_all(format!("{}\n", password.expose()).as_bytes())?;
+
+            let reply = read_until(&mut stream, b"\n")?;
+            if !matches!(parse_line(reply.trim()), Some(Message::Success(_))) {
+                let _ = stream.shutdown();
+                return Err(ErrorKind::PermissionDenied.into());
+            }
+        }
+
+        Ok(stream)
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize, Clone)]
+pub struct OpenVPNState {
+    pub start: u64,
+    pub connected: bool,
+    pub state: String,
+    pub local_ip: String,
+    pub remote_ip: String,
+    /// Only known once connected, `RECONNECTING`, `WAIT` and `AUTH` leave it empty
+    pub port: Option<u16>,
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize, Clone)]
+pub struct Bytecount {
+    pub rx_bytes: u64,
+    pub tx_bytes: u64
+}
+
+/// A line sent by the management interface.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum Message {
+    /// The banner, or any other `>INFO:`
+    Info(String),
+    /// A reply to `state`, or a `>STATE:` notification
+    State(OpenVPNState),
+    Bytecount(Bytecount),
+    Success(String),
+    Error(String),
+    /// Ends a multi-line reply
+    End
+}
+
+/// Parses a state line, i.e. `1700000000,CONNECTED,SUCCESS,10.8.0.2,1.2.3.4,1194,,`
+pub fn parse_state(line: &str) -> Option<OpenVPNState> {
+    let split = line.split(',').collect::<Vec<&str>>();
+    if split.len() < 6 {
+        return None;
+    }
+
+    Some(OpenVPNState {
+        start: split[0].parse::<u64>().ok()?,
+        connected: split[1] == "CONNECTED",
+        state: split[2].to_string(),
+        local_ip: split[3].to_string(),
+        remote_ip: split[4].to_string(),
+        port: match split[5] {
+            "" => None,
+            port => Some(port.parse::<u16>().ok()?)
+        }
+    })
+}
+
+/// Parses a single line, without its line ending. Unknown lines are `None`.
+pub fn parse_line(line: &str) -> Option<Message> {
+    if let Some(info) = line.strip_prefix(">INFO:") {
+        return Some(Message::Info(info.to_string()));
+    }
+
+    if let Some(state) = line.strip_prefix(">STATE:") {
+        return parse_state(state).map(Message::State);
+    }
+
+    if let Some(bytecount) = line.strip_prefix(">BYTECOUNT:") {
+        let (rx, tx) = bytecount.split_once(',')?;
+        return Some(Message::Bytecount(Bytecount {
+            rx_bytes: rx.parse().ok()?,
+            tx_bytes: tx.parse().ok()?
+        }));
+    }
+
+    if let Some(success) = line.strip_prefix("SUCCESS:") {
+        return Some(Message::Success(success.trim().to_string()));
+    }
+
+    if let Some(error) = line.strip_prefix("ERROR:") {
+        return Some(Message::Error(error.trim().to_string()));
+    }
+
+    if line == "END" {
+        return Some(Message::End);
+    }
+
+    parse_state(line).map(Message::State)
+}
+
+/// Runs one session with the management interface, asking for the state
+/// every `interval` and emitting what comes back until the connection drops.
+/// Queued `commands` are sent along, OpenVPN only takes one client at a time.
+pub fn session<E: EventSink>(stream: Stream, sink: &E, commands: &Mutex<Vec<String>>, interval: Duration) -> io::Result<()> {
+    stream.set_read_timeout(Some(interval))?;
+
+    let mut writer = stream.try_clone()?;
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+
+    writer.write_all(b"bytecount 1\n")?;
+
+    loop {
+        for command in commands.lock().unwrap().drain(..) {
+            log::debug!("Sending {} to OpenVPN", command);
+            writer.write_all(format!("{}\n", command).as_bytes())?;
+        }
+
+        writer.write_all(b"state\n")?;
+
+        // Read until the reply ends, notifications can come in between
+        loop {
+            match reader.read_line(&mut line) {
+                Ok(0) => return Ok(()),
+                Ok(_) => {},
+                // Nothing more for now, a partial line stays in the buffer
+                Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => break,
+                Err(e) => return Err(e)
+            }
+
+            if !line.ends_with('\n') {
+                continue;
+            }
+
+            let message = parse_line(line.trim_end());
+            line.clear();
+
+            match message {
+                Some(Message::State(state)) => sink.emit(OpenVPNStatus(Some(state))),
+                Some(Message::Bytecount(bytecount)) => sink.emit(bytecount),
+                Some(Message::Error(error)) => log::warn!("OpenVPN management interface returned an error: {}", error),
+                Some(Message::End) => break,
+                _ => {}
+            }
+        }
+
+        thread::sleep(interval);
+    }
+}
@@ -0,0 +1,262 @@
+use std::{io::ErrorKind, os::unix::net::{UnixListener, UnixStream}, path::{Path, PathBuf}, sync::{Arc, Mutex, atomic::{AtomicBool, Ordering}}, thread, time::{Duration, Instant}};
+
+use serde_json::{json, Value};
+
+use crate::discord_ipc::{read_frame, write_frame, CLOSE, FRAME, HANDSHAKE, PING, PONG};
+
+// Fake of Discord's IPC socket, answering the handshake, commands and pings like
+// the client does, so the presence lifecycle can be tested without Discord.
+
+pub struct MockDiscord {
+    path: PathBuf,
+    frames: Arc<Mutex<Vec<(u32, Value)>>>,
+    streams: Arc<Mutex<Vec<UnixStream>>>,
+    stopped: Arc<AtomicBool>,
+    handle: thread::JoinHandle<()>
+}
+
+/// A socket path no other test uses.
+pub fn socket_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("octovpn-test-{}-{}", std::process::id(), name))
+}
+
+fn serve(mut stream: UnixStream, frames: Arc<Mutex<Vec<(u32, Value)>>>) {
+    while let Ok((opcode, payload)) = read_frame(&mut stream) {
+        frames.lock().unwrap().push((opcode, payload.clone()));
+
+        let reply = match opcode {
+            HANDSHAKE => write_frame(&mut stream, FRAME, &json!({ "cmd": "DISPATCH", "evt": "READY", "data": { "v": 1 } })),
+            FRAME => write_frame(&mut stream, FRAME, &json!({
+                "cmd": payload["cmd"],
+                "evt": null,
+                "data": payload["args"]["activity"],
+                "nonce": payload["nonce"]
+            })),
+            PING => write_frame(&mut stream, PONG, &payload),
+            CLOSE => return,
+            _ => Ok(())
+        };
+
+        if reply.is_err() {
+            return;
+        }
+    }
+}
+
+impl MockDiscord {
+    /// Listens on `path` until stopped, like a running Discord client.
+    pub fn start(path: &Path) -> Self {
+        let _ = std::fs::remove_file(path);
+        let listener = UnixListener::bind(path).unwrap();
+        listener.set_nonblocking(true).unwrap();
+
+        let frames = Arc::new(Mutex::new(vec![]));
+        let streams = Arc::new(Mutex::new(vec![]));
+        let stopped = Arc::new(AtomicBool::new(false));
+
+        let (received, accepted, stop) = (frames.clone(), streams.clone(), stopped.clone());
+        let handle = thread::spawn(move || {
+            while !stop.load(Ordering::SeqCst) {
+                match listener.accept() {
+                    Ok((stream, _)) => {
+                        stream.set_nonblocking(false).unwrap();
+                        accepted.lock().unwrap().push(stream.try_clone().unwrap());
+
+                        let received = received.clone();
+                        thread::spawn(move || serve(stream, received));
+                    },
+                    Err(e) if e.kind() == ErrorKind::WouldBlock => thread::sleep(Duration::from_millis(5)),
+                    Err(_) => return
+                }
+            }
+        });
+
+        MockDiscord { path: path.to_path_buf(), frames, streams, stopped, handle }
+    }
+
+    /// Every frame received so far, from every connection.
+    pub fn frames(&self) -> Vec<(u32, Value)> {
+        self.frames.lock().unwrap().clone()
+    }
+
+    /// The activities set so far, `null` when cleared.
+    pub fn activities(&self) -> Vec<Value> {
+        self.frames().into_iter()
+            .filter(|(opcode, payload)| *opcode == FRAME && payload["cmd"] == "SET_ACTIVITY")
+            .map(|(_, payload)| payload["args"]["activity"].clone())
+            .collect()
+    }
+
+    /// Polls until the frames match, for at most a few seconds.
+    pub fn wait_for<F: Fn(&[(u32, Value)]) -> bool>(&self, condition: F) -> bool {
+        let deadline = Instant::now() + Duration::from_secs(5);
+
+        while Instant::now() < deadline {
+            if condition(&self.frames()) {
+                return true;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        false
+    }
+
+    /// Quits, dropping every connection like Discord closing.
+    pub fn stop(self) {
+        self.stopped.store(true, Ordering::SeqCst);
+        self.handle.join().unwrap();
+
+        for stream in self.streams.lock().unwrap().iter() {
+            let _ = stream.shutdown(std::net::Shutdown::Both);
+        }
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::mpsc::Sender, time::Duration};
+
+    use super::*;
+    use crate::{Status, discord::{self, Options, PresenceConfig, Update}, discord_ipc::Connection};
+
+    fn options(path: &Path) -> Options {
+        Options {
+            paths: vec![path.to_path_buf()],
+            rate_limit: Duration::from_millis(10),
+            backoff: Duration::from_millis(20),
+            max_backoff: Duration::from_millis(100),
+            ping_interval: Duration::from_millis(50)
+        }
+    }
+
+    fn enable(sender: &Sender<Update>, enabled: bool) {
+        sender.send(Update::Settings { enabled, presence: PresenceConfig::default() }).unwrap();
+    }
+
+    fn count(frames: &[(u32, Value)], opcode: u32) -> usize {
+        frames.iter().filter(|(op, _)| *op == opcode).count()
+    }
+
+    #[test]
+    fn sets_activity_after_handshake() {
+        let path = socket_path("discord-handshake");
+        let server = MockDiscord::start(&path);
+        let (sender, handle) = discord::spawn(options(&path));
+
+        enable(&sender, true);
+        sender.send(Update::Status(Status::Connecting)).unwrap();
+
+        assert!(server.wait_for(|frames| frames.iter().any(|(_, payload)| payload["args"]["activity"]["details"] == "Connecting...")));
+
+        let frames = server.frames();
+        assert_eq!(frames[0], (HANDSHAKE, json!({ "v": 1, "client_id": "743953368518492190" })));
+        assert_eq!(frames[1].1["args"]["pid"], std::process::id());
+
+        sender.send(Update::Shutdown).unwrap();
+        handle.join().unwrap();
+        server.stop();
+    }
+
+    #[test]
+    fn skips_sockets_failing_the_handshake() {
+        // Accepts, then hangs up without answering, like a crashed client's socket
+        let stale = socket_path("discord-stale");
+        let _ = std::fs::remove_file(&stale);
+        let listener = UnixListener::bind(&stale).unwrap();
+        let hang_up = thread::spawn(move || drop(listener.accept()));
+
+        let path = socket_path("discord-after-stale");
+        let server = MockDiscord::start(&path);
+
+        let connection = Connection::open(&[stale.clone(), path.clone()], "743953368518492190");
+        hang_up.join().unwrap();
+        let _ = std::fs::remove_file(&stale);
+
+        assert!(connection.is_ok());
+        assert_eq!(count(&server.frames(), HANDSHAKE), 1);
+        server.stop();
+    }
+
+    #[test]
+    fn connects_once_discord_starts() {
+        let path = socket_path("discord-late");
+        let _ = std::fs::remove_file(&path);
+        let (sender, handle) = discord::spawn(options(&path));
+
+        enable(&sender, true);
+        thread::sleep(Duration::from_millis(150));
+
+        let server = MockDiscord::start(&path);
+        assert!(server.wait_for(|frames| count(frames, FRAME) > 0));
+
+        sender.send(Update::Shutdown).unwrap();
+        handle.join().unwrap();
+        server.stop();
+    }
+
+    #[test]
+    fn reconnects_after_discord_restarts() {
+        let path = socket_path("discord-restart");
+        let server = MockDiscord::start(&path);
+        let (sender, handle) = discord::spawn(options(&path));
+
+        enable(&sender, true);
+        assert!(server.wait_for(|frames| count(frames, FRAME) > 0));
+        server.stop();
+
+        // The next ping fails, then it keeps retrying until Discord is back
+        thread::sleep(Duration::from_millis(200));
+        let server = MockDiscord::start(&path);
+
+        assert!(server.wait_for(|frames| count(frames, HANDSHAKE) == 1 && count(frames, FRAME) > 0));
+        assert_eq!(server.activities()[0]["details"], "Not connected");
+
+        sender.send(Update::Shutdown).unwrap();
+        handle.join().unwrap();
+        server.stop();
+    }
+
+    #[test]
+    fn disabling_clears_and_closes() {
+        let path = socket_path("discord-disable");
+        let server = MockDiscord::start(&path);
+        let (sender, handle) = discord::spawn(options(&path));
+
+        enable(&sender, true);
+        assert!(server.wait_for(|frames| count(frames, FRAME) > 0));
+
+        enable(&sender, false);
+        assert!(server.wait_for(|frames| count(frames, CLOSE) == 1));
+        assert_eq!(server.activities().last(), Some(&Value::Null));
+
+        // Nothing reconnects while disabled
+        thread::sleep(Duration::from_millis(200));
+        assert_eq!(count(&server.frames(), HANDSHAKE), 1);
+
+        enable(&sender, true);
+        assert!(server.wait_for(|frames| count(frames, HANDSHAKE) == 2));
+
+        sender.send(Update::Shutdown).unwrap();
+        handle.join().unwrap();
+        // The server reads on its own thread, the close may not be in yet
+        assert!(server.wait_for(|frames| count(frames, CLOSE) == 2));
+        server.stop();
+    }
+
+    #[test]
+    fn stays_idle_until_enabled() {
+        let path = socket_path("discord-idle");
+        let server = MockDiscord::start(&path);
+        let (sender, handle) = discord::spawn(options(&path));
+
+        sender.send(Update::Status(Status::Connected)).unwrap();
+        thread::sleep(Duration::from_millis(150));
+        assert!(server.frames().is_empty());
+
+        // Dropping the sender stops the thread as well
+        drop(sender);
+        handle.join().unwrap();
+        server.stop();
+    }
+}
@@ -0,0 +1,83 @@
+use std::{io::{Read, Write}, net::{TcpListener, TcpStream}, thread};
+
+// Minimal HTTP server for the tests of the API clients, answering each request
+// on a local port with whatever the test makes of it.
+
+/// Reads a whole request, the body included, which may come in several reads.
+pub fn read_request(stream: &mut TcpStream) -> String {
+    let mut request = vec![];
+    let mut buffer = [0u8; 1024];
+
+    loop {
+        let text = String::from_utf8_lossy(&request).to_string();
+        if let Some(end) = text.find("\r\n\r\n") {
+            let length = text[..end].lines()
+                .find_map(|line| line.to_lowercase().strip_prefix("content-length:").map(|length| length.trim().to_string()))
+                .and_then(|length| length.parse::<usize>().ok())
+                .unwrap_or(0);
+
+            if request.len() >= end + 4 + length {
+                return text;
+            }
+        }
+
+        match stream.read(&mut buffer) {
+            Ok(0) | Err(_) => return text,
+            Ok(length) => request.extend_from_slice(&buffer[..length])
+        }
+    }
+}
+
+/// A response with a JSON body, `headers` being extra lines like `ETag: "v1"`.
+pub fn response(status: &str, headers: &[&str], body: &str) -> String {
+    let mut response = format!("HTTP/1.1 {}\r\n", status);
+    for header in headers {
+        response.push_str(&format!("{}\r\n", header));
+    }
+
+    if !body.is_empty() {
+        response.push_str(&format!("Content-Type: application/json\r\nContent-Length: {}\r\n", body.len()));
+    }
+    response.push_str(&format!("Connection: close\r\n\r\n{}", body));
+
+    response
+}
+
+/// Listens on a free local port, answering every request with `respond`'s response.
+/// Returns the URL of `path` on it.
+pub fn serve<F: Fn(&str) -> String + Send + 'static>(path: &str, respond: F) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+
+    thread::spawn(move || {
+        for mut stream in listener.incoming().flatten() {
+            let request = read_request(&mut stream);
+            let _ = stream.write_all(respond(&request).as_bytes());
+        }
+    });
+
+    format!("http://{}{}", address, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_the_body_after_the_headers() {
+        let url = serve("/echo", |request| response("200 OK", &[], request.split("\r\n\r\n").nth(1).unwrap_or_default()));
+        let address = url.trim_start_matches("http://").trim_end_matches("/echo").to_string();
+
+        let mut stream = TcpStream::connect(address).unwrap();
+        // Split so the body arrives in a separate read
+        stream.write_all(b"POST /echo HTTP/1.1\r\nContent-Length: 13\r\n\r\n").unwrap();
+        stream.flush().unwrap();
+        thread::sleep(std::time::Duration::from_millis(50));
+        stream.write_all(b"{\"a\": \"b}}\"}").unwrap();
+        stream.write_all(b"\n").unwrap();
+
+        let mut answer = String::new();
+        stream.read_to_string(&mut answer).unwrap();
+        assert!(answer.ends_with("\r\n\r\n{\"a\": \"b}}\"}\n"), "{}", answer);
+    }
+}
@@ -0,0 +1,373 @@
+use std::{io::{BufRead, BufReader, Write}, net::{TcpListener, TcpStream}, sync::{Arc, Mutex, mpsc::Receiver}, thread, time::Duration};
+
+use crate::{management::{Address, Management, Stream}, secret::Secret};
+
+// Scriptable fake of the OpenVPN management interface, replaying recorded sessions
+// over a local TCP socket so the status logic can be tested without OpenVPN.
+
+pub const BANNER: &str = ">INFO:OpenVPN Management Interface Version 5 -- type 'help' for more info\r\n";
+
+#[derive(Debug, Clone)]
+pub enum Step {
+    /// Sends raw bytes, which don't need to be a full line
+    Send(&'static str),
+    /// Waits for the client to send this command
+    Expect(&'static str),
+    Sleep(Duration),
+    /// Closes the connection
+    Disconnect
+}
+
+pub struct MockManagement {
+    address: String,
+    commands: Arc<Mutex<Vec<String>>>,
+    handle: thread::JoinHandle<()>
+}
+
+impl MockManagement {
+    /// Listens on a free local port and plays the script to the first client.
+    pub fn start(script: Vec<Step>) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let commands = Arc::new(Mutex::new(vec![]));
+
+        let received = commands.clone();
+        let handle = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+
+            for step in script {
+                match step {
+                    Step::Send(data) => {
+                        stream.write_all(data.as_bytes()).unwrap();
+                        stream.flush().unwrap();
+                    },
+                    Step::Expect(command) => loop {
+                        let mut line = String::new();
+                        if reader.read_line(&mut line).unwrap_or(0) == 0 {
+                            return;
+                        }
+
+                        let line = line.trim().to_string();
+                        received.lock().unwrap().push(line.clone());
+                        if line == command {
+                            break;
+                        }
+                    },
+                    Step::Sleep(duration) => thread::sleep(duration),
+                    Step::Disconnect => {
+                        let _ = stream.shutdown(std::net::Shutdown::Both);
+                        return;
+                    }
+                }
+            }
+        });
+
+        MockManagement { address, commands, handle }
+    }
+
+    /// Accepts connections without ever answering, like OpenVPN while it starts up.
+    pub fn listen() -> (Management, TcpListener) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let management = Management { address: Address::Tcp(listener.local_addr().unwrap()), password: None };
+        (management, listener)
+    }
+
+    pub fn connect(&self) -> Stream {
+        Stream::Tcp(TcpStream::connect(&self.address).unwrap())
+    }
+
+    pub fn management(&self, password: Option<&str>) -> Management {
+        Management {
+            address: Address::Tcp(self.address.parse().unwrap()),
+            password: password.map(|password| Secret::new(password.to_string()))
+        }
+    }
+
+    /// Waits for the script to finish, returning the commands the client sent.
+    pub fn finish(self) -> Vec<String> {
+        self.handle.join().unwrap();
+        let commands = self.commands.lock().unwrap().clone();
+        commands
+    }
+}
+
+/// Takes every event sent so far.
+pub fn received(events: &Receiver<(&'static str, serde_json::Value)>) -> Vec<(&'static str, serde_json::Value)> {
+    events.try_iter().collect()
+}
+
+pub fn named(events: &[(&'static str, serde_json::Value)], event: &str) -> Vec<serde_json::Value> {
+    events.iter()
+        .filter(|(name, _)| *name == event)
+        .map(|(_, payload)| payload.clone())
+        .collect()
+}
+
+/// Held by tests going through the connection's globals, like `STATUS`, which would
+/// otherwise see each other's writes when run in parallel.
+static GLOBALS: Mutex<()> = Mutex::new(());
+
+/// Locks `GLOBALS`, even if a test failed while holding it.
+pub fn lock_globals() -> std::sync::MutexGuard<'static, ()> {
+    GLOBALS.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// A client connecting, getting its state, then OpenVPN going away.
+pub fn connected_session() -> Vec<Step> {
+    vec![
+        Step::Send(BANNER),
+        Step::Expect("bytecount 1"),
+        Step::Send("SUCCESS: bytecount interval changed\r\n"),
+        Step::Expect("state"),
+        Step::Send(">BYTECOUNT:1024,2048\r\n"),
+        Step::Send("1700000000,CONNECTED,SUCCESS,10.8.0.2,198.51.100.7,1194,,\r\nEND\r\n"),
+        Step::Expect("state"),
+        Step::Disconnect
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::TcpListener, time::Duration};
+
+    use serde_json::json;
+
+    use super::*;
+    use crate::{HOP, PROTOCOL_CONNECTED, events::ChannelSink, management::{self, Message, parse_line}, multihop::Hop, openvpn_timeout, Protocol, Status};
+
+    const INTERVAL: Duration = Duration::from_millis(50);
+
+    #[test]
+    fn parses_recorded_lines() {
+        assert!(matches!(parse_line(BANNER.trim_end()), Some(Message::Info(_))));
+        assert_eq!(parse_line("END"), Some(Message::End));
+        assert_eq!(parse_line("ERROR: unknown command"), Some(Message::Error(String::from("unknown command"))));
+
+        // The states in between have no addresses or port yet
+        for state in ["RECONNECTING,tls-error", "WAIT,", "AUTH,"] {
+            match parse_line(&format!(">STATE:1700000000,{},,,,,", state)) {
+                Some(Message::State(state)) => {
+                    assert!(!state.connected);
+                    assert_eq!(state.remote_ip, "");
+                    assert_eq!(state.port, None);
+                },
+                other => panic!("expected a state without a port, got {:?}", other)
+            }
+        }
+        assert_eq!(parse_line(">STATE:1700000000,CONNECTED,SUCCESS,10.8.0.2,198.51.100.7,port,,"), None);
+
+        match parse_line(">STATE:1700000000,CONNECTED,SUCCESS,10.8.0.2,198.51.100.7,1194,,") {
+            Some(Message::State(state)) => {
+                assert!(state.connected);
+                assert_eq!(state.remote_ip, "198.51.100.7");
+                assert_eq!(state.port, Some(1194));
+            },
+            other => panic!("expected a state, got {:?}", other)
+        }
+
+        match parse_line(">BYTECOUNT:1024,2048") {
+            Some(Message::Bytecount(bytecount)) => assert_eq!((bytecount.rx_bytes, bytecount.tx_bytes), (1024, 2048)),
+            other => panic!("expected a bytecount, got {:?}", other)
+        }
+    }
+
+    #[test]
+    fn session_emits_state_and_bytecount() {
+        let server = MockManagement::start(connected_session());
+        let (sink, events) = ChannelSink::new();
+
+        management::session(server.connect(), &sink, &Mutex::new(vec![]), INTERVAL).unwrap();
+
+        let events = received(&events);
+        let states = named(&events, "openvpn_status");
+        assert_eq!(states.len(), 1);
+        assert_eq!(states[0]["state"], "SUCCESS");
+        assert_eq!(states[0]["local_ip"], "10.8.0.2");
+
+        assert_eq!(named(&events, "openvpn_bytecount"), vec![json!({ "rx_bytes": 1024, "tx_bytes": 2048 })]);
+        assert_eq!(server.finish(), vec!["bytecount 1", "state", "state"]);
+    }
+
+    #[test]
+    fn session_authenticates_with_password() {
+        let mut script = vec![
+            Step::Send("ENTER PASSWORD:"),
+            Step::Expect("secret"),
+            Step::Send("SUCCESS: password is correct\r\n")
+        ];
+        script.extend(connected_session());
+
+        let server = MockManagement::start(script);
+        let (sink, events) = ChannelSink::new();
+
+        let stream = server.management(Some("secret")).connect().unwrap();
+        management::session(stream, &sink, &Mutex::new(vec![]), INTERVAL).unwrap();
+
+        assert_eq!(named(&received(&events), "openvpn_status").len(), 1);
+        assert_eq!(server.finish(), vec!["secret", "bytecount 1", "state", "state"]);
+    }
+
+    #[test]
+    fn session_sends_queued_commands() {
+        let server = MockManagement::start(vec![
+            Step::Send(BANNER),
+            Step::Expect("bytecount 1"),
+            Step::Expect("state"),
+            Step::Send("END\r\n"),
+            Step::Expect("signal SIGUSR1"),
+            Step::Send("SUCCESS: signal SIGUSR1 thrown\r\n"),
+            Step::Expect("state"),
+            Step::Disconnect
+        ]);
+        let (sink, _events) = ChannelSink::new();
+        let commands = Mutex::new(vec![]);
+
+        // Queued while the session is running, like the network monitor does
+        thread::scope(|scope| {
+            scope.spawn(|| management::session(server.connect(), &sink, &commands, INTERVAL).unwrap());
+
+            thread::sleep(INTERVAL * 2);
+            commands.lock().unwrap().push(String::from("signal SIGUSR1"));
+        });
+
+        assert!(commands.lock().unwrap().is_empty());
+        assert_eq!(server.finish().iter().filter(|command| *command == "signal SIGUSR1").count(), 1);
+    }
+
+    #[test]
+    fn connect_fails_with_wrong_password() {
+        let server = MockManagement::start(vec![
+            Step::Send("ENTER PASSWORD:"),
+            Step::Expect("wrong"),
+            Step::Send("ERROR: bad password\r\n"),
+            Step::Disconnect
+        ]);
+
+        let error = server.management(Some("wrong")).connect().unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::PermissionDenied);
+    }
+
+    #[test]
+    fn reads_management_directive() {
+        let config = "client\nmanagement 127.0.0.1 41234 \"/home/octo vpn/.octovpn/openvpn/missing.pwd\"\nverb 2";
+        assert_eq!(Management::from_config(config), None, "the password file doesn't exist");
+
+        let management = Management::from_config("client\nmanagement 127.0.0.1 41234\nverb 2").unwrap();
+        assert_eq!(management.address, Address::Tcp("127.0.0.1:41234".parse().unwrap()));
+        assert_eq!(management.password, None);
+
+        // Written before the port was chosen per connection
+        assert!(Management::from_config("management localhost 7505\nverb 2").is_some());
+
+        #[cfg(unix)]
+        assert_eq!(
+            Management::from_config("management \"/home/octo vpn/management.sock\" unix").unwrap().address,
+            Address::Unix(std::path::PathBuf::from("/home/octo vpn/management.sock"))
+        );
+    }
+
+    #[test]
+    fn generates_a_new_address_each_connection() {
+        let (first, second) = (Management::generate(), Management::generate());
+
+        assert_ne!(first.address, second.address);
+        assert_ne!(first.password.map(|p| p.expose().clone()), second.password.map(|p| p.expose().clone()));
+    }
+
+    #[test]
+    fn session_handles_partial_writes() {
+        let server = MockManagement::start(vec![
+            Step::Send(BANNER),
+            Step::Expect("state"),
+            Step::Send("1700000000,CONNEC"),
+            Step::Sleep(INTERVAL * 3),
+            Step::Send("TED,SUCCESS,10.8.0.2,198.51.100.7,443,,\r\n"),
+            Step::Send("END\r\n"),
+            Step::Expect("state"),
+            Step::Disconnect
+        ]);
+        let (sink, events) = ChannelSink::new();
+
+        // The client polls again while the line is incomplete, so it may see a reset
+        let _ = management::session(server.connect(), &sink, &Mutex::new(vec![]), INTERVAL);
+
+        let states = named(&received(&events), "openvpn_status");
+        assert_eq!(states.len(), 1);
+        assert_eq!(states[0]["connected"], true);
+        assert_eq!(states[0]["port"], 443);
+    }
+
+    #[test]
+    fn session_follows_state_notifications() {
+        let server = MockManagement::start(vec![
+            Step::Send(BANNER),
+            Step::Expect("state"),
+            Step::Send(">STATE:1700000000,RECONNECTING,ping-restart,,,,,\r\n"),
+            Step::Send(">STATE:1700000005,CONNECTED,SUCCESS,10.8.0.6,198.51.100.7,1194,,\r\n"),
+            Step::Send("1700000005,CONNECTED,SUCCESS,10.8.0.6,198.51.100.7,1194,,\r\nEND\r\n"),
+            Step::Expect("state"),
+            Step::Disconnect
+        ]);
+        let (sink, events) = ChannelSink::new();
+
+        management::session(server.connect(), &sink, &Mutex::new(vec![]), INTERVAL).unwrap();
+
+        // Reconnecting first, without addresses or a port, then connected again
+        let states = named(&received(&events), "openvpn_status");
+        assert_eq!(states.len(), 3);
+        assert_eq!(states[0]["connected"], false);
+        assert_eq!(states[0]["state"], "ping-restart");
+        assert_eq!(states[0]["port"], serde_json::Value::Null);
+        assert!(states[1..].iter().all(|state| state["local_ip"] == "10.8.0.6" && state["port"] == 1194));
+    }
+
+    #[test]
+    fn timeout_reports_connected_once_interface_is_up() {
+        let _globals = lock_globals();
+        let (management, _listener) = MockManagement::listen();
+        let (sink, events) = ChannelSink::new();
+
+        openvpn_timeout(sink, management, true, Protocol::OpenVPN).join().unwrap();
+
+        assert_eq!(received(&events), vec![("status", json!(Status::Connected as u8))]);
+    }
+
+    #[test]
+    fn timeout_reports_disconnected_once_interface_is_gone() {
+        let _globals = lock_globals();
+        // Bind and drop, so nothing listens on the port
+        let address = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let management = Management { address: Address::Tcp(address), password: None };
+        let (sink, events) = ChannelSink::new();
+
+        openvpn_timeout(sink, management, false, Protocol::OpenVPN).join().unwrap();
+
+        assert_eq!(received(&events), vec![
+            ("openvpn_status", json!(null)),
+            ("status", json!(Status::Disconnected as u8))
+        ]);
+    }
+
+    #[test]
+    fn timeout_tears_down_when_the_interface_never_comes_up() {
+        let _globals = lock_globals();
+        // Bind and drop, so nothing ever listens on the port
+        let address = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let management = Management { address: Address::Tcp(address), password: None };
+        let (sink, events) = ChannelSink::new();
+
+        *PROTOCOL_CONNECTED.lock().unwrap() = Some(Protocol::OpenVPN);
+        *HOP.lock().unwrap() = Some(Hop { config: String::new(), destination: None });
+
+        openvpn_timeout(sink, management, true, Protocol::OpenVPN).join().unwrap();
+
+        // Nothing is left for a later disconnect to lift
+        assert_eq!(received(&events), vec![
+            ("status", json!(Status::Disconnected as u8)),
+            ("ipv6_status", json!(null))
+        ]);
+        assert_eq!(*PROTOCOL_CONNECTED.lock().unwrap(), None);
+        assert!(HOP.lock().unwrap().is_none());
+    }
+}
@@ -0,0 +1,472 @@
+#[cfg(target_os = "linux")]
+use std::{collections::HashMap, io, os::fd::{AsRawFd, FromRawFd, OwnedFd}};
+use std::{fmt::{Display, Formatter}, thread, time::{Duration, Instant}};
+
+#[cfg(windows)]
+use crate::network::{Netsh, Network};
+
+// Notices when the network changes under the tunnel, i.e. switching from Wi-Fi
+// to Ethernet, or the system waking up, so the tunnel can be re-established
+// instead of OpenVPN hanging in RECONNECTING or WireGuard sending into the void.
+
+/// How often the clocks are checked, and changes looked at.
+const TICK: Duration = Duration::from_secs(1);
+/// Switching networks comes in bursts of changes, this waits for them to stop.
+const SETTLE: Duration = Duration::from_secs(2);
+/// Changes right after re-establishing are most likely the tunnel itself coming back.
+const COOLDOWN: Duration = Duration::from_secs(15);
+/// More time than this spent suspended between two ticks means the system slept.
+const SLEEP_THRESHOLD: Duration = Duration::from_secs(5);
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Trigger {
+    /// An interface, address or default route changed
+    Interface,
+    /// The system woke up from sleep
+    Resume
+}
+
+impl Display for Trigger {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Trigger::Interface => write!(f, "The network changed"),
+            Trigger::Resume => write!(f, "Woke up from sleep")
+        }
+    }
+}
+
+/// Reports interface changes as they happen.
+pub trait InterfaceEvents: Send + 'static {
+    /// Waits at most `timeout`, returning whether an interface changed meanwhile.
+    fn wait(&mut self, timeout: Duration) -> Result<bool, String>;
+}
+
+/// Decides when to re-establish the tunnel, from the changes and clocks it's given.
+#[derive(Debug)]
+pub struct Monitor {
+    /// Time spent suspended since boot, as of the last tick
+    suspended: Duration,
+    /// Last change not acted upon yet
+    changed: Option<Instant>,
+    acted: Option<Instant>
+}
+
+impl Monitor {
+    pub fn new(suspended: Duration) -> Self {
+        Monitor { suspended, changed: None, acted: None }
+    }
+
+    pub fn changed(&mut self, now: Instant) {
+        if self.acted.is_some_and(|acted| now.duration_since(acted) < COOLDOWN) {
+            return;
+        }
+
+        self.changed = Some(now);
+    }
+
+    pub fn tick(&mut self, now: Instant, suspended: Duration) -> Option<Trigger> {
+        let slept = suspended.saturating_sub(self.suspended) > SLEEP_THRESHOLD;
+        self.suspended = suspended;
+
+        let trigger = match self.changed {
+            // Waking up comes with its own interface changes, handled all at once
+            _ if slept => Trigger::Resume,
+            Some(changed) if now.duration_since(changed) >= SETTLE => Trigger::Interface,
+            _ => return None
+        };
+
+        self.changed = None;
+        self.acted = Some(now);
+        Some(trigger)
+    }
+}
+
+/// Time spent suspended since boot. The boot clock keeps going while suspended, the monotonic one doesn't.
+#[cfg(target_os = "linux")]
+pub fn suspended() -> Duration {
+    let clock = |id: libc::clockid_t| unsafe {
+        let mut time: libc::timespec = std::mem::zeroed();
+        libc::clock_gettime(id, &mut time);
+        Duration::new(time.tv_sec as u64, time.tv_nsec as u32)
+    };
+
+    clock(libc::CLOCK_BOOTTIME).saturating_sub(clock(libc::CLOCK_MONOTONIC))
+}
+
+/// Time spent suspended since boot. The tick count keeps going while suspended, the unbiased interrupt time doesn't.
+#[cfg(windows)]
+pub fn suspended() -> Duration {
+    use windows::Win32::System::{SystemInformation::GetTickCount64, WindowsProgramming::QueryUnbiasedInterruptTime};
+
+    unsafe {
+        let mut unbiased = 0u64;
+        let _ = QueryUnbiasedInterruptTime(&mut unbiased);
+
+        // In 100 nanosecond units
+        Duration::from_millis(GetTickCount64()).saturating_sub(Duration::from_nanos(unbiased * 100))
+    }
+}
+
+/// Watches the interfaces and clocks, calling `act` whenever the tunnel needs re-establishing.
+pub fn run<S: InterfaceEvents, F: FnMut(Trigger)>(mut source: S, mut act: F) {
+    let mut monitor = Monitor::new(suspended());
+
+    loop {
+        match source.wait(TICK) {
+            Ok(true) => monitor.changed(Instant::now()),
+            Ok(false) => {},
+            Err(e) => {
+                log::warn!("Failed to read interface changes: {}", e);
+                thread::sleep(TICK);
+            }
+        }
+
+        if let Some(trigger) = monitor.tick(Instant::now(), suspended()) {
+            act(trigger);
+        }
+    }
+}
+
+/// Interfaces whose changes don't move the tunnel, i.e. the tunnel itself or containers.
+#[cfg(target_os = "linux")]
+const IGNORED_INTERFACES: [&str; 9] = ["lo", "tun", "tap", "wg", "wireguard", "octovpn", "docker", "veth", "br-"];
+
+/// A change as read from netlink, by interface.
+#[cfg(target_os = "linux")]
+#[derive(Debug, PartialEq, Eq)]
+pub struct Change {
+    pub index: u32,
+    /// Only link messages carry the name
+    pub name: Option<String>
+}
+
+#[cfg(target_os = "linux")]
+fn u16_at(buffer: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_ne_bytes(buffer.get(offset..offset + 2)?.try_into().ok()?))
+}
+
+#[cfg(target_os = "linux")]
+fn u32_at(buffer: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_ne_bytes(buffer.get(offset..offset + 4)?.try_into().ok()?))
+}
+
+/// Finds an attribute in the `rtattr` list that follows a message's header.
+#[cfg(target_os = "linux")]
+fn attribute(mut attributes: &[u8], kind: u16) -> Option<&[u8]> {
+    while attributes.len() >= 4 {
+        let length = u16_at(attributes, 0)? as usize;
+        if length < 4 || length > attributes.len() {
+            return None;
+        }
+
+        if u16_at(attributes, 2)? == kind {
+            return Some(&attributes[4..length]);
+        }
+
+        attributes = attributes.get((length + 3) & !3..)?;
+    }
+
+    None
+}
+
+/// Reads the changes that may move the tunnel out of a batch of netlink messages:
+/// links going up or down, addresses other than link-local or temporary ones, and default routes.
+#[cfg(target_os = "linux")]
+pub fn parse(mut buffer: &[u8]) -> Vec<Change> {
+    let mut changes = vec![];
+
+    while let (Some(length), Some(kind)) = (u32_at(buffer, 0), u16_at(buffer, 4)) {
+        let length = length as usize;
+        if length < 16 || length > buffer.len() {
+            break;
+        }
+
+        let message = &buffer[16..length];
+        buffer = buffer.get((length + 3) & !3..).unwrap_or_default();
+
+        let change = match kind {
+            // ifinfomsg: family, pad, type, index, flags, change
+            libc::RTM_NEWLINK | libc::RTM_DELLINK if message.len() >= 16 => {
+                let flags = (libc::IFF_UP | libc::IFF_RUNNING | libc::IFF_LOWER_UP) as u32;
+                let moved = kind == libc::RTM_DELLINK || u32_at(message, 12).unwrap_or(0) & flags != 0;
+
+                let name = attribute(&message[16..], libc::IFLA_IFNAME)
+                    .map(|name| String::from_utf8_lossy(name).trim_end_matches('\0').to_string());
+
+                Some(Change { index: u32_at(message, 4).unwrap_or(0), name }).filter(|_| moved)
+            },
+            // ifaddrmsg: family, prefix length, flags, scope, index
+            libc::RTM_NEWADDR | libc::RTM_DELADDR if message.len() >= 8 => {
+                let temporary = message[2] as u32 & libc::IFA_F_TEMPORARY != 0;
+                let link_local = message[3] == libc::RT_SCOPE_LINK;
+
+                Some(Change { index: u32_at(message, 4).unwrap_or(0), name: None }).filter(|_| !temporary && !link_local)
+            },
+            // rtmsg: family, destination length, source length, tos, table, protocol, scope, type, flags
+            libc::RTM_NEWROUTE | libc::RTM_DELROUTE if message.len() >= 12 => {
+                let default = message[1] == 0 && message[4] == libc::RT_TABLE_MAIN;
+                let index = attribute(&message[12..], libc::RTA_OIF).and_then(|index| u32_at(index, 0));
+
+                index.map(|index| Change { index, name: None }).filter(|_| default)
+            },
+            _ => None
+        };
+
+        changes.extend(change);
+    }
+
+    changes
+}
+
+/// Listens to the kernel's route and address notifications.
+#[cfg(target_os = "linux")]
+pub struct Netlink {
+    socket: OwnedFd,
+    /// Interface names by index, as removed interfaces can't be looked up anymore
+    names: HashMap<u32, String>,
+    buffer: Vec<u8>
+}
+
+#[cfg(target_os = "linux")]
+impl Netlink {
+    pub fn open() -> io::Result<Self> {
+        unsafe {
+            let fd = libc::socket(libc::AF_NETLINK, libc::SOCK_RAW | libc::SOCK_CLOEXEC, libc::NETLINK_ROUTE);
+            if fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let socket = OwnedFd::from_raw_fd(fd);
+
+            let mut address: libc::sockaddr_nl = std::mem::zeroed();
+            address.nl_family = libc::AF_NETLINK as u16;
+            address.nl_groups = (libc::RTMGRP_LINK | libc::RTMGRP_IPV4_IFADDR | libc::RTMGRP_IPV6_IFADDR | libc::RTMGRP_IPV4_ROUTE | libc::RTMGRP_IPV6_ROUTE) as u32;
+
+            let bound = libc::bind(
+                socket.as_raw_fd(),
+                &address as *const libc::sockaddr_nl as *const libc::sockaddr,
+                std::mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t
+            );
+            if bound < 0 {
+                return Err(io::Error::last_os_error());
+            }
+
+            Ok(Netlink { socket, names: HashMap::new(), buffer: vec![0; 64 * 1024] })
+        }
+    }
+
+    fn name(&mut self, change: Change) -> Option<String> {
+        if let Some(name) = change.name {
+            self.names.insert(change.index, name.clone());
+            return Some(name);
+        }
+
+        if let Some(name) = self.names.get(&change.index) {
+            return Some(name.clone());
+        }
+
+        let mut name = [0 as libc::c_char; libc::IF_NAMESIZE];
+        if unsafe { libc::if_indextoname(change.index, name.as_mut_ptr()) }.is_null() {
+            return None;
+        }
+
+        let name = unsafe { std::ffi::CStr::from_ptr(name.as_ptr()) }.to_string_lossy().into_owned();
+        self.names.insert(change.index, name.clone());
+        Some(name)
+    }
+
+    fn relevant(&mut self, change: Change) -> bool {
+        // Gone before it could be looked up, i.e. a tunnel torn down right away
+        let name = match self.name(change) {
+            Some(name) => name,
+            None => return false
+        };
+
+        let relevant = !IGNORED_INTERFACES.iter().any(|prefix| name.starts_with(prefix));
+        if relevant {
+            log::debug!("Interface {} changed", name);
+        }
+        relevant
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl InterfaceEvents for Netlink {
+    fn wait(&mut self, timeout: Duration) -> Result<bool, String> {
+        let mut poll = libc::pollfd { fd: self.socket.as_raw_fd(), events: libc::POLLIN, revents: 0 };
+        match unsafe { libc::poll(&mut poll, 1, timeout.as_millis() as libc::c_int) } {
+            0 => return Ok(false),
+            n if n < 0 => return Err(io::Error::last_os_error().to_string()),
+            _ => {}
+        }
+
+        let mut changed = false;
+
+        // Read everything queued so far
+        loop {
+            let read = unsafe {
+                libc::recv(self.socket.as_raw_fd(), self.buffer.as_mut_ptr() as *mut libc::c_void, self.buffer.len(), libc::MSG_DONTWAIT)
+            };
+
+            if read < 0 {
+                let error = io::Error::last_os_error();
+                return match error.raw_os_error() {
+                    Some(libc::EAGAIN) => Ok(changed),
+                    // Too many changes at once, some were dropped
+                    Some(libc::ENOBUFS) => Ok(true),
+                    _ => Err(error.to_string())
+                };
+            }
+
+            for change in parse(&self.buffer[..read as usize]) {
+                changed |= self.relevant(change);
+            }
+        }
+    }
+}
+
+/// Asks Windows for the network every few seconds, like the auto-connect does.
+#[cfg(windows)]
+pub struct Polling {
+    last: Network,
+    polled: Instant
+}
+
+#[cfg(windows)]
+impl Polling {
+    const INTERVAL: Duration = Duration::from_secs(5);
+
+    pub fn new() -> Self {
+        Polling { last: Netsh::current(), polled: Instant::now() }
+    }
+}
+
+#[cfg(windows)]
+impl InterfaceEvents for Polling {
+    fn wait(&mut self, timeout: Duration) -> Result<bool, String> {
+        thread::sleep(timeout);
+
+        if self.polled.elapsed() < Self::INTERVAL {
+            return Ok(false);
+        }
+        self.polled = Instant::now();
+
+        let network = Netsh::current();
+        let changed = network != self.last;
+        self.last = network;
+        Ok(changed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn waits_for_changes_to_settle() {
+        let start = Instant::now();
+        let mut monitor = Monitor::new(Duration::ZERO);
+
+        monitor.changed(start);
+        assert_eq!(monitor.tick(start + Duration::from_secs(1), Duration::ZERO), None);
+
+        // Another change, like the new interface getting its address
+        monitor.changed(start + Duration::from_secs(1));
+        assert_eq!(monitor.tick(start + Duration::from_secs(2), Duration::ZERO), None);
+        assert_eq!(monitor.tick(start + Duration::from_secs(3), Duration::ZERO), Some(Trigger::Interface));
+        assert_eq!(monitor.tick(start + Duration::from_secs(10), Duration::ZERO), None);
+    }
+
+    #[test]
+    fn ignores_changes_right_after_acting() {
+        let start = Instant::now();
+        let mut monitor = Monitor::new(Duration::ZERO);
+
+        monitor.changed(start);
+        assert_eq!(monitor.tick(start + SETTLE, Duration::ZERO), Some(Trigger::Interface));
+
+        // The tunnel coming back
+        monitor.changed(start + SETTLE + Duration::from_secs(1));
+        assert_eq!(monitor.tick(start + SETTLE * 3, Duration::ZERO), None);
+
+        let later = start + SETTLE + COOLDOWN;
+        monitor.changed(later);
+        assert_eq!(monitor.tick(later + SETTLE, Duration::ZERO), Some(Trigger::Interface));
+    }
+
+    #[test]
+    fn detects_waking_up() {
+        let start = Instant::now();
+        let mut monitor = Monitor::new(Duration::from_secs(100));
+
+        // A little drift between the clocks isn't sleep
+        assert_eq!(monitor.tick(start, Duration::from_millis(100_500)), None);
+
+        // Suspended for an hour, then the interfaces come back
+        monitor.changed(start + Duration::from_secs(1));
+        assert_eq!(monitor.tick(start + Duration::from_secs(1), Duration::from_secs(3700)), Some(Trigger::Resume));
+        monitor.changed(start + Duration::from_secs(2));
+        assert_eq!(monitor.tick(start + Duration::from_secs(5), Duration::from_secs(3700)), None);
+    }
+
+    #[cfg(target_os = "linux")]
+    fn message(kind: u16, body: &[u8]) -> Vec<u8> {
+        let mut message = vec![];
+        message.extend((16 + body.len() as u32).to_ne_bytes());
+        message.extend(kind.to_ne_bytes());
+        message.extend([0; 10]);
+        message.extend(body);
+
+        while message.len() % 4 != 0 {
+            message.push(0);
+        }
+        message
+    }
+
+    #[cfg(target_os = "linux")]
+    fn rtattr(kind: u16, data: &[u8]) -> Vec<u8> {
+        let mut attribute = vec![];
+        attribute.extend((4 + data.len() as u16).to_ne_bytes());
+        attribute.extend(kind.to_ne_bytes());
+        attribute.extend(data);
+
+        while attribute.len() % 4 != 0 {
+            attribute.push(0);
+        }
+        attribute
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn parses_netlink_messages() {
+        let mut buffer = vec![];
+
+        // eth0 going down
+        let mut link = vec![libc::AF_UNSPEC as u8, 0, 1, 0];
+        link.extend(2u32.to_ne_bytes());
+        link.extend(0u32.to_ne_bytes());
+        link.extend((libc::IFF_UP as u32).to_ne_bytes());
+        link.extend(rtattr(libc::IFLA_IFNAME, b"eth0\0"));
+        buffer.extend(message(libc::RTM_NEWLINK, &link));
+
+        // A new address on wlan0, then a link-local one that doesn't move anything
+        let mut address = vec![libc::AF_INET as u8, 24, 0, 0];
+        address.extend(3u32.to_ne_bytes());
+        buffer.extend(message(libc::RTM_NEWADDR, &address));
+        address[3] = libc::RT_SCOPE_LINK;
+        buffer.extend(message(libc::RTM_NEWADDR, &address));
+
+        // A default route through wlan0, then the tunnel's 0.0.0.0/1
+        let mut route = vec![libc::AF_INET as u8, 0, 0, 0, libc::RT_TABLE_MAIN, 0, 0, 0];
+        route.extend(0u32.to_ne_bytes());
+        route.extend(rtattr(libc::RTA_OIF, &3u32.to_ne_bytes()));
+        buffer.extend(message(libc::RTM_NEWROUTE, &route));
+        route[1] = 1;
+        buffer.extend(message(libc::RTM_NEWROUTE, &route));
+
+        assert_eq!(parse(&buffer), vec![
+            Change { index: 2, name: Some(String::from("eth0")) },
+            Change { index: 3, name: None },
+            Change { index: 3, name: None }
+        ]);
+    }
+}
@@ -0,0 +1,254 @@
+use std::{collections::HashMap, net::IpAddr, process::Command};
+
+use serde::{Serialize, Deserialize};
+
+use crate::{CONFIGURATION, Protocol, config::HOME, multihop, network};
+
+// Fits the tunnel's packets to the network. Servers assume a 1500 byte path, so on
+// PPPoE or a mobile hotspot the encapsulated packets are too large, and if the ICMP
+// saying so gets lost on the way, large transfers like HTTPS handshakes stall.
+// The path is probed once per network with packets that may not be fragmented.
+
+/// The smallest path IPv6 allows, anything worse isn't worth tuning for.
+const MIN_MTU: u16 = 1280;
+const MAX_MTU: u16 = 1500;
+
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MtuConfig {
+    /// Probe the path to the server on networks without a known MTU
+    pub discovery: bool,
+    /// Path MTU to use instead, i.e. 1492 on PPPoE
+    pub manual: Option<u16>
+}
+
+impl Default for MtuConfig {
+    fn default() -> Self {
+        MtuConfig {
+            discovery: true,
+            manual: None
+        }
+    }
+}
+
+/// Bytes the tunnel adds to each packet: IP, UDP or TCP, then the protocol's own.
+pub fn overhead(protocol: &Protocol, tcp: bool, ipv6: bool) -> u16 {
+    let ip = if ipv6 { 40 } else { 20 };
+
+    match protocol {
+        // 32 for WireGuard's header and tag
+        Protocol::WireGuard => ip + 8 + 32,
+        // The 2 byte packet length, and 24 for the data channel's opcode, peer id, packet id and tag
+        Protocol::OpenVPN if tcp => ip + 20 + 2 + 24,
+        Protocol::OpenVPN => ip + 8 + 24
+    }
+}
+
+/// Finds the largest size between `low` and `high` that `fits`, assuming all smaller ones do.
+/// `None` if not even `low` does, i.e. when the server doesn't answer at all.
+pub fn search<F: FnMut(u16) -> bool>(low: u16, high: u16, mut fits: F) -> Option<u16> {
+    if !fits(low) {
+        return None;
+    }
+
+    // `low` fits, nothing above `high` does
+    let (mut low, mut high) = (low, high);
+    while low < high {
+        let middle = low + (high - low).div_ceil(2);
+
+        if fits(middle) {
+            low = middle;
+        } else {
+            high = middle - 1;
+        }
+    }
+
+    Some(low)
+}
+
+/// Arguments to `ping` sending a single packet of `size` bytes, headers included, that may not be fragmented.
+pub fn ping_args(address: IpAddr, size: u16) -> Vec<String> {
+    let payload = size.saturating_sub(if address.is_ipv4() { 28 } else { 48 }).to_string();
+
+    let mut args = if cfg!(windows) {
+        vec!["-n", "1", "-w", "1000", "-l", &payload]
+    } else {
+        vec!["-c", "1", "-W", "1", "-M", "do", "-s", &payload]
+    }.into_iter().map(String::from).collect::<Vec<String>>();
+
+    // Routers never fragment IPv6, and Windows only knows the flag for IPv4
+    if cfg!(windows) && address.is_ipv4() {
+        args.push(String::from("-f"));
+    }
+
+    args.push(address.to_string());
+    args
+}
+
+fn probe(address: IpAddr, size: u16) -> bool {
+    match Command::new("ping").args(ping_args(address, size)).output() {
+        // Windows also succeeds when a router answers it's unreachable, only real replies carry a TTL
+        Ok(output) if cfg!(windows) && address.is_ipv4() => output.status.success() && String::from_utf8_lossy(&output.stdout).contains("TTL="),
+        Ok(output) => output.status.success(),
+        Err(e) => {
+            log::warn!("Failed to run ping: {}", e);
+            false
+        }
+    }
+}
+
+/// Sets the tunnel's MTU for a path of `path` bytes, replacing the server's.
+pub fn apply(protocol: &Protocol, config: &str, path: u16, ipv6: bool) -> String {
+    match protocol {
+        Protocol::OpenVPN => {
+            let tcp = config.lines().any(|line| line.trim().starts_with("proto tcp"));
+
+            let mut lines = config.lines()
+                .map(|line| {
+                    let trimmed = line.trim();
+
+                    if trimmed.starts_with("tun-mtu ") || trimmed.starts_with("mssfix") {
+                        format!("# {}", trimmed)
+                    } else {
+                        line.to_string()
+                    }
+                })
+                .collect::<Vec<String>>();
+
+            lines.push(format!("tun-mtu {}", path.saturating_sub(overhead(protocol, tcp, ipv6))));
+
+            // The largest UDP payload, over TCP the outer connection splits packets itself
+            if !tcp {
+                lines.push(format!("mssfix {}", path.saturating_sub(if ipv6 { 48 } else { 28 })));
+            }
+
+            lines.join("\n")
+        },
+        Protocol::WireGuard => {
+            multihop::set_interface(config, vec![format!("MTU = {}", path.saturating_sub(overhead(protocol, false, ipv6)))])
+        }
+    }
+}
+
+fn cache_path() -> String {
+    format!("{}/.octovpn/mtu.json", HOME.to_str().unwrap())
+}
+
+/// Path MTUs found so far, by network. `None` where probing failed, it isn't tried again there.
+fn read_cache() -> HashMap<String, Option<u16>> {
+    std::fs::read_to_string(cache_path()).ok()
+        .and_then(|cache| serde_json::from_str(&cache).ok())
+        .unwrap_or_default()
+}
+
+fn write_cache(cache: &HashMap<String, Option<u16>>) {
+    if let Err(e) = std::fs::write(cache_path(), serde_json::to_string(cache).unwrap()) {
+        log::warn!("Failed to write the MTU cache: {}", e);
+    }
+}
+
+/// The path MTU to `endpoint` on the current network, probed only if it isn't known yet.
+fn discover(endpoint: IpAddr) -> Option<u16> {
+    let network = network::current().and_then(|network| network.key());
+    let mut cache = read_cache();
+
+    if let Some(mtu) = network.as_ref().and_then(|key| cache.get(key)) {
+        log::debug!("Path MTU on this network is {:?}", mtu);
+        return *mtu;
+    }
+
+    log::info!("Probing the path MTU to {}", endpoint);
+    let mtu = search(MIN_MTU, MAX_MTU, |size| probe(endpoint, size));
+    match mtu {
+        Some(mtu) => log::info!("Path MTU to {} is {}", endpoint, mtu),
+        None => log::warn!("{} doesn't answer pings, leaving the MTU as is on this network", endpoint)
+    }
+
+    if let Some(key) = network {
+        cache.insert(key, mtu);
+        write_cache(&cache);
+    }
+
+    mtu
+}
+
+/// Fits the config to the path MTU: the one set manually, else the one found for this network.
+pub fn tune(protocol: &Protocol, config: &str) -> String {
+    let settings = CONFIGURATION.lock().unwrap().mtu.clone();
+    if settings.manual.is_none() && !settings.discovery {
+        return config.to_string();
+    }
+
+    // IPv4 sorts first, and is what's tried first
+    let endpoint = multihop::resolve(&multihop::hosts(config)).into_iter().next();
+    let path = match settings.manual {
+        Some(mtu) => Some(mtu),
+        None => endpoint.and_then(discover)
+    };
+
+    match path {
+        // Unknown endpoints get the worst case
+        Some(path) => apply(protocol, config, path, !endpoint.is_some_and(|endpoint| endpoint.is_ipv4())),
+        None => config.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OPENVPN: &str = "client\ndev tun\nproto udp\nremote 198.51.100.7 1194\ntun-mtu 1500\nmssfix 1450";
+    const WIREGUARD: &str = "[Interface]\nPrivateKey = cHJpdmF0ZQ==\nAddress = 10.64.0.2/32\nMTU = 1420\n\n[Peer]\nEndpoint = 192.0.2.10:51820";
+
+    #[test]
+    fn finds_the_largest_size_that_fits() {
+        let mut probes = 0;
+        assert_eq!(search(MIN_MTU, MAX_MTU, |size| { probes += 1; size <= 1492 }), Some(1492));
+        assert!(probes <= 9, "{} probes", probes);
+
+        assert_eq!(search(MIN_MTU, MAX_MTU, |_| true), Some(MAX_MTU));
+        assert_eq!(search(MIN_MTU, MAX_MTU, |size| size == MIN_MTU), Some(MIN_MTU));
+        assert_eq!(search(MIN_MTU, MAX_MTU, |_| false), None);
+    }
+
+    #[test]
+    fn pings_without_fragmenting() {
+        let address = "192.0.2.10".parse().unwrap();
+        let args = ping_args(address, 1492);
+
+        // 20 IP and 8 ICMP
+        assert!(args.contains(&String::from("1464")));
+        assert_eq!(args.last().unwrap(), "192.0.2.10");
+        if cfg!(windows) {
+            assert!(args.contains(&String::from("-f")));
+        } else {
+            assert_eq!(args[4..6], ["-M", "do"]);
+        }
+
+        assert!(ping_args("2001:db8::7".parse().unwrap(), 1492).contains(&String::from("1444")));
+    }
+
+    #[test]
+    fn fits_openvpn_to_the_path() {
+        let config = apply(&Protocol::OpenVPN, OPENVPN, 1492, false);
+        let lines = config.lines().collect::<Vec<&str>>();
+
+        assert!(lines.contains(&"# tun-mtu 1500"));
+        assert!(lines.contains(&"# mssfix 1450"));
+        assert!(lines.contains(&"tun-mtu 1440"));
+        assert!(lines.contains(&"mssfix 1464"));
+
+        let config = apply(&Protocol::OpenVPN, &OPENVPN.replace("proto udp", "proto tcp"), 1492, false);
+        assert!(config.contains("\ntun-mtu 1426"));
+        assert!(!config.contains("\nmssfix"));
+    }
+
+    #[test]
+    fn fits_wireguard_to_the_path() {
+        let config = apply(&Protocol::WireGuard, WIREGUARD, 1492, false);
+
+        assert!(config.starts_with("[Interface]\nMTU = 1432\n"));
+        assert_eq!(config.matches("MTU").count(), 1);
+        assert!(apply(&Protocol::WireGuard, WIREGUARD, 1492, true).contains("MTU = 1412"));
+    }
+}
@@ -0,0 +1,272 @@
+use std::net::{IpAddr, ToSocketAddrs};
+
+use serde::{Serialize, Deserialize};
+
+use crate::{Destination, Protocol, config::{HOME, write_private}, mtu::overhead, platform, wireguard::PeerStats};
+
+// Double VPN: traffic goes through a WireGuard tunnel to an entry server first, and the
+// usual tunnel to the exit server is routed inside it. The entry only sees traffic going
+// to the exit, and the exit never sees where it came from.
+
+/// Name of the entry's tunnel, taken from its config file name.
+pub const TUNNEL: &str = "hop";
+
+/// Both WireGuard tunnels mark their own packets with it, so the entry's don't loop back into the exit's default route.
+const FWMARK: u32 = 51820;
+
+/// wg-quick's default, used when the entry's config doesn't set one.
+const DEFAULT_MTU: u16 = 1420;
+
+#[derive(Debug, PartialEq, Eq, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MultiHop {
+    pub enabled: bool,
+    /// API location traffic enters through, always over WireGuard
+    pub entry: Option<i32>
+}
+
+/// The entry of the current connection.
+#[derive(Debug, Clone)]
+pub struct Hop {
+    /// Its WireGuard config, as given by the API
+    pub config: String,
+    pub destination: Option<Destination>
+}
+
+/// Both hops of the connection.
+#[derive(Debug, PartialEq, Eq, Clone, Serialize)]
+pub struct Hops {
+    pub entry: Option<Destination>,
+    /// `None` until the entry's tunnel is up
+    pub entry_peer: Option<PeerStats>,
+    pub exit: Option<Destination>
+}
+
+fn key(line: &str) -> &str {
+    line.split('=').next().unwrap_or_default().trim()
+}
+
+/// Hosts a config connects to, from OpenVPN's `remote` or WireGuard's `Endpoint` lines.
+pub fn hosts(config: &str) -> Vec<String> {
+    config.lines()
+        .map(|line| line.trim())
+        .filter_map(|line| match line.strip_prefix("remote ") {
+            Some(remote) => remote.split_whitespace().next().map(String::from),
+            None if key(line) == "Endpoint" => line.split_once('=')
+                .and_then(|(_, endpoint)| endpoint.trim().rsplit_once(':'))
+                .map(|(host, _)| host.trim_start_matches('[').trim_end_matches(']').to_string()),
+            None => None
+        })
+        .collect()
+}
+
+/// Resolves the hosts right away, the routes are set before any tunnel is up.
+pub fn resolve(hosts: &[String]) -> Vec<IpAddr> {
+    let mut addresses = vec![];
+
+    for host in hosts {
+        match (host.as_str(), 0).to_socket_addrs() {
+            Ok(resolved) => addresses.extend(resolved.map(|address| address.ip())),
+            Err(e) => log::warn!("Failed to resolve {}: {}", host, e)
+        }
+    }
+
+    addresses.sort();
+    addresses.dedup();
+    addresses
+}
+
+/// The entry's MTU, as set in its config.
+pub fn mtu(config: &str) -> u16 {
+    config.lines()
+        .find(|line| key(line) == "MTU")
+        .and_then(|line| line.split_once('='))
+        .and_then(|(_, mtu)| mtu.trim().parse().ok())
+        .unwrap_or(DEFAULT_MTU)
+}
+
+/// Adds lines to the `[Interface]` section, replacing the existing ones with the same keys.
+pub fn set_interface(config: &str, set: Vec<String>) -> String {
+    let keys = set.iter().map(|line| key(line).to_string()).collect::<Vec<String>>();
+    let mut lines = vec![];
+
+    for line in config.lines() {
+        if keys.iter().any(|replaced| replaced == key(line)) {
+            continue;
+        }
+
+        lines.push(line.to_string());
+        if line.trim() == "[Interface]" {
+            lines.extend(set.iter().cloned());
+        }
+    }
+
+    lines.join("\n")
+}
+
+/// Marks the tunnel's own packets on Linux, the Windows client has no such option.
+fn fwmark() -> Vec<String> {
+    if cfg!(target_os = "linux") { vec![format!("FwMark = {}", FWMARK)] } else { vec![] }
+}
+
+/// Only routes the exit servers through the entry, the exit's DNS servers are used.
+pub fn entry_config(config: &str, exits: &[IpAddr]) -> String {
+    let allowed = exits.iter()
+        .map(|exit| format!("{}/{}", exit, if exit.is_ipv4() { 32 } else { 128 }))
+        .collect::<Vec<String>>();
+
+    let config = config.lines()
+        .filter(|line| key(line) != "DNS")
+        .map(|line| match key(line) {
+            "AllowedIPs" => format!("AllowedIPs = {}", allowed.join(", ")),
+            _ => line.to_string()
+        })
+        .collect::<Vec<String>>()
+        .join("\n");
+
+    set_interface(&config, fwmark())
+}
+
+/// Fits the exit's tunnel inside the entry's, and keeps the entry's own traffic out of it.
+pub fn exit_config(protocol: &Protocol, config: &str, entry_mtu: u16, entries: &[IpAddr]) -> String {
+    match protocol {
+        Protocol::OpenVPN => {
+            let tcp = config.lines().any(|line| line.trim().starts_with("proto tcp"));
+            // Worst case, the exit may be reached over IPv6
+            let overhead = overhead(protocol, tcp, true);
+
+            let mut lines = config.lines()
+                .map(|line| {
+                    let trimmed = line.trim();
+
+                    if trimmed.starts_with("tun-mtu ") {
+                        format!("# {}", trimmed)
+                    } else if let Some(flags) = trimmed.strip_prefix("redirect-gateway").filter(|flags| !flags.contains("local")) {
+                        // The exit is reached through the entry, not the local gateway
+                        format!("redirect-gateway local{}", flags)
+                    } else {
+                        line.to_string()
+                    }
+                })
+                .collect::<Vec<String>>();
+
+            lines.push(format!("tun-mtu {}", entry_mtu.saturating_sub(overhead)));
+
+            // The server's would route the exit around the entry
+            lines.push(String::from("pull-filter ignore \"redirect-gateway\""));
+            if !lines.iter().any(|line| line.starts_with("redirect-gateway ")) {
+                lines.push(String::from("redirect-gateway local def1"));
+            }
+
+            // The entry's own packets still leave through the local gateway
+            for entry in entries.iter().filter(|entry| entry.is_ipv4()) {
+                lines.push(format!("route {} 255.255.255.255 net_gateway", entry));
+            }
+
+            lines.join("\n")
+        },
+        Protocol::WireGuard => {
+            let mut set = vec![format!("MTU = {}", entry_mtu.saturating_sub(overhead(protocol, false, true)))];
+            set.extend(fwmark());
+
+            set_interface(config, set)
+        }
+    }
+}
+
+/// Prepares both configs, returning the exit's and the entry's.
+pub fn prepare(protocol: &Protocol, exit: &str, entry: &str) -> Option<(String, String)> {
+    // The Windows client sends from the default interface whatever the routes say, so its packets would skip the entry
+    if cfg!(windows) && protocol == &Protocol::WireGuard {
+        log::error!("Multi-hop needs OpenVPN for the exit on Windows");
+        return None;
+    }
+
+    let exits = resolve(&hosts(exit));
+    if exits.is_empty() {
+        log::error!("Failed to resolve the exit server");
+        return None;
+    }
+
+    let entries = resolve(&hosts(entry));
+    Some((exit_config(protocol, exit, mtu(entry), &entries), entry_config(entry, &exits)))
+}
+
+pub fn config_path() -> String {
+    format!("{}/.octovpn/{}/{}.conf", HOME.to_str().unwrap(), TUNNEL, TUNNEL)
+}
+
+/// Writes the entry's config and brings its tunnel up.
+pub fn start(config: &str) -> bool {
+    let path = config_path();
+
+    if let Some(dir) = std::path::Path::new(&path).parent() {
+        let _ = std::fs::create_dir_all(dir);
+    }
+
+    // Holds the entry's private key
+    if let Err(e) = write_private(&path, config) {
+        log::error!("Failed to write the multi-hop entry config: {}", e);
+        return false;
+    }
+
+    unsafe { platform::start_hop(&path) }
+}
+
+pub fn stop() -> bool {
+    unsafe { !platform::is_hop_started() || platform::stop_hop() }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ENTRY: &str = "[Interface]\nPrivateKey = cHJpdmF0ZQ==\nAddress = 10.64.0.2/32\nDNS = 10.64.0.1\n\n[Peer]\nPublicKey = cHVibGlj\nAllowedIPs = 0.0.0.0/0, ::/0\nEndpoint = 192.0.2.10:51820";
+    const OPENVPN: &str = "client\ndev tun\nproto udp\nremote 198.51.100.7 1194\nredirect-gateway def1";
+    const WIREGUARD: &str = "[Interface]\nPrivateKey = ZXhpdA==\nAddress = 10.65.0.2/32\nMTU = 1420\n\n[Peer]\nAllowedIPs = 0.0.0.0/0\nEndpoint = [2001:db8::7]:51820";
+
+    fn address(address: &str) -> IpAddr {
+        address.parse().unwrap()
+    }
+
+    #[test]
+    fn finds_hosts() {
+        assert_eq!(hosts("client\nremote vpn.example.com 1194 udp\nremote 198.51.100.7"), vec!["vpn.example.com", "198.51.100.7"]);
+        assert_eq!(hosts(ENTRY), vec!["192.0.2.10"]);
+        assert_eq!(hosts(WIREGUARD), vec!["2001:db8::7"]);
+    }
+
+    #[test]
+    fn entry_only_carries_the_exit() {
+        let config = entry_config(ENTRY, &[address("198.51.100.7"), address("2001:db8::7")]);
+
+        assert!(config.contains("AllowedIPs = 198.51.100.7/32, 2001:db8::7/128"));
+        assert!(!config.contains("DNS"));
+        assert!(config.contains("Endpoint = 192.0.2.10:51820"));
+        assert_eq!(config.contains("FwMark = 51820"), cfg!(target_os = "linux"));
+    }
+
+    #[test]
+    fn openvpn_exit_fits_inside_the_entry() {
+        let config = exit_config(&Protocol::OpenVPN, OPENVPN, mtu(ENTRY), &[address("192.0.2.10")]);
+        let lines = config.lines().collect::<Vec<&str>>();
+
+        assert!(lines.contains(&"tun-mtu 1348"));
+        assert!(lines.contains(&"redirect-gateway local def1"));
+        assert!(lines.contains(&"pull-filter ignore \"redirect-gateway\""));
+        assert!(lines.contains(&"route 192.0.2.10 255.255.255.255 net_gateway"));
+
+        // TCP adds its own header
+        let config = exit_config(&Protocol::OpenVPN, &OPENVPN.replace("proto udp", "proto tcp"), 1420, &[]);
+        assert!(config.contains("tun-mtu 1334"));
+    }
+
+    #[test]
+    fn wireguard_exit_fits_inside_the_entry() {
+        let config = exit_config(&Protocol::WireGuard, WIREGUARD, mtu(&format!("{}\nMTU = 1380", ENTRY)), &[]);
+
+        assert!(config.contains("MTU = 1300"));
+        assert_eq!(config.matches("MTU").count(), 1);
+        assert!(config.starts_with("[Interface]\nMTU = 1300\n"));
+    }
+}
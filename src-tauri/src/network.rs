@@ -0,0 +1,363 @@
+#[cfg(test)]
+use std::sync::mpsc::Receiver;
+#[cfg(windows)]
+use std::{process::Command, time::Duration};
+
+use serde::{Serialize, Deserialize};
+
+use crate::{Configuration, Status, events::{ConnectTo, EventSink}, profile::Target};
+
+/// When to connect without being asked.
+#[derive(Debug, PartialEq, Eq, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AutoConnect {
+    /// Connects to the last location when the app starts
+    pub launch: bool,
+    /// Starts the service with the system, using the last connection's config
+    pub boot: bool,
+    /// Connects whenever the network is not trusted
+    pub untrusted: bool,
+    /// Wi-Fi names that don't need the VPN
+    #[serde(rename = "trustedSsids")]
+    pub trusted_ssids: Vec<String>,
+    /// Default gateways that don't need the VPN, by IP or MAC address
+    #[serde(rename = "trustedGateways")]
+    pub trusted_gateways: Vec<String>
+}
+
+/// What tells networks apart.
+#[derive(Debug, PartialEq, Eq, Clone, Default)]
+pub struct Network {
+    /// Wi-Fi name, `None` on wired networks
+    pub ssid: Option<String>,
+    /// Default gateway IP
+    pub gateway: Option<String>,
+    /// Default gateway MAC, which unlike the IP differs between routers
+    pub gateway_mac: Option<String>
+}
+
+impl Network {
+    pub fn is_trusted(&self, config: &AutoConnect) -> bool {
+        let ssid = self.ssid.as_ref().is_some_and(|ssid| config.trusted_ssids.contains(ssid));
+        let gateway = [&self.gateway, &self.gateway_mac].into_iter()
+            .flatten()
+            .any(|address| config.trusted_gateways.iter().any(|trusted| trusted.eq_ignore_ascii_case(address)));
+
+        ssid || gateway
+    }
+
+    /// Names the network for what's remembered per network: the router's MAC, the Wi-Fi name, or the gateway IP.
+    pub fn key(&self) -> Option<String> {
+        [&self.gateway_mac, &self.ssid, &self.gateway].into_iter()
+            .find_map(|key| key.clone())
+    }
+}
+
+/// Reports the network the system is on.
+pub trait NetworkSource: Send + 'static {
+    /// Returns the current network, then blocks until it changes.
+    /// `None` once it can't tell anymore.
+    fn next(&mut self) -> Option<Network>;
+}
+
+/// Where to connect to on `network`, if it isn't trusted and nothing is connected yet.
+pub fn target(network: &Network, config: &Configuration, status: Status) -> Option<Target> {
+    if !config.auto_connect.untrusted || status != Status::Disconnected || network == &Network::default() {
+        return None;
+    }
+
+    if network.is_trusted(&config.auto_connect) {
+        return None;
+    }
+
+    config.recent.first().map(|recent| recent.target.clone())
+}
+
+/// Follows the network, asking the window to connect on untrusted ones.
+/// `state` reads the configuration and status as they are when the network changes.
+pub fn watch<S: NetworkSource, E: EventSink, F: Fn() -> (Configuration, Status)>(mut source: S, sink: E, state: F) {
+    let mut last = None;
+
+    while let Some(network) = source.next() {
+        if last.as_ref() == Some(&network) {
+            continue;
+        }
+
+        log::debug!("Network changed: {:?}", network);
+
+        let (config, status) = state();
+        if let Some(target) = target(&network, &config, status) {
+            log::info!("Connecting, the network is not trusted");
+            sink.emit(ConnectTo(target));
+        }
+
+        last = Some(network);
+    }
+
+    log::warn!("Stopped watching the network");
+}
+
+/// Reads the MAC address of a neighbour from the ARP table.
+#[cfg(target_os = "linux")]
+fn arp_lookup(ip: &str) -> Option<String> {
+    let table = std::fs::read_to_string("/proc/net/arp").ok()?;
+
+    // IP address, HW type, Flags, HW address, Mask, Device
+    table.lines()
+        .skip(1)
+        .map(|line| line.split_whitespace().collect::<Vec<&str>>())
+        .find(|fields| fields.first() == Some(&ip))
+        .and_then(|fields| fields.get(3).map(|mac| mac.to_string()))
+        .filter(|mac| mac != "00:00:00:00:00:00")
+}
+
+#[cfg(target_os = "linux")]
+const NM: &str = "org.freedesktop.NetworkManager";
+
+/// Follows NetworkManager's primary connection over D-Bus.
+#[cfg(target_os = "linux")]
+pub struct NetworkManager {
+    connection: zbus::blocking::Connection,
+    changes: zbus::blocking::PropertyIterator<'static, zbus::zvariant::OwnedObjectPath>,
+    started: bool
+}
+
+#[cfg(target_os = "linux")]
+impl NetworkManager {
+    pub fn new() -> zbus::Result<Self> {
+        let connection = zbus::blocking::Connection::system()?;
+        let manager = zbus::blocking::Proxy::new(&connection, NM, "/org/freedesktop/NetworkManager", NM)?;
+        let changes = manager.receive_property_changed("PrimaryConnection");
+
+        Ok(NetworkManager { connection, changes, started: false })
+    }
+
+    fn proxy<'a>(&self, path: &'a str, interface: &'a str) -> zbus::Result<zbus::blocking::Proxy<'a>> {
+        zbus::blocking::Proxy::new(&self.connection, NM, path, interface)
+    }
+
+    /// The primary connection, `None` while it's a VPN, ours included.
+    fn current(&self) -> zbus::Result<Option<Network>> {
+        let manager = self.proxy("/org/freedesktop/NetworkManager", NM)?;
+        let primary: zbus::zvariant::OwnedObjectPath = manager.get_property("PrimaryConnection")?;
+
+        // Offline
+        if primary.as_str() == "/" {
+            return Ok(Some(Network::default()));
+        }
+
+        let active = self.proxy(primary.as_str(), "org.freedesktop.NetworkManager.Connection.Active")?;
+        let kind: String = active.get_property("Type")?;
+
+        if matches!(kind.as_str(), "vpn" | "wireguard" | "tun") {
+            return Ok(None);
+        }
+
+        let ssid = if kind == "802-11-wireless" {
+            let access_point: zbus::zvariant::OwnedObjectPath = active.get_property("SpecificObject")?;
+            let ssid: Vec<u8> = self.proxy(access_point.as_str(), "org.freedesktop.NetworkManager.AccessPoint")?.get_property("Ssid")?;
+            Some(String::from_utf8_lossy(&ssid).into_owned())
+        } else {
+            None
+        };
+
+        let ip4: zbus::zvariant::OwnedObjectPath = active.get_property("Ip4Config")?;
+        let gateway = match ip4.as_str() {
+            "/" => None,
+            path => Some(self.proxy(path, "org.freedesktop.NetworkManager.IP4Config")?.get_property::<String>("Gateway")?)
+        }.filter(|gateway| !gateway.is_empty());
+
+        let gateway_mac = gateway.as_deref().and_then(arp_lookup);
+        Ok(Some(Network { ssid, gateway, gateway_mac }))
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl NetworkSource for NetworkManager {
+    fn next(&mut self) -> Option<Network> {
+        loop {
+            if self.started {
+                self.changes.next()?;
+            }
+            self.started = true;
+
+            match self.current() {
+                Ok(Some(network)) => return Some(network),
+                Ok(None) => continue,
+                Err(e) => log::warn!("Failed to read the network from NetworkManager: {}", e)
+            }
+        }
+    }
+}
+
+/// Asks Windows for the network every few seconds, it has no simple change notification.
+#[cfg(windows)]
+pub struct Netsh {
+    started: bool
+}
+
+#[cfg(windows)]
+impl Netsh {
+    pub fn new() -> Self {
+        Netsh { started: false }
+    }
+
+    fn output(program: &str, args: &[&str]) -> String {
+        Command::new(program).args(args).output()
+            .map(|output| String::from_utf8_lossy(&output.stdout).into_owned())
+            .unwrap_or_default()
+    }
+
+    pub fn current() -> Network {
+        // `    SSID                   : Home`, but not `BSSID`
+        let ssid = Self::output("netsh", &["wlan", "show", "interfaces"]).lines()
+            .map(|line| line.trim())
+            .find(|line| line.starts_with("SSID"))
+            .and_then(|line| line.split_once(':'))
+            .map(|(_, ssid)| ssid.trim().to_string());
+
+        // `          0.0.0.0          0.0.0.0      192.168.1.1    192.168.1.20     35`
+        let gateway = Self::output("route", &["print", "-4", "0.0.0.0"]).lines()
+            .map(|line| line.split_whitespace().collect::<Vec<&str>>())
+            .find(|fields| fields.len() == 5 && fields[0] == "0.0.0.0" && fields[1] == "0.0.0.0")
+            .map(|fields| fields[2].to_string());
+
+        // `  192.168.1.1           aa-bb-cc-dd-ee-ff     dynamic`
+        let gateway_mac = gateway.as_ref().and_then(|gateway| {
+            Self::output("arp", &["-a", gateway]).lines()
+                .map(|line| line.split_whitespace().collect::<Vec<&str>>())
+                .find(|fields| fields.first() == Some(&gateway.as_str()))
+                .and_then(|fields| fields.get(1).map(|mac| mac.replace('-', ":")))
+        });
+
+        Network { ssid, gateway, gateway_mac }
+    }
+}
+
+#[cfg(windows)]
+impl NetworkSource for Netsh {
+    fn next(&mut self) -> Option<Network> {
+        if self.started {
+            std::thread::sleep(Duration::from_secs(5));
+        }
+        self.started = true;
+
+        Some(Self::current())
+    }
+}
+
+/// The network the system is on right now, `None` when it can't tell.
+#[cfg(target_os = "linux")]
+pub fn current() -> Option<Network> {
+    match NetworkManager::new().and_then(|manager| manager.current()) {
+        Ok(network) => network.filter(|network| network != &Network::default()),
+        Err(e) => {
+            log::debug!("Failed to read the network from NetworkManager: {}", e);
+            None
+        }
+    }
+}
+
+/// The network the system is on right now, `None` when it can't tell.
+#[cfg(windows)]
+pub fn current() -> Option<Network> {
+    Some(Netsh::current()).filter(|network| network != &Network::default())
+}
+
+/// Replays networks sent down a channel, to test what happens on changes.
+#[cfg(test)]
+pub struct ChannelSource(pub Receiver<Network>);
+
+#[cfg(test)]
+impl NetworkSource for ChannelSource {
+    fn next(&mut self) -> Option<Network> {
+        self.0.recv().ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc::channel;
+
+    use serde_json::json;
+
+    use super::*;
+    use crate::{events::ChannelSink, mock_management::received, tray::Recent};
+
+    fn wifi(ssid: &str, gateway: &str) -> Network {
+        Network {
+            ssid: Some(ssid.to_string()),
+            gateway: Some(gateway.to_string()),
+            gateway_mac: Some(String::from("aa:bb:cc:dd:ee:ff"))
+        }
+    }
+
+    fn config() -> Configuration {
+        let mut config = Configuration::default();
+        config.auto_connect.untrusted = true;
+        config.auto_connect.trusted_ssids = vec![String::from("Home")];
+        config.auto_connect.trusted_gateways = vec![String::from("AA:BB:CC:00:00:01")];
+        config.recent = vec![Recent { target: Target::Location(7), name: String::from("Amsterdam") }];
+        config
+    }
+
+    #[test]
+    fn trusts_listed_ssids_and_gateways() {
+        let config = config();
+
+        assert!(wifi("Home", "192.168.1.1").is_trusted(&config.auto_connect));
+        assert!(!wifi("Cafe", "192.168.1.1").is_trusted(&config.auto_connect));
+
+        let mut wired = Network { ssid: None, gateway: Some(String::from("10.0.0.1")), gateway_mac: Some(String::from("aa:bb:cc:00:00:01")) };
+        assert!(wired.is_trusted(&config.auto_connect), "MAC addresses match whatever the case");
+
+        wired.gateway_mac = None;
+        assert!(!wired.is_trusted(&config.auto_connect));
+    }
+
+    #[test]
+    fn keys_networks_by_router_first() {
+        assert_eq!(wifi("Home", "192.168.1.1").key(), Some(String::from("aa:bb:cc:dd:ee:ff")));
+
+        let wired = Network { ssid: None, gateway: Some(String::from("10.0.0.1")), gateway_mac: None };
+        assert_eq!(wired.key(), Some(String::from("10.0.0.1")));
+        assert_eq!(Network::default().key(), None);
+    }
+
+    #[test]
+    fn connects_only_when_untrusted_and_idle() {
+        let mut config = config();
+        let cafe = wifi("Cafe", "192.168.1.1");
+
+        assert_eq!(target(&cafe, &config, Status::Disconnected), Some(Target::Location(7)));
+        assert_eq!(target(&wifi("Home", "192.168.1.1"), &config, Status::Disconnected), None);
+        assert_eq!(target(&cafe, &config, Status::Connected), None);
+        assert_eq!(target(&Network::default(), &config, Status::Disconnected), None, "offline");
+
+        config.recent.clear();
+        assert_eq!(target(&cafe, &config, Status::Disconnected), None, "nowhere to connect to");
+
+        config = self::config();
+        config.auto_connect.untrusted = false;
+        assert_eq!(target(&cafe, &config, Status::Disconnected), None);
+    }
+
+    #[test]
+    fn watch_connects_on_each_untrusted_change() {
+        let (sender, receiver) = channel();
+        let (sink, events) = ChannelSink::new();
+
+        for network in [wifi("Home", "192.168.1.1"), wifi("Cafe", "192.168.1.1"), wifi("Cafe", "192.168.1.1"), wifi("Airport", "10.0.0.1")] {
+            sender.send(network).unwrap();
+        }
+        drop(sender);
+
+        watch(ChannelSource(receiver), sink, || (config(), Status::Disconnected));
+
+        // Home is trusted, and the second Cafe is the same network
+        assert_eq!(received(&events), vec![
+            ("connect_to", json!({ "type": "location", "id": 7 })),
+            ("connect_to", json!({ "type": "location", "id": 7 }))
+        ]);
+    }
+}
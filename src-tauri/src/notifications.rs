@@ -0,0 +1,331 @@
+use std::{any::Any, collections::HashMap, sync::Mutex, time::{Duration, Instant}};
+
+use lazy_static::lazy_static;
+use serde::{Serialize, Deserialize};
+use tauri::api::notification::Notification;
+
+use crate::{CONFIGURATION, DESTINATION, Status, diagnostics::Diagnostic, events::{Event, EventSink, OpenVPNStatus}, management::OpenVPNState};
+
+/// The same notification is shown at most once in this interval,
+/// so a tunnel flapping between dropped and reconnected doesn't spam.
+/// Failures of something the user just did are always shown, they're waiting on it.
+const RATE_LIMIT: Duration = Duration::from_secs(60);
+
+lazy_static! {
+    /// Bundle identifier the notifications are sent as, set once the app is built
+    static ref IDENTIFIER: Mutex<Option<String>> = Mutex::new(None);
+    static ref STATE: Mutex<(Tracker, RateLimit)> = Mutex::new((Tracker::new(), RateLimit::new(RATE_LIMIT)));
+}
+
+/// Which notifications are shown.
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct NotificationConfig {
+    pub connected: bool,
+    pub disconnected: bool,
+    /// The tunnel went down without being asked to
+    pub dropped: bool,
+    /// The tunnel came back after dropping
+    pub reconnected: bool,
+    /// Failed connections, preflight checks and service changes
+    pub failures: bool
+}
+
+impl Default for NotificationConfig {
+    fn default() -> Self {
+        NotificationConfig {
+            connected: true,
+            disconnected: true,
+            dropped: true,
+            reconnected: true,
+            failures: true
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
+pub enum Kind {
+    Connected,
+    Disconnected,
+    Dropped,
+    Reconnected,
+    Failure
+}
+
+impl NotificationConfig {
+    pub fn enabled(&self, kind: Kind) -> bool {
+        match kind {
+            Kind::Connected => self.connected,
+            Kind::Disconnected => self.disconnected,
+            Kind::Dropped => self.dropped,
+            Kind::Reconnected => self.reconnected,
+            Kind::Failure => self.failures
+        }
+    }
+}
+
+/// Tells expected changes from drops, by following the status like the window does.
+#[derive(Debug)]
+pub struct Tracker {
+    status: Status,
+    /// Dropped while connected, waiting for the tunnel to come back
+    dropped: bool,
+    /// The diagnostics already said why this connection failed
+    explained: bool
+}
+
+impl Tracker {
+    pub fn new() -> Self {
+        Tracker { status: Status::Disconnected, dropped: false, explained: false }
+    }
+
+    /// Holds back the generic failure, a more telling one was shown.
+    pub fn explain(&mut self) {
+        self.explained = true;
+    }
+
+    pub fn status(&mut self, status: Status) -> Option<Kind> {
+        let previous = std::mem::replace(&mut self.status, status);
+
+        let kind = match (previous, status) {
+            (_, Status::Connected) if self.dropped => {
+                self.dropped = false;
+                Some(Kind::Reconnected)
+            },
+            (Status::Connecting, Status::Connected) => Some(Kind::Connected),
+            (Status::Disconnecting, Status::Disconnected) => Some(Kind::Disconnected),
+            // Nobody clicks connect while connected, the server stopped answering
+            (Status::Connected, Status::Connecting) => {
+                self.dropped = true;
+                Some(Kind::Dropped)
+            },
+            (Status::Connected, Status::Disconnected) => Some(Kind::Dropped),
+            (Status::Connecting, Status::Disconnected) if !self.explained => Some(Kind::Failure),
+            _ => None
+        };
+
+        // Whatever happened before, the next connection starts over
+        if status == Status::Disconnected {
+            self.dropped = false;
+            self.explained = false;
+        }
+
+        kind
+    }
+
+    /// OpenVPN reconnects on its own, without the status changing.
+    pub fn openvpn(&mut self, state: Option<&OpenVPNState>) -> Option<Kind> {
+        if self.status != Status::Connected {
+            return None;
+        }
+
+        let connected = state.is_some_and(|state| state.connected);
+        match (connected, self.dropped) {
+            (false, false) => {
+                self.dropped = true;
+                Some(Kind::Dropped)
+            },
+            (true, true) => {
+                self.dropped = false;
+                Some(Kind::Reconnected)
+            },
+            _ => None
+        }
+    }
+}
+
+/// Remembers when each notification, by kind and title, was last shown.
+#[derive(Debug)]
+pub struct RateLimit {
+    interval: Duration,
+    last: HashMap<(Kind, String), Instant>
+}
+
+impl RateLimit {
+    pub fn new(interval: Duration) -> Self {
+        RateLimit { interval, last: HashMap::new() }
+    }
+
+    pub fn allow(&mut self, kind: Kind, title: &str, now: Instant) -> bool {
+        let key = (kind, title.to_string());
+
+        match self.last.get(&key) {
+            Some(last) if now.duration_since(*last) < self.interval => false,
+            _ => {
+                self.last.insert(key, now);
+                true
+            }
+        }
+    }
+}
+
+/// Sets the identifier the notifications are sent as.
+pub fn init(identifier: String) {
+    *IDENTIFIER.lock().unwrap() = Some(identifier);
+}
+
+fn text(kind: Kind) -> (String, String) {
+    let destination = DESTINATION.lock().unwrap().as_ref().map(|destination| destination.name.clone());
+    let location = destination.unwrap_or(String::from("the VPN"));
+
+    match kind {
+        Kind::Connected => (String::from("Connected"), format!("Your traffic now goes through {}.", location)),
+        Kind::Disconnected => (String::from("Disconnected"), String::from("Your traffic no longer goes through the VPN.")),
+        Kind::Dropped => (String::from("Connection lost"), format!("The connection to {} dropped.", location)),
+        Kind::Reconnected => (String::from("Reconnected"), format!("The connection to {} is back.", location)),
+        Kind::Failure => (String::from("Connection failed"), format!("Could not connect to {}.", location))
+    }
+}
+
+/// Shows a notification, unless it's turned off or the same one was just shown.
+pub fn notify(kind: Kind, title: &str, body: &str) {
+    show(kind, title, body, true);
+}
+
+fn show(kind: Kind, title: &str, body: &str, limited: bool) {
+    if !CONFIGURATION.lock().unwrap().notifications.enabled(kind) {
+        return;
+    }
+
+    if limited && !STATE.lock().unwrap().1.allow(kind, title, Instant::now()) {
+        log::debug!("Not showing {:?} notification \"{}\", it was just shown", kind, title);
+        return;
+    }
+
+    let identifier = match IDENTIFIER.lock().unwrap().clone() {
+        Some(identifier) => identifier,
+        None => return
+    };
+
+    if let Err(e) = Notification::new(identifier).title(title).body(body).show() {
+        log::error!("Failed to show notification: {}", e);
+    }
+}
+
+/// Shows why something failed in the background, i.e. the preflight check.
+pub fn failure(title: &str, body: &str) {
+    notify(Kind::Failure, title, body);
+}
+
+/// Shows why something the user just did failed, never rate limited.
+pub fn action_failed(title: &str, body: &str) {
+    show(Kind::Failure, title, body, false);
+}
+
+/// Turns the connection events and known failures into notifications.
+#[derive(Clone, Copy)]
+pub struct Notifier;
+
+impl EventSink for Notifier {
+    fn emit<E: Event>(&self, event: E) {
+        let event = &event as &dyn Any;
+
+        // Says why it failed, instead of the generic failure once the status follows
+        if let Some(diagnostic) = event.downcast_ref::<Diagnostic>() {
+            STATE.lock().unwrap().0.explain();
+            action_failed("Connection failed", &diagnostic.message);
+            return;
+        }
+
+        let kind = {
+            let tracker = &mut STATE.lock().unwrap().0;
+
+            if let Some(status) = event.downcast_ref::<Status>() {
+                tracker.status(*status)
+            } else if let Some(OpenVPNStatus(state)) = event.downcast_ref::<OpenVPNStatus>() {
+                tracker.openvpn(state.as_ref())
+            } else {
+                None
+            }
+        };
+
+        // Failing to connect follows a click, or a network the user asked to connect on
+        if let Some(kind) = kind {
+            let (title, body) = text(kind);
+            show(kind, &title, &body, kind != Kind::Failure);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state(connected: bool) -> OpenVPNState {
+        OpenVPNState {
+            start: 1700000000,
+            connected,
+            state: String::from(if connected { "CONNECTED" } else { "RECONNECTING" }),
+            local_ip: String::from(if connected { "10.8.0.2" } else { "" }),
+            remote_ip: String::from(if connected { "198.51.100.7" } else { "" }),
+            port: Some(1194).filter(|_| connected)
+        }
+    }
+
+    #[test]
+    fn tells_drops_from_disconnects() {
+        let mut tracker = Tracker::new();
+        assert_eq!(tracker.status(Status::Connecting), None);
+        assert_eq!(tracker.status(Status::Connected), Some(Kind::Connected));
+        assert_eq!(tracker.status(Status::Disconnecting), None);
+        assert_eq!(tracker.status(Status::Disconnected), Some(Kind::Disconnected));
+
+        // Connected at startup, then the tunnel went away
+        assert_eq!(tracker.status(Status::Connected), None);
+        assert_eq!(tracker.status(Status::Disconnected), Some(Kind::Dropped));
+
+        assert_eq!(tracker.status(Status::Connecting), None);
+        assert_eq!(tracker.status(Status::Disconnected), Some(Kind::Failure));
+    }
+
+    #[test]
+    fn reports_reconnects_once() {
+        let mut tracker = Tracker::new();
+        tracker.status(Status::Connecting);
+        tracker.status(Status::Connected);
+
+        // WireGuard going stale and recovering
+        assert_eq!(tracker.status(Status::Connecting), Some(Kind::Dropped));
+        assert_eq!(tracker.status(Status::Connected), Some(Kind::Reconnected));
+
+        // OpenVPN restarting on its own, reporting every half second
+        assert_eq!(tracker.openvpn(None), Some(Kind::Dropped));
+        assert_eq!(tracker.openvpn(None), None);
+        assert_eq!(tracker.openvpn(Some(&state(false))), None);
+        assert_eq!(tracker.openvpn(Some(&state(true))), Some(Kind::Reconnected));
+        assert_eq!(tracker.openvpn(Some(&state(true))), None);
+
+        // Not connected, so there is nothing to drop
+        tracker.status(Status::Disconnecting);
+        tracker.status(Status::Disconnected);
+        assert_eq!(tracker.openvpn(None), None);
+    }
+
+    #[test]
+    fn holds_back_explained_failures() {
+        let mut tracker = Tracker::new();
+        tracker.status(Status::Connecting);
+        tracker.explain();
+        assert_eq!(tracker.status(Status::Disconnected), None);
+
+        // The next attempt isn't explained yet
+        tracker.status(Status::Connecting);
+        assert_eq!(tracker.status(Status::Disconnected), Some(Kind::Failure));
+    }
+
+    #[test]
+    fn rate_limits_each_kind_and_title() {
+        let mut limit = RateLimit::new(Duration::from_secs(60));
+        let start = Instant::now();
+
+        assert!(limit.allow(Kind::Dropped, "Connection lost", start));
+        assert!(limit.allow(Kind::Reconnected, "Reconnected", start));
+        assert!(!limit.allow(Kind::Dropped, "Connection lost", start + Duration::from_secs(10)));
+        assert!(limit.allow(Kind::Dropped, "Connection lost", start + Duration::from_secs(61)));
+
+        // Different failures don't hold each other back
+        assert!(limit.allow(Kind::Failure, "Possible leak", start));
+        assert!(limit.allow(Kind::Failure, "OpenVPN is not set up", start));
+        assert!(!limit.allow(Kind::Failure, "Possible leak", start + Duration::from_secs(10)));
+    }
+}
@@ -0,0 +1,499 @@
+use std::net::Ipv4Addr;
+
+use serde::{Serialize, Deserialize};
+use serde_repr::{Serialize_repr, Deserialize_repr};
+
+use crate::Protocol;
+
+/// What a profile connects to.
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", content = "id", rename_all = "lowercase")]
+pub enum Target {
+    /// An API location, by id
+    Location(i32),
+    /// An imported config, by id
+    Custom(String)
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default, Serialize_repr, Deserialize_repr)]
+#[repr(u8)]
+pub enum Transport {
+    #[default]
+    Udp,
+    Tcp
+}
+
+impl Transport {
+    /// Name used by the API and by OpenVPN's `proto` directive.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Transport::Udp => "udp",
+            Transport::Tcp => "tcp"
+        }
+    }
+}
+
+/// What happens to IPv6 traffic while connected.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize_repr, Deserialize_repr)]
+#[repr(u8)]
+pub enum Ipv6Mode {
+    /// Rejected by the firewall, for servers that only carry IPv4
+    Block,
+    /// Routed through the tunnel along with IPv4
+    Tunnel
+}
+
+/// A named set of connection options, e.g. "Work - TCP 443".
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+pub struct Profile {
+    #[serde(default)]
+    pub id: String,
+    pub name: String,
+    pub target: Target,
+    pub protocol: Protocol,
+    /// Only used by OpenVPN
+    pub transport: Transport,
+    /// Overrides the server port
+    pub port: Option<u16>,
+    #[serde(rename = "killSwitch")]
+    pub kill_switch: bool,
+    /// DNS servers to use while connected, the server's are used if empty
+    pub dns: Vec<String>,
+    /// IPv4 networks (CIDR) that bypass the tunnel
+    #[serde(rename = "splitTunnel")]
+    pub split_tunnel: Vec<String>,
+    /// Left to the server's config if unset, as in profiles saved before it could be chosen
+    #[serde(default)]
+    pub ipv6: Option<Ipv6Mode>
+}
+
+impl Profile {
+    pub fn new_id() -> String {
+        format!("{:016x}", rand::random::<u64>())
+    }
+
+    /// What the profile does with IPv6 on this config. Blocking it would leave a WireGuard config
+    /// without IPv4 addresses with no address at all, so it's left alone there.
+    pub fn ipv6_mode(&self, protocol: &Protocol, config: &str) -> Option<Ipv6Mode> {
+        match self.ipv6 {
+            Some(Ipv6Mode::Block) if protocol == &Protocol::WireGuard && !has_ipv4_address(config) => None,
+            mode => mode
+        }
+    }
+
+    /// Rewrites the config with the profile's options.
+    /// Runs before `patch_config`, which adds credentials and the management interface.
+    pub fn apply(&self, protocol: &Protocol, config: String) -> String {
+        let profile = Profile { ipv6: self.ipv6_mode(protocol, &config), ..self.clone() };
+
+        match protocol {
+            Protocol::OpenVPN => profile.apply_openvpn(config),
+            Protocol::WireGuard => profile.apply_wireguard(config)
+        }
+    }
+
+    fn apply_openvpn(&self, config: String) -> String {
+        // API configs are fetched for the profile's transport already, imported ones are rewritten
+        let config = match self.target {
+            Target::Location(_) if self.port.is_none() => config,
+            _ => set_transport(&config, self.transport, self.port)
+        };
+
+        let mut lines = config
+            .lines()
+            .map(|line| match line.trim() {
+                "tun-ipv6" if self.ipv6 == Some(Ipv6Mode::Block) => format!("# {}", line.trim()),
+                _ => line.to_string()
+            })
+            .collect::<Vec<String>>();
+
+        // The profile's servers replace the pushed ones, as with WireGuard's `DNS`
+        if !self.dns.is_empty() {
+            lines.push(String::from("pull-filter ignore \"dhcp-option DNS\""));
+        }
+        for server in &self.dns {
+            lines.push(format!("dhcp-option DNS {}", server));
+        }
+
+        for (network, prefix) in self.split_tunnel.iter().filter_map(|rule| parse_cidr(rule)) {
+            lines.push(format!("route {} {} net_gateway", Ipv4Addr::from(network), Ipv4Addr::from(mask(prefix))));
+        }
+
+        // The addresses themselves are pushed by the server, with `ifconfig-ipv6`
+        match self.ipv6 {
+            Some(Ipv6Mode::Tunnel) => {
+                if !lines.iter().any(|line| line.trim() == "tun-ipv6") {
+                    lines.push(String::from("tun-ipv6"));
+                }
+                lines.push(String::from("redirect-gateway def1 ipv6"));
+            },
+            // Rejected by the firewall either way, so don't set up what can't be used
+            Some(Ipv6Mode::Block) => {
+                lines.push(String::from("pull-filter ignore \"ifconfig-ipv6\""));
+                lines.push(String::from("pull-filter ignore \"route-ipv6\""));
+            },
+            None => {}
+        }
+
+        // The firewall does the blocking, keeping the tun device up while reconnecting saves tearing down the routes
+        if self.kill_switch {
+            lines.push(String::from("persist-tun"));
+        }
+
+        lines.join("\n")
+    }
+
+    fn apply_wireguard(&self, config: String) -> String {
+        let excluded = self.split_tunnel.iter()
+            .filter_map(|rule| parse_cidr(rule))
+            .collect::<Vec<(u32, u8)>>();
+
+        let mut lines = vec![];
+        let mut section = String::new();
+
+        for line in config.lines() {
+            let trimmed = line.trim();
+            let key = trimmed.split('=').next().unwrap_or_default().trim();
+            let value = trimmed.split_once('=').map(|(_, value)| value.trim()).unwrap_or_default();
+
+            if trimmed.starts_with('[') {
+                // Leaving [Interface], add what it's missing
+                if section == "[Interface]" {
+                    lines.extend(self.wireguard_interface());
+                }
+
+                section = trimmed.to_string();
+                lines.push(line.to_string());
+            } else if key == "DNS" && !self.dns.is_empty() {
+                // Replaced by the profile's servers
+                continue;
+            } else if let Some(port) = self.port.filter(|_| key == "Endpoint") {
+                let host = value.rsplit_once(':').map(|(host, _)| host).unwrap_or(value);
+                lines.push(format!("Endpoint = {}:{}", host, port));
+            } else if key == "AllowedIPs" {
+                lines.push(format!("AllowedIPs = {}", self.allowed_ips(value, &excluded).join(", ")));
+            } else if key == "Address" && self.ipv6 == Some(Ipv6Mode::Block) {
+                let addresses = value.split(',')
+                    .map(|address| address.trim())
+                    .filter(|address| !address.contains(':'))
+                    .collect::<Vec<&str>>();
+
+                lines.push(format!("Address = {}", addresses.join(", ")));
+            } else {
+                lines.push(line.to_string());
+            }
+        }
+
+        if section == "[Interface]" {
+            lines.extend(self.wireguard_interface());
+        }
+
+        lines.join("\n")
+    }
+
+    /// Removes the split tunnel's networks from the allowed ranges, and adds or drops the IPv6 ones.
+    fn allowed_ips(&self, value: &str, excluded: &[(u32, u8)]) -> Vec<String> {
+        let ranges = value.split(',')
+            .map(|ip| ip.trim())
+            .filter(|ip| !ip.is_empty())
+            .collect::<Vec<&str>>();
+
+        let mut allowed = ranges.iter()
+            .filter(|ip| self.ipv6 != Some(Ipv6Mode::Block) || !ip.contains(':'))
+            .flat_map(|ip| match parse_cidr(ip) {
+                Some(cidr) => excluded.iter()
+                    .fold(vec![cidr], |allowed, excluded| allowed.into_iter()
+                        .flat_map(|allowed| subtract(allowed, *excluded))
+                        .collect())
+                    .into_iter()
+                    .map(|(network, prefix)| format!("{}/{}", Ipv4Addr::from(network), prefix))
+                    .collect::<Vec<String>>(),
+                // The split tunnel only takes IPv4 networks out
+                None => vec![ip.to_string()]
+            })
+            .collect::<Vec<String>>();
+
+        // Only full tunnels take IPv6 along, split ones keep to the ranges they were given
+        if self.ipv6 == Some(Ipv6Mode::Tunnel) && ranges.contains(&"0.0.0.0/0") && !ranges.contains(&"::/0") {
+            allowed.push(String::from("::/0"));
+        }
+
+        allowed
+    }
+
+    fn wireguard_interface(&self) -> Vec<String> {
+        let mut lines = vec![];
+
+        if !self.dns.is_empty() {
+            lines.push(format!("DNS = {}", self.dns.join(", ")));
+        }
+
+        lines
+    }
+}
+
+/// Rewrites an OpenVPN config's `proto` and `remote` directives, keeping the port of each remote unless one is given.
+/// Remotes without a port keep using the config's `port` or `rport`, or OpenVPN's default.
+pub fn set_transport(config: &str, transport: Transport, port: Option<u16>) -> String {
+    let mut lines = vec![];
+
+    for line in config.lines() {
+        let trimmed = line.trim();
+
+        if trimmed.starts_with("proto ") {
+            lines.push(format!("proto {}", transport.as_str()));
+        } else if let Some(remote) = trimmed.strip_prefix("remote ") {
+            // `remote host [port] [proto]`, the protocol is set globally above
+            let mut split = remote.split_whitespace();
+            let host = split.next().unwrap_or_default();
+            match port.map(|p| p.to_string()).or(split.next().map(String::from)) {
+                Some(port) => lines.push(format!("remote {} {}", host, port)),
+                None => lines.push(format!("remote {}", host))
+            }
+        } else if trimmed.starts_with("explicit-exit-notify") && transport == Transport::Tcp {
+            // Only valid over UDP, OpenVPN refuses to start otherwise
+            lines.push(format!("# {}", trimmed));
+        } else {
+            lines.push(line.to_string());
+        }
+    }
+
+    if !lines.iter().any(|line| line.starts_with("proto ")) {
+        lines.push(format!("proto {}", transport.as_str()));
+    }
+
+    lines.join("\n")
+}
+
+/// Whether a WireGuard config's interface has an IPv4 address.
+fn has_ipv4_address(config: &str) -> bool {
+    config.lines()
+        .filter_map(|line| line.split_once('='))
+        .filter(|(key, _)| key.trim() == "Address")
+        .flat_map(|(_, value)| value.split(','))
+        .any(|address| address.split('/').next().unwrap_or_default().trim().parse::<Ipv4Addr>().is_ok())
+}
+
+fn mask(prefix: u8) -> u32 {
+    if prefix == 0 { 0 } else { u32::MAX << (32 - prefix as u32) }
+}
+
+/// Parses an IPv4 CIDR such as `10.0.0.0/8`, a bare address is a /32.
+fn parse_cidr(cidr: &str) -> Option<(u32, u8)> {
+    let (address, prefix) = cidr.trim().split_once('/').unwrap_or((cidr.trim(), "32"));
+    let address = address.parse::<Ipv4Addr>().ok()?;
+    let prefix = prefix.parse::<u8>().ok().filter(|prefix| *prefix <= 32)?;
+
+    Some((u32::from(address) & mask(prefix), prefix))
+}
+
+fn contains(outer: (u32, u8), inner: (u32, u8)) -> bool {
+    inner.1 >= outer.1 && inner.0 & mask(outer.1) == outer.0
+}
+
+/// Removes `excluded` from `allowed`, splitting it into the smallest set of ranges.
+fn subtract(allowed: (u32, u8), excluded: (u32, u8)) -> Vec<(u32, u8)> {
+    if contains(excluded, allowed) {
+        return vec![];
+    }
+
+    if !contains(allowed, excluded) {
+        return vec![allowed];
+    }
+
+    let prefix = allowed.1 + 1;
+    let low = (allowed.0, prefix);
+    let high = (allowed.0 | (1 << (32 - prefix as u32)), prefix);
+
+    let mut ranges = subtract(low, excluded);
+    ranges.extend(subtract(high, excluded));
+    ranges
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OPENVPN: &str = "client\ndev tun\nproto udp\nremote 198.51.100.7 1194\nremote backup.example.com\nexplicit-exit-notify 3";
+
+    #[test]
+    fn rewrites_transport_and_port() {
+        assert_eq!(
+            set_transport(OPENVPN, Transport::Tcp, Some(443)),
+            "client\ndev tun\nproto tcp\nremote 198.51.100.7 443\nremote backup.example.com 443\n# explicit-exit-notify 3"
+        );
+
+        // Each remote keeps its port, or the global one if it had none
+        assert_eq!(
+            set_transport(OPENVPN, Transport::Udp, None),
+            "client\ndev tun\nproto udp\nremote 198.51.100.7 1194\nremote backup.example.com\nexplicit-exit-notify 3"
+        );
+        assert!(set_transport("client\nport 443\nremote vpn.example.com\nverb 3", Transport::Tcp, None).contains("\nport 443\nremote vpn.example.com\n"));
+
+        assert!(set_transport("client\nremote 198.51.100.7 1194", Transport::Tcp, None).ends_with("\nproto tcp"));
+    }
+
+    fn profile() -> Profile {
+        Profile {
+            id: Profile::new_id(),
+            name: String::from("Work"),
+            target: Target::Location(1),
+            protocol: Protocol::WireGuard,
+            transport: Transport::Udp,
+            port: None,
+            kill_switch: false,
+            dns: vec![],
+            split_tunnel: vec![],
+            ipv6: Some(Ipv6Mode::Block)
+        }
+    }
+
+    fn cidr(cidr: &str) -> (u32, u8) {
+        parse_cidr(cidr).unwrap()
+    }
+
+    #[test]
+    fn parses_cidrs() {
+        assert_eq!(parse_cidr("10.1.2.3/8"), Some((u32::from(Ipv4Addr::new(10, 0, 0, 0)), 8)));
+        assert_eq!(parse_cidr("192.168.1.1"), Some((u32::from(Ipv4Addr::new(192, 168, 1, 1)), 32)));
+        assert_eq!(parse_cidr("10.0.0.0/33"), None);
+        assert_eq!(parse_cidr("2001:db8::/32"), None);
+    }
+
+    #[test]
+    fn subtracts_networks() {
+        // Halving down to the excluded network
+        assert_eq!(subtract(cidr("10.0.0.0/8"), cidr("10.0.0.0/9")), vec![cidr("10.128.0.0/9")]);
+        assert_eq!(subtract(cidr("0.0.0.0/0"), cidr("192.168.0.0/16")).len(), 16);
+        // A single address leaves one range of each size around it
+        let ranges = subtract(cidr("192.168.0.0/24"), cidr("192.168.0.7/32"));
+        assert_eq!(ranges.len(), 8);
+        assert_eq!(ranges.iter().map(|(_, prefix)| 1u32 << (32 - *prefix as u32)).sum::<u32>(), 255);
+        assert!(ranges.iter().all(|range| !contains(*range, cidr("192.168.0.7"))));
+        assert!(ranges.contains(&cidr("192.168.0.6/32")));
+
+        // Nothing left, or nothing to take out
+        assert_eq!(subtract(cidr("10.1.0.0/16"), cidr("10.0.0.0/8")), vec![]);
+        assert_eq!(subtract(cidr("10.0.0.0/8"), cidr("192.168.0.0/16")), vec![cidr("10.0.0.0/8")]);
+    }
+
+    #[test]
+    fn splits_the_tunnel_around_excluded_networks() {
+        let config = "[Interface]\nPrivateKey = cHJpdmF0ZQ==\nAddress = 10.64.0.2/32, fd00::2/128\nDNS = 10.64.0.1\n\n[Peer]\nPublicKey = cHVibGlj\nAllowedIPs = 0.0.0.0/1, 128.0.0.0/1, ::/0\nEndpoint = 198.51.100.7:51820";
+        let profile = Profile {
+            dns: vec![String::from("9.9.9.9")],
+            split_tunnel: vec![String::from("192.168.0.0/16")],
+            port: Some(443),
+            ..profile()
+        };
+
+        let config = profile.apply(&Protocol::WireGuard, config.to_string());
+        let lines = config.lines().collect::<Vec<&str>>();
+
+        // The profile's DNS replaces the server's, at the end of [Interface]
+        assert!(!lines.contains(&"DNS = 10.64.0.1"));
+        assert_eq!(lines[lines.iter().position(|line| *line == "[Peer]").unwrap() - 1], "DNS = 9.9.9.9");
+
+        // IPv6 is blocked, so its address and range are gone
+        assert!(lines.contains(&"Address = 10.64.0.2/32"));
+        assert!(lines.contains(&"Endpoint = 198.51.100.7:443"));
+
+        let allowed = lines.iter().find_map(|line| line.strip_prefix("AllowedIPs = ")).unwrap().split(", ").collect::<Vec<&str>>();
+        assert!(allowed.contains(&"0.0.0.0/1"));
+        assert!(allowed.contains(&"192.0.0.0/9"));
+        assert!(allowed.contains(&"192.169.0.0/16"));
+        assert!(!allowed.iter().any(|range| range.starts_with("192.168.")));
+        assert!(!allowed.contains(&"::/0"));
+    }
+
+    #[test]
+    fn replaces_the_pushed_dns_servers() {
+        let custom = Profile {
+            protocol: Protocol::OpenVPN,
+            dns: vec![String::from("9.9.9.9"), String::from("149.112.112.112")],
+            ..profile()
+        };
+
+        let config = custom.apply(&Protocol::OpenVPN, OPENVPN.to_string());
+        let lines = config.lines().collect::<Vec<&str>>();
+        let filter = lines.iter().position(|line| *line == "pull-filter ignore \"dhcp-option DNS\"").unwrap();
+        assert_eq!(lines[filter + 1..filter + 3], ["dhcp-option DNS 9.9.9.9", "dhcp-option DNS 149.112.112.112"]);
+
+        // Without servers of its own, the pushed ones are kept
+        let config = Profile { protocol: Protocol::OpenVPN, ..profile() }.apply(&Protocol::OpenVPN, OPENVPN.to_string());
+        assert!(!config.contains("dhcp-option DNS"));
+    }
+
+    #[test]
+    fn tunnels_ipv6_in_full_tunnels() {
+        let config = "[Interface]\nAddress = 10.64.0.2/32\n\n[Peer]\nAllowedIPs = 0.0.0.0/0";
+        let profile = Profile { ipv6: Some(Ipv6Mode::Tunnel), ..profile() };
+
+        assert!(profile.apply(&Protocol::WireGuard, config.to_string()).ends_with("AllowedIPs = 0.0.0.0/0, ::/0"));
+
+        // Split tunnels keep to their ranges
+        let config = config.replace("0.0.0.0/0", "10.0.0.0/8");
+        assert!(profile.apply(&Protocol::WireGuard, config).ends_with("AllowedIPs = 10.0.0.0/8"));
+    }
+
+    #[test]
+    fn keeps_the_transport_of_api_configs() {
+        let tcp = "client\ndev tun\nproto tcp\nremote 198.51.100.7 443";
+        let profile = Profile { protocol: Protocol::OpenVPN, transport: Transport::Tcp, ipv6: None, ..profile() };
+
+        // Fetched for TCP already, with the API's port for it
+        assert!(profile.apply(&Protocol::OpenVPN, tcp.to_string()).starts_with(tcp));
+
+        // Only a port override changes the remotes
+        let profile = Profile { port: Some(8443), ..profile };
+        assert!(profile.apply(&Protocol::OpenVPN, tcp.to_string()).starts_with("client\ndev tun\nproto tcp\nremote 198.51.100.7 8443"));
+
+        // Imported configs have nothing else to go by
+        let profile = Profile { target: Target::Custom(String::from("work")), port: None, ..profile };
+        assert!(profile.apply(&Protocol::OpenVPN, OPENVPN.to_string()).contains("\nproto tcp\n"));
+    }
+
+    #[test]
+    fn leaves_ipv6_alone_in_older_profiles() {
+        let profile = serde_json::from_str::<Profile>(r#"{
+            "id": "0123456789abcdef",
+            "name": "Work",
+            "target": { "type": "location", "id": 1 },
+            "protocol": 1,
+            "transport": 0,
+            "port": null,
+            "killSwitch": false,
+            "dns": [],
+            "splitTunnel": []
+        }"#).unwrap();
+        assert_eq!(profile.ipv6, None);
+
+        let config = "[Interface]\nAddress = 10.64.0.2/32, fd00::2/128\n\n[Peer]\nAllowedIPs = 0.0.0.0/0, ::/0";
+        assert_eq!(profile.ipv6_mode(&Protocol::WireGuard, config), None);
+        assert_eq!(profile.apply(&Protocol::WireGuard, config.to_string()), config);
+
+        let config = profile.apply(&Protocol::OpenVPN, String::from("client\ntun-ipv6\nremote 198.51.100.7 1194"));
+        assert!(config.contains("\ntun-ipv6\n") && !config.contains("ipv6\""));
+    }
+
+    #[test]
+    fn keeps_ipv6_in_configs_without_ipv4_addresses() {
+        let config = "[Interface]\nAddress = fd00::2/128\n\n[Peer]\nAllowedIPs = ::/0\nEndpoint = [2001:db8::7]:51820";
+        let profile = profile();
+
+        // Blocking would leave `Address =` empty, which wg-quick refuses
+        assert_eq!(profile.ipv6_mode(&Protocol::WireGuard, config), None);
+        assert_eq!(profile.apply(&Protocol::WireGuard, config.to_string()), config);
+
+        // With an IPv4 address to keep, it's blocked as usual
+        let config = config.replace("fd00::2/128", "10.64.0.2/32, fd00::2/128");
+        assert_eq!(profile.ipv6_mode(&Protocol::WireGuard, &config), Some(Ipv6Mode::Block));
+        assert!(profile.apply(&Protocol::WireGuard, config).contains("Address = 10.64.0.2/32\n"));
+    }
+
+    #[test]
+    fn falling_back_twice_changes_nothing() {
+        let fallback = set_transport(OPENVPN, Transport::Tcp, Some(443));
+        assert_eq!(set_transport(&fallback, Transport::Tcp, Some(443)), fallback);
+    }
+}
@@ -0,0 +1,99 @@
+use std::{borrow::Cow, fmt::{Debug, Display, Formatter}};
+
+use lazy_static::lazy_static;
+use regex::Regex;
+use serde::{Serialize, Deserialize};
+
+pub const REDACTED: &str = "[redacted]";
+
+/// A value that never shows up in `{:?}` or `{}`, so it can't be logged by accident.
+/// Serialization is unaffected, the config and the frontend still need the real value.
+#[derive(Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Secret<T>(T);
+
+impl<T> Secret<T> {
+    pub fn new(value: T) -> Self {
+        Secret(value)
+    }
+
+    /// The actual value, keep it out of log lines.
+    pub fn expose(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T> From<T> for Secret<T> {
+    fn from(value: T) -> Self {
+        Secret(value)
+    }
+}
+
+impl<T> Debug for Secret<T> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", REDACTED)
+    }
+}
+
+impl<T> Display for Secret<T> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", REDACTED)
+    }
+}
+
+lazy_static! {
+    /// Secrets that could end up in a line through something other than a `Secret`,
+    /// such as a config or a server response. The groups around the secret are kept.
+    static ref PATTERNS: Vec<Regex> = [
+        // Inline OpenVPN credentials and keys
+        r"(?s)(<(?:auth-user-pass|key|tls-auth|tls-crypt|tls-crypt-v2|secret)>).*?(</|$)",
+        r"(?s)(-----BEGIN [A-Z ]*PRIVATE KEY-----).*?(-----END [A-Z ]*PRIVATE KEY-----|$)",
+        // WireGuard configs and the UAPI
+        r"(?i)((?:PrivateKey|PresharedKey|private_key|preshared_key)\s*=\s*)[^\s,]+",
+        // JSON bodies, i.e. `"password":"..."` or `"token": "..."`
+        r#"(?i)("(?:password|token|access_token|refresh_token|api_key|private_key)"\s*:\s*")(?:[^"\\]|\\.)*(")"#,
+        r"(?i)((?:Authorization:\s*)?Bearer\s+)[A-Za-z0-9\-._~+/]+=*"
+    ]
+    .iter()
+    .map(|pattern| Regex::new(pattern).unwrap())
+    .collect();
+}
+
+/// Replaces anything looking like a secret, used on every log line.
+pub fn scrub(text: &str) -> Cow<'_, str> {
+    let mut text = Cow::Borrowed(text);
+
+    for pattern in PATTERNS.iter() {
+        if pattern.is_match(&text) {
+            // `$2` closes the block again when there is one
+            let replaced = pattern.replace_all(&text, format!("${{1}}{}${{2}}", REDACTED).as_str()).to_string();
+            text = Cow::Owned(replaced);
+        }
+    }
+
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn secret_is_redacted_when_formatted() {
+        let password = Secret::new(String::from("hunter22"));
+
+        assert_eq!(format!("{:?} {}", password, password), "[redacted] [redacted]");
+        assert_eq!(password.expose(), "hunter22");
+        assert_eq!(serde_json::to_string(&password).unwrap(), "\"hunter22\"");
+    }
+
+    #[test]
+    fn scrubs_known_patterns() {
+        assert_eq!(scrub("<auth-user-pass>\nuser\nhunter22\n</auth-user-pass>"), "<auth-user-pass>[redacted]</auth-user-pass>");
+        assert_eq!(scrub("PrivateKey = yAnz5TF+lXXJte14tji3zlMNq+hd2rYUIgJBgB3fBmk="), "PrivateKey = [redacted]");
+        assert_eq!(scrub("private_key=e84b5a6d2717c1003a13b431570353dbaca9146cf150c5f8575680feba52027a"), "private_key=[redacted]");
+        assert_eq!(scrub(r#"{"token":"eyJhbGciOi.J9","user":"octo"}"#), r#"{"token":"[redacted]","user":"octo"}"#);
+        assert_eq!(scrub("Authorization: Bearer eyJhbGciOi.J9"), "Authorization: Bearer [redacted]");
+        assert_eq!(scrub("Connected to 198.51.100.7:1194"), "Connected to 198.51.100.7:1194");
+    }
+}
@@ -0,0 +1,289 @@
+use std::{fmt::{Display, Formatter}, sync::Mutex, time::{Duration, SystemTime, UNIX_EPOCH}};
+
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+use lazy_static::lazy_static;
+use reqwest::{StatusCode, header};
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::{CONFIGURATION, Credentials, Protocol, config::{config_path, write_config}, events::{ConfigChanged, EventSink, SessionExpired, SessionToken}, locations::{self, BASE_URL}, multihop, secret::Secret};
+
+// The API session. The token only lives in memory, a restart logs in again with the
+// stored credentials. It's renewed shortly before it expires, or when the API refuses it,
+// and only if the API refuses the credentials too is the window told to ask for them.
+
+/// How long tokens that don't tell their expiry are trusted for.
+const DEFAULT_LIFETIME: Duration = Duration::from_secs(60 * 60);
+/// Renews this long before the expiry, so a request never goes out with a token about to expire.
+const MARGIN: Duration = Duration::from_secs(60);
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+lazy_static! {
+    static ref SESSION: Mutex<Option<Session>> = Mutex::new(None);
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ApiError {
+    /// The token or the credentials were refused
+    Unauthorized,
+    Failed(String)
+}
+
+impl Display for ApiError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ApiError::Unauthorized => write!(f, "Unauthorized"),
+            ApiError::Failed(e) => write!(f, "{}", e)
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Session {
+    pub token: Secret<String>,
+    /// Unix time
+    pub expires: u64
+}
+
+impl Session {
+    pub fn new(token: String, now: u64) -> Self {
+        let expires = expiry(&token).unwrap_or(now + DEFAULT_LIFETIME.as_secs());
+        Session { token: Secret::new(token), expires }
+    }
+
+    pub fn is_valid(&self, now: u64) -> bool {
+        now + MARGIN.as_secs() < self.expires
+    }
+}
+
+#[derive(Deserialize)]
+struct LoginData {
+    token: String
+}
+
+#[derive(Deserialize)]
+struct ErrorData {
+    code: Option<u16>,
+    message: Option<String>
+}
+
+#[derive(Deserialize)]
+struct Response<T> {
+    success: bool,
+    data: Option<T>,
+    error: Option<ErrorData>
+}
+
+/// Reads the `exp` claim of JWTs, other tokens don't tell.
+pub fn expiry(token: &str) -> Option<u64> {
+    let claims = token.split('.').nth(1)?;
+    let claims = URL_SAFE_NO_PAD.decode(claims.trim_end_matches('=')).ok()?;
+
+    serde_json::from_slice::<Value>(&claims).ok()?["exp"].as_u64()
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|now| now.as_secs()).unwrap_or_default()
+}
+
+fn client() -> Result<reqwest::blocking::Client, ApiError> {
+    reqwest::blocking::Client::builder()
+        .timeout(TIMEOUT)
+        .build()
+        .map_err(|e| ApiError::Failed(e.to_string()))
+}
+
+/// Trades the credentials for a token.
+pub fn authenticate(url: &str, credentials: &Credentials) -> Result<String, ApiError> {
+    let body = json!({ "username": credentials.username, "password": credentials.password.expose() });
+
+    let response = client()?
+        .post(url)
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(body.to_string())
+        .send()
+        .map_err(|e| ApiError::Failed(e.to_string()))?;
+
+    if matches!(response.status(), StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN) {
+        return Err(ApiError::Unauthorized);
+    }
+
+    let body = response.error_for_status()
+        .and_then(|response| response.text())
+        .map_err(|e| ApiError::Failed(e.to_string()))?;
+
+    // Only a refusal of the credentials themselves asks for new ones, not any failure
+    match serde_json::from_str::<Response<LoginData>>(&body) {
+        Ok(Response { success: true, data: Some(data), .. }) => Ok(data.token),
+        Ok(Response { error: Some(ErrorData { code: Some(401 | 403), .. }), .. }) => Err(ApiError::Unauthorized),
+        Ok(Response { error, .. }) => Err(ApiError::Failed(
+            error.and_then(|error| error.message).unwrap_or(String::from("The API refused to log in"))
+        )),
+        Err(e) => Err(ApiError::Failed(format!("Unexpected answer from the API: {}", e)))
+    }
+}
+
+/// Logs in, keeping the credentials to log in again later on.
+pub fn login<E: EventSink>(credentials: Credentials, sink: &E) -> Result<Secret<String>, ApiError> {
+    let token = authenticate(&format!("{}/account/login", BASE_URL), &credentials)?;
+    let session = Session::new(token, now());
+    *SESSION.lock().unwrap() = Some(session.clone());
+
+    let config = {
+        let mut configuration = CONFIGURATION.lock().unwrap();
+        configuration.credentials = Some(credentials);
+        write_config(&configuration);
+        ConfigChanged::new(&configuration)
+    };
+
+    log::info!("Logged in");
+    sink.emit(config);
+    Ok(session.token)
+}
+
+/// Logs in again with the stored credentials.
+/// Tells the window the session expired if they were refused, not if the API couldn't be reached.
+pub fn renew<E: EventSink>(sink: &E) -> Result<Secret<String>, ApiError> {
+    let credentials = CONFIGURATION.lock().unwrap().credentials.clone()
+        .ok_or(ApiError::Failed(String::from("Not logged in")))?;
+
+    match authenticate(&format!("{}/account/login", BASE_URL), &credentials) {
+        Ok(token) => {
+            let session = Session::new(token, now());
+            *SESSION.lock().unwrap() = Some(session.clone());
+
+            log::info!("Renewed the session");
+            sink.emit(SessionToken(session.token.clone()));
+            Ok(session.token)
+        },
+        Err(ApiError::Unauthorized) => {
+            log::warn!("The stored credentials were refused, the session expired");
+            *SESSION.lock().unwrap() = None;
+            sink.emit(SessionExpired);
+            Err(ApiError::Unauthorized)
+        },
+        Err(e) => {
+            log::warn!("Failed to renew the session: {}", e);
+            Err(e)
+        }
+    }
+}
+
+/// A token that's still valid, renewed first if needed.
+pub fn token<E: EventSink>(sink: &E) -> Result<Secret<String>, ApiError> {
+    let session = SESSION.lock().unwrap().clone();
+
+    match session.filter(|session| session.is_valid(now())) {
+        Some(session) => Ok(session.token),
+        None => renew(sink)
+    }
+}
+
+/// Tells the session the API refused `token`, renewing it unless that happened already.
+pub fn rejected<E: EventSink>(token: &str, sink: &E) -> Result<Secret<String>, ApiError> {
+    let current = SESSION.lock().unwrap().clone();
+
+    match current.filter(|session| session.token.expose() != token) {
+        Some(session) => Ok(session.token),
+        None => renew(sink)
+    }
+}
+
+/// Runs an API request with the session's token, renewing it once if it's refused.
+pub fn authorized<T, E: EventSink, F: Fn(&str) -> Result<T, ApiError>>(sink: &E, request: F) -> Result<T, ApiError> {
+    let token = token(sink)?;
+
+    match request(token.expose()) {
+        Err(ApiError::Unauthorized) => request(rejected(token.expose(), sink)?.expose()),
+        result => result
+    }
+}
+
+/// Ends the session, and forgets the credentials and anything fetched with them.
+pub fn logout<E: EventSink>(sink: &E) {
+    if let Some(session) = SESSION.lock().unwrap().take() {
+        // Best effort, the token is gone here either way
+        let result = client().and_then(|client| {
+            client.post(&format!("{}/account/logout", BASE_URL))
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(json!({ "token": session.token.expose() }).to_string())
+                .send()
+                .map_err(|e| ApiError::Failed(e.to_string()))
+        });
+
+        if let Err(e) = result {
+            log::warn!("Failed to end the session with the API: {}", e);
+        }
+    }
+
+    let config = {
+        let mut configuration = CONFIGURATION.lock().unwrap();
+        configuration.credentials = None;
+        write_config(&configuration);
+        ConfigChanged::new(&configuration)
+    };
+
+    locations::clear();
+    for path in [config_path(&Protocol::OpenVPN), config_path(&Protocol::WireGuard), multihop::config_path()] {
+        match std::fs::remove_file(&path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => log::warn!("Failed to remove {}: {}", path, e),
+            _ => {}
+        }
+    }
+
+    log::info!("Logged out");
+    sink.emit(config);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_http::{response, serve};
+
+    fn credentials(password: &str) -> Credentials {
+        Credentials { username: String::from("octo"), password: Secret::new(password.to_string()) }
+    }
+
+    /// Hands out a token for the right password, like the login endpoint.
+    fn serve_login() -> String {
+        serve("/account/login", |request| match request.contains("\"password\":\"hunter22\"") {
+            true => response("200 OK", &[], r#"{ "success": true, "data": { "token": "abc" } }"#),
+            false if request.contains("\"username\":\"octo\"") => {
+                response("200 OK", &[], r#"{ "success": false, "error": { "code": 401, "message": "Invalid credentials" } }"#)
+            },
+            false => response("200 OK", &[], r#"{ "success": false, "error": { "code": 500, "message": "Try again later" } }"#)
+        })
+    }
+
+    #[test]
+    fn reads_jwt_expiry() {
+        let claims = URL_SAFE_NO_PAD.encode(r#"{"sub":"octo","exp":1700000000}"#);
+        assert_eq!(expiry(&format!("eyJhbGciOiJIUzI1NiJ9.{}.c2lnbmF0dXJl", claims)), Some(1700000000));
+
+        assert_eq!(expiry("abc"), None);
+        assert_eq!(expiry("not.base64!.jwt"), None);
+    }
+
+    #[test]
+    fn renews_ahead_of_expiry() {
+        let session = Session::new(String::from("abc"), 1000);
+        assert_eq!(session.expires, 1000 + DEFAULT_LIFETIME.as_secs());
+
+        assert!(session.is_valid(1000));
+        assert!(!session.is_valid(session.expires - MARGIN.as_secs()));
+        assert!(!session.is_valid(session.expires));
+    }
+
+    #[test]
+    fn authenticates_with_credentials() {
+        let url = serve_login();
+
+        assert_eq!(authenticate(&url, &credentials("hunter22")), Ok(String::from("abc")));
+        assert_eq!(authenticate(&url, &credentials("hunter2")), Err(ApiError::Unauthorized));
+
+        // Failing for another reason keeps the credentials
+        let other = Credentials { username: String::from("other"), ..credentials("hunter2") };
+        assert_eq!(authenticate(&url, &other), Err(ApiError::Failed(String::from("Try again later"))));
+        assert!(matches!(authenticate("http://127.0.0.1:1/account/login", &credentials("hunter22")), Err(ApiError::Failed(_))));
+    }
+}
@@ -0,0 +1,321 @@
+use std::any::Any;
+
+use serde::{Serialize, Deserialize};
+use tauri::{AppHandle, CustomMenuItem, Icon, Manager, SystemTray, SystemTrayEvent, SystemTrayMenu, SystemTrayMenuItem, SystemTraySubmenu, Window};
+
+use crate::{Configuration, CONFIGURATION, DESTINATION, STATUS, Protocol, Status, app_sink, locations, set_connection, config::write_config, events::{ConfigChanged, ConnectTo, Event, EventSink}, profile::Target};
+
+/// How many recent locations the tray offers.
+const MAX_RECENT: usize = 5;
+
+/// A location connected to before, enough to show it and connect again.
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+pub struct Recent {
+    pub target: Target,
+    pub name: String
+}
+
+fn icon(status: Status) -> Icon {
+    let bytes: &[u8] = match status {
+        Status::Connected => include_bytes!("../icons/tray/connected.png"),
+        Status::Connecting | Status::Disconnecting => include_bytes!("../icons/tray/connecting.png"),
+        Status::Disconnected => include_bytes!("../icons/tray/disconnected.png")
+    };
+
+    Icon::Raw(bytes.to_vec())
+}
+
+fn label(status: Status) -> String {
+    match status {
+        Status::Connected => match DESTINATION.lock().unwrap().as_ref() {
+            Some(destination) => format!("Connected to {}", destination.name),
+            None => String::from("Connected")
+        },
+        Status::Connecting => String::from("Connecting..."),
+        Status::Disconnecting => String::from("Disconnecting..."),
+        Status::Disconnected => String::from("Disconnected")
+    }
+}
+
+/// What the tray menu shows, apart from tauri's types.
+#[derive(Debug, PartialEq, Eq)]
+enum Entry {
+    Item { id: String, title: String, enabled: bool },
+    Submenu { title: &'static str, items: Vec<Entry> },
+    Separator
+}
+
+fn item(id: &str, title: String, enabled: bool) -> Entry {
+    Entry::Item { id: id.to_string(), title, enabled }
+}
+
+/// The menu for the settings and status, with the favourites already named.
+fn entries(config: &Configuration, status: Status, label: String, favourites: &[(i32, String)]) -> Vec<Entry> {
+    // Connecting is only offered when there is nothing to interrupt
+    let idle = status == Status::Disconnected;
+
+    let last = match config.recent.first() {
+        Some(recent) => item("connect_last", format!("Connect to {}", recent.name), idle),
+        None => item("connect_last", String::from("Connect to last location"), false)
+    };
+
+    let mut recent = config.recent.iter()
+        .enumerate()
+        .map(|(index, location)| item(&format!("recent_{}", index), location.name.clone(), idle))
+        .collect::<Vec<Entry>>();
+    if recent.is_empty() {
+        recent.push(item("recent_none", String::from("None yet"), false));
+    }
+
+    let mut favourite = favourites.iter()
+        .map(|(id, name)| item(&format!("favourite_{}", id), name.clone(), idle))
+        .collect::<Vec<Entry>>();
+    if favourite.is_empty() {
+        favourite.push(item("favourite_none", String::from("None yet"), false));
+    }
+
+    let other = match config.protocol {
+        Protocol::OpenVPN => Protocol::WireGuard,
+        Protocol::WireGuard => Protocol::OpenVPN
+    };
+
+    vec![
+        item("status", label, false),
+        Entry::Separator,
+        last,
+        Entry::Submenu { title: "Recent locations", items: recent },
+        Entry::Submenu { title: "Favourites", items: favourite },
+        item("disconnect", String::from("Disconnect"), !idle),
+        Entry::Separator,
+        item("protocol", format!("Switch to {}", other), idle),
+        Entry::Separator,
+        item("show", String::from("Show OctoVPN"), true),
+        item("quit", String::from("Quit"), true)
+    ]
+}
+
+fn to_menu(entries: Vec<Entry>) -> SystemTrayMenu {
+    entries.into_iter().fold(SystemTrayMenu::new(), |menu, entry| match entry {
+        Entry::Item { id, title, enabled } => {
+            let item = CustomMenuItem::new(id, title);
+            menu.add_item(if enabled { item } else { item.disabled() })
+        },
+        Entry::Submenu { title, items } => menu.add_submenu(SystemTraySubmenu::new(title, to_menu(items))),
+        Entry::Separator => menu.add_native_item(SystemTrayMenuItem::Separator)
+    })
+}
+
+fn menu(config: &Configuration, status: Status) -> SystemTrayMenu {
+    // Named from the cached locations, the window may not have loaded them yet
+    let favourites = config.favourites.iter()
+        .filter_map(|id| locations::name(*id).map(|name| (*id, name)))
+        .collect::<Vec<(i32, String)>>();
+
+    to_menu(entries(config, status, label(status), &favourites))
+}
+
+/// What a menu item does, parsed from its id.
+#[derive(Debug, PartialEq, Eq)]
+enum Action {
+    Show,
+    Quit,
+    Disconnect,
+    SwitchProtocol,
+    /// Connects to the recent location at that index, the last one being 0
+    Recent(usize),
+    Favourite(i32)
+}
+
+fn action(id: &str) -> Option<Action> {
+    match id {
+        "show" => Some(Action::Show),
+        "quit" => Some(Action::Quit),
+        "connect_last" => Some(Action::Recent(0)),
+        "disconnect" => Some(Action::Disconnect),
+        "protocol" => Some(Action::SwitchProtocol),
+        id => {
+            if let Some(index) = id.strip_prefix("recent_") {
+                index.parse().ok().map(Action::Recent)
+            } else {
+                id.strip_prefix("favourite_").and_then(|id| id.parse().ok()).map(Action::Favourite)
+            }
+        }
+    }
+}
+
+pub fn build(config: &Configuration) -> SystemTray {
+    let status = *STATUS.lock().unwrap();
+
+    SystemTray::new()
+        .with_icon(icon(status))
+        .with_menu(menu(config, status))
+        .with_tooltip(&format!("OctoVPN - {}", label(status)))
+}
+
+/// Redraws the tray for the current status and settings.
+pub fn refresh(app: &AppHandle) {
+    let status = *STATUS.lock().unwrap();
+    let menu = menu(&CONFIGURATION.lock().unwrap(), status);
+
+    let tray = app.tray_handle();
+    let _ = tray.set_icon(icon(status));
+    let _ = tray.set_tooltip(&format!("OctoVPN - {}", label(status)));
+    let _ = tray.set_menu(menu);
+}
+
+/// Moves the location to the top of the recent ones.
+pub fn remember(window: &Window, recent: Recent) {
+    let config = {
+        let mut configuration = CONFIGURATION.lock().unwrap();
+        configuration.recent.retain(|r| r.target != recent.target);
+        configuration.recent.insert(0, recent);
+        configuration.recent.truncate(MAX_RECENT);
+        write_config(&configuration);
+        configuration.clone()
+    };
+
+    // The window saves its own copy of the config, which would drop the change otherwise
+    EventSink::emit(window, ConfigChanged::new(&config));
+    refresh(&window.app_handle());
+}
+
+fn show(window: &Window) {
+    let _ = window.show();
+    let _ = window.unminimize();
+    let _ = window.set_focus();
+}
+
+fn connect(window: &Window, index: usize) {
+    let recent = CONFIGURATION.lock().unwrap().recent.get(index).cloned();
+
+    if let Some(recent) = recent {
+        log::info!("Connecting to {} from the tray", recent.name);
+        EventSink::emit(window, ConnectTo(recent.target));
+    }
+}
+
+fn switch_protocol(window: &Window) {
+    let config = {
+        let mut configuration = CONFIGURATION.lock().unwrap();
+        configuration.protocol = match configuration.protocol {
+            Protocol::OpenVPN => Protocol::WireGuard,
+            Protocol::WireGuard => Protocol::OpenVPN
+        };
+        write_config(&configuration);
+        configuration.clone()
+    };
+
+    log::info!("Switched to {} from the tray", config.protocol);
+    EventSink::emit(window, ConfigChanged::new(&config));
+    refresh(&window.app_handle());
+}
+
+pub fn handle(app: &AppHandle, event: SystemTrayEvent) {
+    let window = match app.get_window("main") {
+        Some(window) => window,
+        None => return
+    };
+
+    match event {
+        SystemTrayEvent::LeftClick { .. } => show(&window),
+        SystemTrayEvent::MenuItemClick { id, .. } => match action(&id) {
+            Some(Action::Show) => show(&window),
+            Some(Action::Quit) => app.exit(0),
+            Some(Action::Disconnect) => {
+                // Stopping the services takes a while, which would hold up the event loop
+                let protocol = CONFIGURATION.lock().unwrap().protocol;
+                let sink = app_sink(window);
+                std::thread::spawn(move || set_connection(false, protocol, None, None, sink));
+            },
+            Some(Action::SwitchProtocol) => switch_protocol(&window),
+            Some(Action::Recent(index)) => connect(&window, index),
+            Some(Action::Favourite(id)) => {
+                log::info!("Connecting to favourite {} from the tray", id);
+                EventSink::emit(&window, ConnectTo(Target::Location(id)));
+            },
+            None => {}
+        },
+        _ => {}
+    }
+}
+
+/// Follows the connection status, like the window does. The status itself is set by whoever reports it.
+#[derive(Clone)]
+pub struct Tray(pub AppHandle);
+
+impl EventSink for Tray {
+    fn emit<E: Event>(&self, event: E) {
+        if (&event as &dyn Any).is::<Status>() {
+            refresh(&self.0);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn recent(id: i32, name: &str) -> Recent {
+        Recent { target: Target::Location(id), name: name.to_string() }
+    }
+
+    fn find<'a>(entries: &'a [Entry], wanted: &str) -> Option<&'a Entry> {
+        entries.iter().find_map(|entry| match entry {
+            Entry::Item { id, .. } if id == wanted => Some(entry),
+            Entry::Submenu { items, .. } => find(items, wanted),
+            _ => None
+        })
+    }
+
+    fn enabled(entries: &[Entry], id: &str) -> bool {
+        matches!(find(entries, id), Some(Entry::Item { enabled: true, .. }))
+    }
+
+    #[test]
+    fn offers_connecting_only_while_disconnected() {
+        let config = Configuration { recent: vec![recent(1, "Amsterdam"), recent(2, "Zurich")], ..Configuration::default() };
+        let favourites = [(7, String::from("Tokyo"))];
+
+        let idle = entries(&config, Status::Disconnected, String::from("Disconnected"), &favourites);
+        assert_eq!(find(&idle, "connect_last"), Some(&item("connect_last", String::from("Connect to Amsterdam"), true)));
+        assert_eq!(find(&idle, "recent_1"), Some(&item("recent_1", String::from("Zurich"), true)));
+        assert_eq!(find(&idle, "favourite_7"), Some(&item("favourite_7", String::from("Tokyo"), true)));
+        assert!(!enabled(&idle, "disconnect"));
+
+        let connected = entries(&config, Status::Connected, String::from("Connected to Amsterdam"), &favourites);
+        assert!(["connect_last", "recent_0", "recent_1", "favourite_7", "protocol"].iter().all(|id| !enabled(&connected, id)));
+        assert!(enabled(&connected, "disconnect"));
+        assert_eq!(find(&connected, "status"), Some(&item("status", String::from("Connected to Amsterdam"), false)));
+    }
+
+    #[test]
+    fn shows_placeholders_without_locations() {
+        let menu = entries(&Configuration::default(), Status::Disconnected, String::from("Disconnected"), &[]);
+
+        assert!(!enabled(&menu, "connect_last"));
+        assert!(find(&menu, "recent_none").is_some() && find(&menu, "favourite_none").is_some());
+        assert!(find(&menu, "recent_0").is_none());
+    }
+
+    #[test]
+    fn offers_the_other_protocol() {
+        let config = Configuration { protocol: Protocol::WireGuard, ..Configuration::default() };
+        let menu = entries(&config, Status::Disconnected, String::new(), &[]);
+
+        assert_eq!(find(&menu, "protocol"), Some(&item("protocol", String::from("Switch to OpenVPN"), true)));
+    }
+
+    #[test]
+    fn parses_item_ids() {
+        assert_eq!(action("connect_last"), Some(Action::Recent(0)));
+        assert_eq!(action("recent_3"), Some(Action::Recent(3)));
+        assert_eq!(action("favourite_42"), Some(Action::Favourite(42)));
+        assert_eq!(action("disconnect"), Some(Action::Disconnect));
+
+        // Placeholders and anything malformed do nothing
+        assert_eq!(action("recent_none"), None);
+        assert_eq!(action("favourite_none"), None);
+        assert_eq!(action("recent_-1"), None);
+        assert_eq!(action("status"), None);
+    }
+}
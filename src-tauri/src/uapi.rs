@@ -0,0 +1,289 @@
+use std::{io::{BufRead, BufReader, Read, Write}, net::SocketAddr};
+
+use base64::{Engine, engine::general_purpose::STANDARD};
+
+use crate::{multihop, wireguard::PeerStats};
+
+// Client for the cross-platform WireGuard UAPI (https://www.wireguard.com/xplatform/),
+// spoken by wireguard-go, the Windows tunnel service and other userspace implementations.
+
+/// Name of the tunnel, taken from the config file name.
+const TUNNEL: &str = "wireguard";
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum UapiError {
+    NotRunning,
+    InvalidResponse,
+    Errno(i32),
+}
+
+/// Converts the UAPI's hex keys to the usual base64.
+fn hex_to_base64(hex: &str) -> Option<String> {
+    if hex.len() % 2 != 0 {
+        return None;
+    }
+
+    let bytes = (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+        .collect::<Option<Vec<u8>>>()?;
+
+    Some(STANDARD.encode(bytes))
+}
+
+/// Converts a key to the UAPI's hex.
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Parses the response to `get=1`, ignoring the interface-level keys.
+pub fn parse(response: &str) -> Result<Vec<PeerStats>, UapiError> {
+    let mut peers: Vec<PeerStats> = vec![];
+
+    for line in response.lines() {
+        if line.is_empty() {
+            break;
+        }
+
+        let (key, value) = line.split_once('=').ok_or(UapiError::InvalidResponse)?;
+
+        if key == "public_key" {
+            peers.push(PeerStats {
+                public_key: hex_to_base64(value).ok_or(UapiError::InvalidResponse)?,
+                endpoint: None,
+                last_handshake: None,
+                rx_bytes: 0,
+                tx_bytes: 0
+            });
+            continue;
+        }
+
+        if key == "errno" {
+            let errno = value.parse::<i32>().map_err(|_| UapiError::InvalidResponse)?;
+            if errno != 0 {
+                return Err(UapiError::Errno(errno));
+            }
+            continue;
+        }
+
+        // Everything before the first peer describes the interface
+        let peer = match peers.last_mut() {
+            Some(peer) => peer,
+            None => continue
+        };
+
+        let number = || value.parse::<u64>().map_err(|_| UapiError::InvalidResponse);
+        match key {
+            "endpoint" => peer.endpoint = Some(value.to_string()),
+            // Zero means no handshake happened yet
+            "last_handshake_time_sec" => peer.last_handshake = Some(number()?).filter(|time| *time != 0),
+            "rx_bytes" => peer.rx_bytes = number()?,
+            "tx_bytes" => peer.tx_bytes = number()?,
+            _ => {}
+        }
+    }
+
+    Ok(peers)
+}
+
+fn query<S: Read + Write>(mut stream: S, request: &str) -> Result<Vec<PeerStats>, UapiError> {
+    stream.write_all(request.as_bytes()).map_err(|_| UapiError::NotRunning)?;
+
+    // The connection stays open, the response ends with an empty line
+    let mut response = String::new();
+    let mut reader = BufReader::new(stream);
+    loop {
+        let mut line = String::new();
+        match reader.read_line(&mut line) {
+            Ok(0) => break,
+            Ok(_) if line == "\n" => break,
+            Ok(_) => response.push_str(&line),
+            Err(_) => return Err(UapiError::InvalidResponse)
+        }
+    }
+
+    parse(&response)
+}
+
+#[cfg(unix)]
+fn connect(tunnel: &str, request: &str) -> Result<Vec<PeerStats>, UapiError> {
+    use std::os::unix::net::UnixStream;
+
+    let stream = UnixStream::connect(format!("/var/run/wireguard/{}.sock", tunnel))
+        .map_err(|_| UapiError::NotRunning)?;
+    let _ = stream.set_read_timeout(Some(std::time::Duration::from_secs(1)));
+
+    query(stream, request)
+}
+
+#[cfg(windows)]
+fn connect(tunnel: &str, request: &str) -> Result<Vec<PeerStats>, UapiError> {
+    let pipe = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(format!(r"\\.\pipe\ProtectedPrefix\Administrators\WireGuard\{}", tunnel))
+        .map_err(|_| UapiError::NotRunning)?;
+
+    query(pipe, request)
+}
+
+/// The kernel module has no UAPI socket, `wg` reads it over netlink instead.
+#[cfg(target_os = "linux")]
+fn kernel(tunnel: &str) -> Result<Vec<PeerStats>, UapiError> {
+    let output = std::process::Command::new("wg")
+        .args(["show", tunnel, "dump"])
+        .output()
+        .map_err(|_| UapiError::NotRunning)?;
+
+    if !output.status.success() {
+        return Err(UapiError::NotRunning);
+    }
+
+    // The first line is the interface, then one line per peer:
+    // public-key, preshared-key, endpoint, allowed-ips, latest-handshake, rx, tx, keepalive
+    String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .skip(1)
+        .map(|line| {
+            let fields = line.split('\t').collect::<Vec<&str>>();
+            if fields.len() < 7 {
+                return Err(UapiError::InvalidResponse);
+            }
+
+            let number = |i: usize| fields[i].parse::<u64>().map_err(|_| UapiError::InvalidResponse);
+            Ok(PeerStats {
+                public_key: fields[0].to_string(),
+                endpoint: Some(fields[2].to_string()).filter(|endpoint| endpoint != "(none)"),
+                last_handshake: Some(number(4)?).filter(|time| *time != 0),
+                rx_bytes: number(5)?,
+                tx_bytes: number(6)?
+            })
+        })
+        .collect()
+}
+
+#[cfg(target_os = "linux")]
+fn kernel_set_endpoint(public_key: &[u8; 32], endpoint: SocketAddr) -> bool {
+    std::process::Command::new("wg")
+        .args(["set", TUNNEL, "peer", &STANDARD.encode(public_key), "endpoint", &endpoint.to_string()])
+        .status()
+        .is_ok_and(|status| status.success())
+}
+
+/// Gets the status of every peer of the running tunnel.
+pub fn get() -> Result<Vec<PeerStats>, UapiError> {
+    // Running in-process, no need to go through a socket
+    #[cfg(all(feature = "userspace-wireguard", unix))]
+    if crate::userspace::is_running() {
+        return crate::userspace::stats().map(|stats| vec![stats]).ok_or(UapiError::NotRunning);
+    }
+
+    #[cfg(target_os = "linux")]
+    if let Ok(peers) = kernel(TUNNEL) {
+        return Ok(peers);
+    }
+
+    connect(TUNNEL, "get=1\n\n")
+}
+
+/// Gets the status of the multi-hop entry's tunnel, which always runs as a service.
+pub fn get_hop() -> Result<Vec<PeerStats>, UapiError> {
+    #[cfg(target_os = "linux")]
+    if let Ok(peers) = kernel(multihop::TUNNEL) {
+        return Ok(peers);
+    }
+
+    connect(multihop::TUNNEL, "get=1\n\n")
+}
+
+/// Points a peer of the running tunnel to `endpoint`. Setting it, even to the same
+/// address, also drops the source address the tunnel was sending from.
+pub fn set_endpoint(public_key: &[u8; 32], endpoint: SocketAddr) -> Result<(), UapiError> {
+    // The in-process tunnel's socket stays connected to the endpoint it started with
+    #[cfg(all(feature = "userspace-wireguard", unix))]
+    if crate::userspace::is_running() {
+        log::info!("Userspace WireGuard keeps its endpoint, not moving it to {}", endpoint);
+        return Ok(());
+    }
+
+    #[cfg(target_os = "linux")]
+    if kernel_set_endpoint(public_key, endpoint) {
+        return Ok(());
+    }
+
+    connect(TUNNEL, &format!("set=1\npublic_key={}\nupdate_only=true\nendpoint={}\n\n", to_hex(public_key), endpoint)).map(|_| ())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Recorded from wireguard-go: the interface, a peer that's connected, and one that never answered.
+    const RESPONSE: &str = "private_key=e84b5a6d2717c1003a13b431570353dbaca9146cf150c5f8575680feba52027a\n\
+        listen_port=51820\n\
+        fwmark=0\n\
+        public_key=b85996fecc9c7f1fc6d2572a76eda11d59bcd20be8e543b15ce4bd85a8e75a33\n\
+        preshared_key=0000000000000000000000000000000000000000000000000000000000000000\n\
+        protocol_version=1\n\
+        endpoint=198.51.100.7:51820\n\
+        last_handshake_time_sec=1700000000\n\
+        last_handshake_time_nsec=42\n\
+        tx_bytes=38333\n\
+        rx_bytes=2224\n\
+        persistent_keepalive_interval=25\n\
+        allowed_ip=0.0.0.0/0\n\
+        public_key=58402e695ba1772b1cc9309755f043251ea77fdcf10fbe63989ceb7e19321376\n\
+        protocol_version=1\n\
+        endpoint=[2001:db8::7]:51820\n\
+        last_handshake_time_sec=0\n\
+        last_handshake_time_nsec=0\n\
+        tx_bytes=148\n\
+        rx_bytes=0\n\
+        persistent_keepalive_interval=0\n\
+        allowed_ip=10.0.0.0/8\n\
+        errno=0\n\
+        \n";
+
+    #[test]
+    fn parses_every_peer() {
+        let peers = parse(RESPONSE).unwrap();
+
+        assert_eq!(peers, vec![
+            PeerStats {
+                public_key: String::from("uFmW/sycfx/G0lcqdu2hHVm80gvo5UOxXOS9hajnWjM="),
+                endpoint: Some(String::from("198.51.100.7:51820")),
+                last_handshake: Some(1700000000),
+                rx_bytes: 2224,
+                tx_bytes: 38333
+            },
+            PeerStats {
+                public_key: String::from("WEAuaVuhdyscyTCXVfBDJR6nf9zxD75jmJzrfhkyE3Y="),
+                endpoint: Some(String::from("[2001:db8::7]:51820")),
+                // Zero, it never handshaked
+                last_handshake: None,
+                rx_bytes: 0,
+                tx_bytes: 148
+            }
+        ]);
+    }
+
+    #[test]
+    fn parses_an_interface_without_peers() {
+        assert_eq!(parse("private_key=e84b5a6d2717c1003a13b431570353dbaca9146cf150c5f8575680feba52027a\nlisten_port=51820\nerrno=0\n\n"), Ok(vec![]));
+    }
+
+    #[test]
+    fn reports_errors() {
+        assert_eq!(parse("errno=2\n\n"), Err(UapiError::Errno(2)));
+        assert_eq!(parse("public_key=zz\n"), Err(UapiError::InvalidResponse));
+        assert_eq!(parse(&RESPONSE.replace("rx_bytes=2224", "rx_bytes=lots")), Err(UapiError::InvalidResponse));
+        assert_eq!(parse("garbage\n"), Err(UapiError::InvalidResponse));
+    }
+
+    #[test]
+    fn converts_keys_between_hex_and_base64() {
+        let key = [7u8; 32];
+        assert_eq!(hex_to_base64(&to_hex(&key)), Some(STANDARD.encode(key)));
+        assert_eq!(hex_to_base64("abc"), None);
+    }
+}
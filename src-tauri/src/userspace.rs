@@ -0,0 +1,314 @@
+use std::{fs::File, io::{Read, Write}, net::{SocketAddr, ToSocketAddrs, UdpSocket}, os::fd::{AsRawFd, FromRawFd, RawFd}, process::Command, sync::{Arc, Mutex, atomic::{AtomicBool, Ordering}}, thread, time::{Duration, SystemTime, UNIX_EPOCH}};
+
+use base64::{Engine, engine::general_purpose::STANDARD};
+use boringtun::{noise::{Tunn, TunnResult}, x25519::{StaticSecret, PublicKey}};
+use lazy_static::lazy_static;
+
+use crate::wireguard::{WireGuardConfig, PeerStats};
+
+// In-process WireGuard, for when neither the kernel module nor `wireguard.exe` is available.
+// Only the first peer is used, which is all the API configs ever have.
+
+const INTERFACE: &str = "octovpn0";
+const BUFFER_SIZE: usize = 65535;
+const TICK: Duration = Duration::from_millis(250);
+
+lazy_static! {
+    static ref TUNNEL: Mutex<Option<Tunnel>> = Mutex::new(None);
+}
+
+#[derive(Debug)]
+pub enum UserspaceError {
+    InvalidConfig,
+    DeviceFailed,
+    SocketFailed,
+    RoutesFailed,
+}
+
+struct Tunnel {
+    running: Arc<AtomicBool>,
+    tunn: Arc<Mutex<Tunn>>,
+    public_key: String,
+    endpoint: SocketAddr,
+    threads: Vec<thread::JoinHandle<()>>,
+    /// Host route keeping the endpoint reachable outside of the tunnel
+    bypass: Option<Vec<String>>,
+    _device: tun::platform::Device
+}
+
+fn ip(args: &[&str]) -> bool {
+    Command::new("ip")
+        .args(args)
+        .status()
+        .map(|status| status.success())
+        .unwrap_or(false)
+}
+
+/// Finds how the endpoint is currently reached, i.e. `via 192.168.1.1 dev eth0`.
+fn current_route(endpoint: &SocketAddr) -> Option<Vec<String>> {
+    let output = Command::new("ip")
+        .args(["route", "get", &endpoint.ip().to_string()])
+        .output()
+        .ok()?;
+    let output = String::from_utf8_lossy(&output.stdout);
+
+    let words = output.split_whitespace().collect::<Vec<&str>>();
+    let mut route = vec![];
+    for key in ["via", "dev"] {
+        if let Some(position) = words.iter().position(|word| *word == key) {
+            route.push(key.to_string());
+            route.push(words.get(position + 1)?.to_string());
+        }
+    }
+
+    Some(route)
+}
+
+/// Waits until the fd is readable, so the loop can notice being stopped.
+fn poll_readable(fd: RawFd) -> bool {
+    let mut pollfd = libc::pollfd { fd, events: libc::POLLIN, revents: 0 };
+    unsafe { libc::poll(&mut pollfd, 1, TICK.as_millis() as i32) > 0 }
+}
+
+fn duplicate(fd: RawFd) -> Result<File, UserspaceError> {
+    let duplicate = unsafe { libc::dup(fd) };
+    if duplicate < 0 {
+        return Err(UserspaceError::DeviceFailed);
+    }
+
+    Ok(unsafe { File::from_raw_fd(duplicate) })
+}
+
+/// Creates the TUN device, assigns its addresses and routes its allowed IPs through it.
+pub fn start(config: &WireGuardConfig) -> Result<(), UserspaceError> {
+    stop();
+
+    let peer = config.peers.first().ok_or(UserspaceError::InvalidConfig)?;
+    let endpoint = peer.endpoint.as_ref()
+        .and_then(|endpoint| endpoint.to_socket_addrs().ok())
+        .and_then(|mut addresses| addresses.next())
+        .ok_or(UserspaceError::InvalidConfig)?;
+
+    let tunn = Tunn::new(
+        StaticSecret::from(*config.interface.private_key.expose()),
+        PublicKey::from(peer.public_key),
+        peer.preshared_key.as_ref().map(|key| *key.expose()),
+        peer.persistent_keepalive,
+        0,
+        None
+    ).map_err(|e| {
+        log::error!("Failed to create WireGuard tunnel: {}", e);
+        UserspaceError::InvalidConfig
+    })?;
+    let tunn = Arc::new(Mutex::new(tunn));
+
+    let mut tun_config = tun::Configuration::default();
+    tun_config
+        .name(INTERFACE)
+        .mtu(config.interface.mtu.unwrap_or(1420) as i32)
+        .up();
+    #[cfg(target_os = "linux")]
+    tun_config.platform(|platform| {
+        platform.packet_information(false);
+    });
+
+    let device = tun::create(&tun_config).map_err(|e| {
+        log::error!("Failed to create TUN device: {}", e);
+        UserspaceError::DeviceFailed
+    })?;
+
+    let fd = device.as_raw_fd();
+    let mut reader = duplicate(fd)?;
+    let mut writer = duplicate(fd)?;
+
+    let socket = UdpSocket::bind(if endpoint.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" })
+        .and_then(|socket| socket.connect(endpoint).map(|_| socket))
+        .and_then(|socket| socket.set_read_timeout(Some(TICK)).map(|_| socket))
+        .map_err(|_| UserspaceError::SocketFailed)?;
+
+    for address in &config.interface.addresses {
+        if !ip(&["address", "add", address, "dev", INTERFACE]) {
+            return Err(UserspaceError::RoutesFailed);
+        }
+    }
+
+    // Full tunnels need the endpoint itself to keep going through the real gateway
+    let bypass = current_route(&endpoint);
+    if let Some(route) = &bypass {
+        let host = endpoint.ip().to_string();
+        let mut args = vec!["route", "add", host.as_str()];
+        args.extend(route.iter().map(|arg| arg.as_str()));
+        ip(&args);
+    }
+
+    for allowed in &peer.allowed_ips {
+        let routes = match allowed.as_str() {
+            // Split in two, so they take precedence over the default route without replacing it
+            "0.0.0.0/0" => vec!["0.0.0.0/1", "128.0.0.0/1"],
+            "::/0" => vec!["::/1", "8000::/1"],
+            allowed => vec![allowed]
+        };
+
+        for route in routes {
+            if !ip(&["route", "add", route, "dev", INTERFACE]) {
+                log::warn!("Failed to add route {} through {}", route, INTERFACE);
+            }
+        }
+    }
+
+    let running = Arc::new(AtomicBool::new(true));
+    let mut threads = vec![];
+
+    // Outgoing: TUN -> encrypt -> endpoint
+    {
+        let running = running.clone();
+        let tunn = tunn.clone();
+        let socket = socket.try_clone().map_err(|_| UserspaceError::SocketFailed)?;
+
+        threads.push(thread::spawn(move || {
+            let mut packet = vec![0u8; BUFFER_SIZE];
+            let mut out = vec![0u8; BUFFER_SIZE];
+
+            while running.load(Ordering::Relaxed) {
+                if !poll_readable(reader.as_raw_fd()) {
+                    continue;
+                }
+
+                let n = match reader.read(&mut packet) {
+                    Ok(n) => n,
+                    Err(_) => break
+                };
+
+                match tunn.lock().unwrap().encapsulate(&packet[..n], &mut out) {
+                    TunnResult::WriteToNetwork(data) => { let _ = socket.send(data); },
+                    TunnResult::Err(e) => log::debug!("Failed to encapsulate packet: {:?}", e),
+                    _ => {}
+                }
+            }
+        }));
+    }
+
+    // Incoming: endpoint -> decrypt -> TUN
+    {
+        let running = running.clone();
+        let tunn = tunn.clone();
+        let socket = socket.try_clone().map_err(|_| UserspaceError::SocketFailed)?;
+
+        threads.push(thread::spawn(move || {
+            let mut datagram = vec![0u8; BUFFER_SIZE];
+            let mut out = vec![0u8; BUFFER_SIZE];
+
+            while running.load(Ordering::Relaxed) {
+                let n = match socket.recv(&mut datagram) {
+                    Ok(n) => n,
+                    // Timed out, check if we're still running
+                    Err(_) => continue
+                };
+
+                let mut tunn = tunn.lock().unwrap();
+                let mut flush = false;
+
+                match tunn.decapsulate(Some(endpoint.ip()), &datagram[..n], &mut out) {
+                    TunnResult::WriteToNetwork(data) => {
+                        let _ = socket.send(data);
+                        flush = true;
+                    },
+                    TunnResult::WriteToTunnelV4(data, _) | TunnResult::WriteToTunnelV6(data, _) => {
+                        let _ = writer.write_all(data);
+                    },
+                    TunnResult::Err(e) => log::debug!("Failed to decapsulate packet: {:?}", e),
+                    TunnResult::Done => {}
+                }
+
+                // Packets queued during a handshake come out one by one
+                while flush {
+                    match tunn.decapsulate(None, &[], &mut out) {
+                        TunnResult::WriteToNetwork(data) => { let _ = socket.send(data); },
+                        _ => flush = false
+                    }
+                }
+            }
+        }));
+    }
+
+    // Handshakes, keepalives and rekeying
+    {
+        let running = running.clone();
+        let tunn = tunn.clone();
+        let socket = socket.try_clone().map_err(|_| UserspaceError::SocketFailed)?;
+
+        threads.push(thread::spawn(move || {
+            let mut out = vec![0u8; BUFFER_SIZE];
+
+            if let TunnResult::WriteToNetwork(data) = tunn.lock().unwrap().format_handshake_initiation(&mut out, false) {
+                let _ = socket.send(data);
+            }
+
+            while running.load(Ordering::Relaxed) {
+                match tunn.lock().unwrap().update_timers(&mut out) {
+                    TunnResult::WriteToNetwork(data) => { let _ = socket.send(data); },
+                    TunnResult::Err(e) => log::debug!("WireGuard timer error: {:?}", e),
+                    _ => {}
+                }
+
+                thread::sleep(TICK);
+            }
+        }));
+    }
+
+    log::info!("Userspace WireGuard is up on {} ({})", INTERFACE, endpoint);
+
+    *TUNNEL.lock().unwrap() = Some(Tunnel {
+        running,
+        tunn,
+        public_key: STANDARD.encode(peer.public_key),
+        endpoint,
+        threads,
+        bypass,
+        _device: device
+    });
+
+    Ok(())
+}
+
+/// Stops the tunnel, the TUN device and its routes go away with it.
+pub fn stop() -> bool {
+    let tunnel = TUNNEL.lock().unwrap().take();
+
+    match tunnel {
+        Some(tunnel) => {
+            tunnel.running.store(false, Ordering::Relaxed);
+            for thread in tunnel.threads {
+                let _ = thread.join();
+            }
+
+            if tunnel.bypass.is_some() {
+                ip(&["route", "del", &tunnel.endpoint.ip().to_string()]);
+            }
+
+            log::info!("Userspace WireGuard is down");
+            true
+        },
+        None => false
+    }
+}
+
+pub fn is_running() -> bool {
+    TUNNEL.lock().unwrap().is_some()
+}
+
+pub fn stats() -> Option<PeerStats> {
+    let tunnel = TUNNEL.lock().unwrap();
+    let tunnel = tunnel.as_ref()?;
+
+    let (since_handshake, tx_bytes, rx_bytes, _, _) = tunnel.tunn.lock().unwrap().stats();
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+
+    Some(PeerStats {
+        public_key: tunnel.public_key.clone(),
+        endpoint: Some(tunnel.endpoint.to_string()),
+        last_handshake: since_handshake.map(|since| now.saturating_sub(since).as_secs()),
+        rx_bytes: rx_bytes as u64,
+        tx_bytes: tx_bytes as u64
+    })
+}
@@ -0,0 +1,274 @@
+use std::{any::Any, net::IpAddr, sync::Mutex, thread, time::Duration};
+
+use lazy_static::lazy_static;
+use serde::{Serialize, Deserialize};
+use serde_json::Value;
+
+use crate::{CONFIGURATION, DESTINATION, STATUS, Destination, Status, events::{Event, EventSink}, notifications};
+
+// Checks where traffic leaves once connected, if turned on. The server's address only
+// says where the tunnel goes, asking a "what is my IP" endpoint through the tunnel
+// says whether traffic actually follows it. The endpoint is only asked without the
+// tunnel if comparing with the home address is turned on too.
+
+/// Lets the routes settle first, OpenVPN reports connected before they are all set.
+const DELAY: Duration = Duration::from_secs(5);
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+lazy_static! {
+    /// Public IP without the tunnel, learned while disconnected
+    static ref HOME_IP: Mutex<Option<String>> = Mutex::new(None);
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct VerifyConfig {
+    pub enabled: bool,
+    /// Answers with the caller's IP, as JSON with an `ip` and a country code, or as plain text
+    pub endpoint: String,
+    /// Also asks while disconnected, to flag traffic still leaving from that address
+    #[serde(rename = "compareHome")]
+    pub compare_home: bool
+}
+
+impl Default for VerifyConfig {
+    fn default() -> Self {
+        VerifyConfig {
+            enabled: false,
+            endpoint: String::from("https://ipinfo.io/json"),
+            compare_home: false
+        }
+    }
+}
+
+/// Where a request came from, as the endpoint saw it.
+#[derive(Debug, PartialEq, Eq, Clone, Serialize)]
+pub struct Exit {
+    pub ip: String,
+    /// Upper case ISO code, if the endpoint tells
+    pub country: Option<String>
+}
+
+/// The outcome of a check.
+#[derive(Debug, PartialEq, Eq, Clone, Serialize)]
+pub struct VerifiedExit {
+    /// `None` if the endpoint couldn't be asked
+    pub exit: Option<Exit>,
+    pub expected_ip: Option<String>,
+    pub expected_country: Option<String>,
+    /// Traffic leaves from the server itself. Many servers exit from another address, so this alone proves nothing
+    pub ip_matches: bool,
+    /// `None` when either country is unknown
+    pub country_matches: Option<bool>,
+    /// Traffic may not go through the tunnel
+    pub leak: bool,
+    pub error: Option<String>
+}
+
+/// Reads the endpoint's answer, i.e. ipinfo.io, ipapi.co, ifconfig.co, ip-api.com or a bare IP.
+pub fn parse(body: &str) -> Option<Exit> {
+    let body = body.trim();
+
+    if let Ok(ip) = body.parse::<IpAddr>() {
+        return Some(Exit { ip: ip.to_string(), country: None });
+    }
+
+    let json: Value = serde_json::from_str(body).ok()?;
+    let ip = ["ip", "query"].iter()
+        .find_map(|key| json[key].as_str())?
+        .parse::<IpAddr>()
+        .ok()?;
+
+    // Some put the country's name under `country`, only codes are compared
+    let country = ["country_code", "countryCode", "country_iso", "country"].iter()
+        .filter_map(|key| json[key].as_str())
+        .find(|country| country.len() == 2)
+        .map(|country| country.to_uppercase());
+
+    Some(Exit { ip: ip.to_string(), country })
+}
+
+/// Asks the endpoint where the request comes from.
+pub fn query(endpoint: &str) -> Result<Exit, String> {
+    let body = reqwest::blocking::Client::builder()
+        .timeout(TIMEOUT)
+        .build()
+        .and_then(|client| client.get(endpoint).header("Accept", "application/json").send())
+        .and_then(|response| response.error_for_status())
+        .and_then(|response| response.text())
+        .map_err(|e| e.to_string())?;
+
+    parse(&body).ok_or_else(|| format!("Unexpected answer from {}", endpoint))
+}
+
+/// Compares the exit with where the tunnel should go, and with the address it had without the tunnel.
+pub fn compare(exit: Result<Exit, String>, destination: Option<&Destination>, home: Option<&str>) -> VerifiedExit {
+    let expected_ip = destination.and_then(|destination| destination.ip.clone());
+    let expected_country = destination.and_then(|destination| destination.country_code.as_ref().map(|code| code.to_uppercase()));
+
+    let exit = match exit {
+        Ok(exit) => exit,
+        Err(error) => return VerifiedExit {
+            exit: None,
+            expected_ip,
+            expected_country,
+            ip_matches: false,
+            country_matches: None,
+            leak: false,
+            error: Some(error)
+        }
+    };
+
+    let ip_matches = expected_ip.as_ref() == Some(&exit.ip);
+    let country_matches = match (&exit.country, &expected_country) {
+        (Some(country), Some(expected)) => Some(country == expected),
+        _ => None
+    };
+
+    // Still leaving from home, or from another country than the server's
+    let leak = !ip_matches && (home == Some(exit.ip.as_str()) || country_matches == Some(false));
+
+    VerifiedExit { exit: Some(exit), expected_ip, expected_country, ip_matches, country_matches, leak, error: None }
+}
+
+/// Checks the exit of the current connection.
+pub fn verify(config: &VerifyConfig) -> VerifiedExit {
+    let destination = DESTINATION.lock().unwrap().clone();
+    let home = HOME_IP.lock().unwrap().clone().filter(|_| config.compare_home);
+
+    let verified = compare(query(&config.endpoint), destination.as_ref(), home.as_deref());
+
+    match (&verified.exit, &verified.error) {
+        (Some(exit), _) if verified.leak => {
+            log::warn!("Traffic leaves from {} ({:?}), expected {:?} ({:?})", exit.ip, exit.country, verified.expected_ip, verified.expected_country);
+            notifications::failure("Possible leak", &format!("Traffic leaves from {}, not through the VPN.", exit.ip));
+        },
+        (Some(exit), _) => log::info!("Traffic leaves from {} ({:?})", exit.ip, exit.country),
+        (None, error) => log::warn!("Failed to verify the exit: {:?}", error)
+    }
+
+    verified
+}
+
+/// Learns the address without the tunnel, to recognize it if traffic leaks later on.
+/// Only when asked to, the endpoint otherwise never sees it.
+pub fn learn_home() {
+    let config = CONFIGURATION.lock().unwrap().verification.clone();
+    if !config.enabled || !config.compare_home {
+        return;
+    }
+
+    match query(&config.endpoint) {
+        // Connected again meanwhile, that's not home
+        Ok(exit) if *STATUS.lock().unwrap() == Status::Disconnected => *HOME_IP.lock().unwrap() = Some(exit.ip),
+        Ok(_) => {},
+        Err(e) => log::debug!("Failed to learn the public IP: {}", e)
+    }
+}
+
+/// Verifies the exit each time the connection comes up, sending the result to the inner sink.
+#[derive(Clone)]
+pub struct Verifier<E>(pub E);
+
+impl<E: EventSink> EventSink for Verifier<E> {
+    fn emit<V: Event>(&self, event: V) {
+        let status = match (&event as &dyn Any).downcast_ref::<Status>() {
+            Some(status) => *status,
+            None => return
+        };
+
+        let config = CONFIGURATION.lock().unwrap().verification.clone();
+        if !config.enabled {
+            return;
+        }
+
+        match status {
+            Status::Connected => {
+                let sink = self.0.clone();
+                thread::spawn(move || {
+                    thread::sleep(DELAY);
+
+                    // Dropped meanwhile, there is nothing to verify
+                    if *STATUS.lock().unwrap() == Status::Connected {
+                        sink.emit(verify(&config));
+                    }
+                });
+            },
+            Status::Disconnected => {
+                thread::spawn(learn_home);
+            },
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_http::{response, serve};
+
+    fn destination() -> Destination {
+        Destination {
+            name: String::from("Amsterdam #1"),
+            city: Some(String::from("Amsterdam")),
+            country: String::from("Netherlands"),
+            ip: Some(String::from("198.51.100.7")),
+            country_code: Some(String::from("nl"))
+        }
+    }
+
+    fn exit(ip: &str, country: Option<&str>) -> Result<Exit, String> {
+        Ok(Exit { ip: ip.to_string(), country: country.map(String::from) })
+    }
+
+    /// Answers every request with `body`, like a "what is my IP" endpoint would.
+    fn serve_ip(body: &'static str) -> String {
+        serve("/json", move |_| response("200 OK", &[], body))
+    }
+
+    #[test]
+    fn parses_known_endpoints() {
+        let ipinfo = r#"{ "ip": "203.0.113.9", "city": "Amsterdam", "country": "NL" }"#;
+        assert_eq!(parse(ipinfo), Some(Exit { ip: String::from("203.0.113.9"), country: Some(String::from("NL")) }));
+
+        let ifconfig = r#"{ "ip": "203.0.113.9", "country": "Netherlands", "country_iso": "NL" }"#;
+        assert_eq!(parse(ifconfig).unwrap().country, Some(String::from("NL")));
+
+        let ip_api = r#"{ "status": "success", "query": "2001:db8::1", "countryCode": "nl" }"#;
+        assert_eq!(parse(ip_api), Some(Exit { ip: String::from("2001:db8::1"), country: Some(String::from("NL")) }));
+
+        assert_eq!(parse("203.0.113.9\n"), Some(Exit { ip: String::from("203.0.113.9"), country: None }));
+        assert_eq!(parse("<html>rate limited</html>"), None);
+    }
+
+    #[test]
+    fn flags_exits_that_may_leak() {
+        let destination = destination();
+
+        // Leaving from another address in the same country is normal
+        let verified = compare(exit("203.0.113.9", Some("NL")), Some(&destination), Some("192.0.2.1"));
+        assert_eq!((verified.ip_matches, verified.country_matches, verified.leak), (false, Some(true), false));
+
+        let verified = compare(exit("198.51.100.7", None), Some(&destination), None);
+        assert_eq!((verified.ip_matches, verified.leak), (true, false));
+
+        // Still the address from before connecting
+        assert!(compare(exit("192.0.2.1", None), Some(&destination), Some("192.0.2.1")).leak);
+
+        let verified = compare(exit("192.0.2.50", Some("DE")), Some(&destination), None);
+        assert_eq!((verified.country_matches, verified.leak), (Some(false), true));
+
+        // Not knowing isn't a leak
+        let verified = compare(Err(String::from("timed out")), Some(&destination), Some("192.0.2.1"));
+        assert_eq!((verified.exit, verified.leak, verified.error), (None, false, Some(String::from("timed out"))));
+    }
+
+    #[test]
+    fn queries_the_endpoint() {
+        let endpoint = serve_ip(r#"{ "ip": "203.0.113.9", "country": "NL" }"#);
+        assert_eq!(query(&endpoint), Ok(Exit { ip: String::from("203.0.113.9"), country: Some(String::from("NL")) }));
+
+        let endpoint = serve_ip("Too many requests");
+        assert!(query(&endpoint).is_err());
+    }
+}
@@ -1,85 +1,131 @@
-use std::path::PathBuf;
+use std::time::{Duration, Instant};
 
-use lazy_static::lazy_static;
-use windows::{Win32::{System::{Services::{OpenSCManagerW, SC_MANAGER_ALL_ACCESS, OpenServiceW, SERVICE_ALL_ACCESS, CreateServiceW, SERVICE_WIN32_OWN_PROCESS, SERVICE_DEMAND_START, SERVICE_ERROR_NORMAL, StartServiceW, ControlService, SERVICE_CONTROL_STOP, SERVICE_STOPPED, ChangeServiceConfig2W, SERVICE_CONFIG_SERVICE_SID_INFO, SERVICE_SID_TYPE_UNRESTRICTED, SERVICE_SID_INFO, QueryServiceStatus, SERVICE_STATUS, SERVICE_RUNNING, SERVICE_START_PENDING, SERVICE_STOP_PENDING, ChangeServiceConfigW, SERVICE_NO_CHANGE}, Registry::{RegCreateKeyW, HKEY_LOCAL_MACHINE, HKEY, REG_SZ, RegOpenKeyExW, KEY_WRITE, RRF_RT_REG_SZ, RegGetValueW, RegSetKeyValueW}}, Security::SC_HANDLE}, core::{w, PWSTR, HSTRING}};
+use windows::{Win32::{System::{Services::{OpenSCManagerW, SC_MANAGER_ALL_ACCESS, OpenServiceW, SERVICE_ALL_ACCESS, CreateServiceW, SERVICE_WIN32_OWN_PROCESS, SERVICE_DEMAND_START, SERVICE_AUTO_START, SERVICE_START_TYPE, SERVICE_ERROR_NORMAL, StartServiceW, ControlService, SERVICE_CONTROL_STOP, SERVICE_STOPPED, ChangeServiceConfig2W, SERVICE_CONFIG_SERVICE_SID_INFO, SERVICE_SID_TYPE_UNRESTRICTED, SERVICE_SID_INFO, QueryServiceStatus, SERVICE_STATUS, SERVICE_RUNNING, SERVICE_START_PENDING, SERVICE_STOP_PENDING, ChangeServiceConfigW, SERVICE_NO_CHANGE, DeleteService, CloseServiceHandle}, Registry::{RegCreateKeyW, HKEY_LOCAL_MACHINE, HKEY, REG_SZ, RegOpenKeyExW, KEY_WRITE, RRF_RT_REG_SZ, RegGetValueW, RegSetKeyValueW, RegDeleteTreeW, RegDeleteKeyW}}, Security::SC_HANDLE}, core::{w, PWSTR, HSTRING}};
 use reqwest::blocking::Client;
 
-use crate::{CONNECTED, Protocol, Credentials, Configuration};
+use crate::{CONFIGURATION, CONNECTED, Protocol, PreflightError, ServiceReport, config::HOME, multihop};
 
+/// Checks the service for the given protocol is installed, without changing anything.
+/// Installing is left to `install`, and to `start_service` when connecting.
+pub unsafe fn preflight(protocol: &Protocol) -> Result<(), PreflightError> {
+    let service = open_service(protocol)?;
+    let _ = CloseServiceHandle(service);
 
-lazy_static! {
-    static ref HOME: PathBuf = home::home_dir().unwrap();
+    Ok(())
 }
 
-#[derive(Debug)]
-pub enum PreflightError {
-    ServiceNotInstalled,
-    FilesMissing,
-    InvalidHash,
+/// Opens the installed service, the handle has to be closed after.
+unsafe fn open_service(protocol: &Protocol) -> Result<SC_HANDLE, PreflightError> {
+    let sc_manager = OpenSCManagerW(
+        None,
+        None,
+        SC_MANAGER_ALL_ACCESS
+    ).map_err(|_| PreflightError::ServiceNotInstalled)?;
+
+    let service_name = HSTRING::from(protocol.service_name());
+    let service = OpenServiceW(sc_manager, &service_name, SERVICE_ALL_ACCESS);
+
+    let _ = CloseServiceHandle(sc_manager);
+    service.map_err(|_| PreflightError::ServiceNotInstalled)
 }
 
-/// Performs configuration checks and ensures program
-/// has everything it needs to correctly start.
-pub unsafe fn preflight(protocol: &Protocol) -> Result<SC_HANDLE, PreflightError> {
+/// Makes sure the program has everything it needs to correctly start,
+/// installing the service if it's missing.
+unsafe fn prepare(protocol: &Protocol) -> Result<SC_HANDLE, PreflightError> {
     are_files_present(protocol)?;
     if protocol == &Protocol::OpenVPN {
         // Wireguard does not use the registry
         is_registry_set()?;
     }
-    let service = is_service_installed(protocol)?;
+    let service = ensure_service(protocol)?;
 
     Ok(service)
 }
 
-/// Checks if the OpenVPN service is installed.
+/// Opens the service, installing it if needed, and points it at the current files.
 /// Requires admin privileges.
-unsafe fn is_service_installed(protocol: &Protocol) -> Result<SC_HANDLE, PreflightError> {
-    let sc_manager = OpenSCManagerW(
-        None, 
-        None,
-        SC_MANAGER_ALL_ACCESS
-    ).map_err(|_| PreflightError::ServiceNotInstalled)?;
+unsafe fn ensure_service(protocol: &Protocol) -> Result<SC_HANDLE, PreflightError> {
+    let service = match open_service(protocol) {
+        Ok(service) => service,
+        Err(_) => install_service(protocol)?
+    };
 
-    let service_name = HSTRING::from(protocol.service_name());
-    
-    let service = match OpenServiceW(
-        sc_manager,
-        &service_name,
-        SERVICE_ALL_ACCESS
-    ) {
-        Ok(service) => Ok(service),
-        Err(_) => match install_service(protocol) {
-            Ok(service) => Ok(service),
-            Err(e) => Err(e)
-        }
-    }.map_err(|_| PreflightError::ServiceNotInstalled)?;
+    if let Err(e) = configure_service(service, protocol) {
+        let _ = CloseServiceHandle(service);
+        return Err(e);
+    }
+
+    Ok(service)
+}
 
-    // Set to startup MANUAL
+/// Command line the service runs, WireGuard's is given the config to bring up.
+fn binary_path(protocol: &Protocol) -> String {
     let home = HOME.to_str().unwrap();
 
-    let mut protocol_dir = format!("{}/.octovpn/{}/{}", home, protocol.to_string().to_lowercase(), protocol.executable());
+    let mut path = format!("{}/.octovpn/{}/{}", home, protocol.to_string().to_lowercase(), protocol.executable());
     if protocol == &Protocol::WireGuard {
-        protocol_dir += format!(" /service {}/.octovpn/wireguard/wireguard.conf", home).as_str();
+        path += format!(" /service {}/.octovpn/wireguard/wireguard.conf", home).as_str();
     }
-    let protocol_dir = HSTRING::from(protocol_dir);
 
-    let display_name = HSTRING::from(format!("OctoVPN Service ({})", protocol.to_string()));
+    path
+}
+
+fn display_name(protocol: &Protocol) -> String {
+    format!("OctoVPN Service ({})", protocol)
+}
 
+/// Points an installed service at the current files and startup type.
+unsafe fn configure_service(service: SC_HANDLE, protocol: &Protocol) -> Result<(), PreflightError> {
+    // Set to startup MANUAL, or AUTOMATIC to start on boot
     ChangeServiceConfigW(
         service,
         SERVICE_NO_CHANGE,
-        SERVICE_DEMAND_START,
+        start_type(protocol),
         SERVICE_ERROR_NORMAL,
-        &protocol_dir,
+        &HSTRING::from(binary_path(protocol)),
         None,
         None,
         None,
         None,
         None,
-        &display_name,
-    ).map_err(|_| PreflightError::ServiceNotInstalled)?;
+        &HSTRING::from(display_name(protocol)),
+    ).map_err(|_| PreflightError::ServiceNotInstalled)
+}
 
-    Ok(service)
+/// Only the selected protocol's service starts with the system.
+fn start_type(protocol: &Protocol) -> SERVICE_START_TYPE {
+    let configuration = CONFIGURATION.lock().unwrap();
+
+    match configuration.auto_connect.boot && configuration.protocol == *protocol {
+        true => SERVICE_AUTO_START,
+        false => SERVICE_DEMAND_START
+    }
+}
+
+/// Changes the service's startup type to follow the start on boot option.
+/// A service that isn't installed yet gets it once connecting installs it.
+pub unsafe fn set_start_on_boot(protocol: &Protocol) -> bool {
+    let service = match open_service(protocol) {
+        Ok(service) => service,
+        Err(_) => return true
+    };
+
+    let result = ChangeServiceConfigW(
+        service,
+        SERVICE_NO_CHANGE,
+        start_type(protocol),
+        SERVICE_ERROR_NORMAL,
+        None,
+        None,
+        None,
+        None,
+        None,
+        None,
+        None,
+    );
+
+    let _ = CloseServiceHandle(service);
+    result.is_ok()
 }
 
 unsafe fn ensure_reg_key(key: &str, value: String) -> bool {
@@ -151,33 +197,25 @@ unsafe fn install_service(protocol: &Protocol) -> Result<SC_HANDLE, PreflightErr
         SC_MANAGER_ALL_ACCESS
     ).map_err(|_| PreflightError::ServiceNotInstalled)?;
 
-    let home = HOME.to_str().unwrap();
-
-    let mut protocol_dir = format!("{}/.octovpn/{}/{}", home, protocol.to_string().to_lowercase(), protocol.executable());
-    if protocol == &Protocol::WireGuard {
-        protocol_dir += format!(" /service {}/.octovpn/wireguard/wireguard.conf", home).as_str();
-    }
-    let protocol_dir = HSTRING::from(protocol_dir);
-
-    let service_name = HSTRING::from(protocol.service_name());
-    let display_name = HSTRING::from(format!("OctoVPN Service ({})", protocol.to_string()));
-
     // Create service
     let service = CreateServiceW(
         sc_manager,
-        &service_name,
-        &display_name,
+        &HSTRING::from(protocol.service_name()),
+        &HSTRING::from(display_name(protocol)),
         SERVICE_ALL_ACCESS,
         SERVICE_WIN32_OWN_PROCESS,
-        SERVICE_DEMAND_START,
+        start_type(protocol),
         SERVICE_ERROR_NORMAL,
-        &protocol_dir,
+        &HSTRING::from(binary_path(protocol)),
         None,
         None,
         None,
         None,
         None
-    ).map_err(|_| PreflightError::ServiceNotInstalled)?;
+    );
+
+    let _ = CloseServiceHandle(sc_manager);
+    let service = service.map_err(|_| PreflightError::ServiceNotInstalled)?;
 
     // WireGuard requires the unrestricted SID type
     if protocol == &Protocol::WireGuard {
@@ -185,11 +223,14 @@ unsafe fn install_service(protocol: &Protocol) -> Result<SC_HANDLE, PreflightErr
             dwServiceSidType: SERVICE_SID_TYPE_UNRESTRICTED
         };
 
-        ChangeServiceConfig2W(
+        if ChangeServiceConfig2W(
             service,
             SERVICE_CONFIG_SERVICE_SID_INFO, // SERVICE_CONFIG_SERVICE_SID_INFO
             Some(&info as *const _ as *const u8 as *const _)
-        ).map_err(|_| PreflightError::ServiceNotInstalled)?;
+        ).is_err() {
+            let _ = CloseServiceHandle(service);
+            return Err(PreflightError::ServiceNotInstalled);
+        }
     }
 
     Ok(service)
@@ -286,21 +327,23 @@ fn download(client: &Client, protocol: &Protocol, name: &str, home: &String) ->
 }
 
 pub unsafe fn is_service_started(protocol: &Protocol) -> bool {
-    let service = preflight(protocol);
+    let service = open_service(protocol);
     if service.is_err() {
         log::error!("Failed to check service: {:?}", service);
         return false;
     }
 
+    let service = service.unwrap();
     let mut status: SERVICE_STATUS = std::mem::zeroed();
-    let status = QueryServiceStatus(service.unwrap(), &mut status).is_ok() && status.dwCurrentState == SERVICE_STOPPED;
+    let status = QueryServiceStatus(service, &mut status).is_ok() && status.dwCurrentState == SERVICE_STOPPED;
 
+    let _ = CloseServiceHandle(service);
     !status
 }
 
 pub unsafe fn start_service(protocol: &Protocol) -> bool {
-    // Ensure everything is set up correctly
-    let service = preflight(protocol);
+    // Ensure everything is set up correctly, connecting installs the service if needed
+    let service = prepare(protocol);
     if service.is_err() {
         log::error!("Failed preflight check: {:?}", service);
         return false;
@@ -309,12 +352,15 @@ pub unsafe fn start_service(protocol: &Protocol) -> bool {
     let service = service.unwrap();
     if StartServiceW(service, None).is_err() {
         log::error!("Failed to start service");
+        let _ = CloseServiceHandle(service);
         return false;
     }
 
     // Check if service is running
     let mut status: SERVICE_STATUS = SERVICE_STATUS::default();
-    if QueryServiceStatus(service, &mut status).is_err() {
+    let queried = QueryServiceStatus(service, &mut status);
+    let _ = CloseServiceHandle(service);
+    if queried.is_err() {
         log::error!("Failed to query service status");
         return false;
     }
@@ -328,7 +374,7 @@ pub unsafe fn start_service(protocol: &Protocol) -> bool {
 
 pub unsafe fn stop_service(protocol: &Protocol) -> bool {
     // Ensure everything is set up correctly
-    let service = preflight(protocol);
+    let service = open_service(protocol);
     if service.is_err() {
         log::error!("Failed preflight check: {:?}", service);
         return false;
@@ -336,7 +382,9 @@ pub unsafe fn stop_service(protocol: &Protocol) -> bool {
 
     let service = service.unwrap();
     let mut status = SERVICE_STATUS::default();
-    if ControlService(service, SERVICE_CONTROL_STOP, &mut status).is_err() {
+    let stopped = ControlService(service, SERVICE_CONTROL_STOP, &mut status);
+    let _ = CloseServiceHandle(service);
+    if stopped.is_err() {
         return false;
     }
 
@@ -347,43 +395,182 @@ pub unsafe fn stop_service(protocol: &Protocol) -> bool {
     status
 }
 
-/// Rewrites the config file with necessary additions.
-/// This should've been done API-side, but it is what it is.
-pub fn patch_config(protocol: &Protocol, mut config: String, credentials: Credentials) -> bool {
-    let path = format!("{}/.octovpn/{}/{}", HOME.to_str().unwrap(), protocol.to_string().to_lowercase(), if protocol == &Protocol::OpenVPN { "config.ovpn" } else { "wireguard.conf" });
+fn wireguard_exe() -> String {
+    format!("{}/.octovpn/wireguard/{}", HOME.to_str().unwrap(), Protocol::WireGuard.executable())
+}
+
+/// Brings up the multi-hop entry as a tunnel service of WireGuard's own, next to ours.
+pub unsafe fn start_hop(config: &str) -> bool {
+    // Makes sure wireguard.exe is there
+    if let Err(e) = are_files_present(&Protocol::WireGuard) {
+        log::error!("Failed preflight check: {:?}", e);
+        return false;
+    }
+
+    // Left over from a connection that dropped, it can't be installed twice
+    if is_hop_started() {
+        stop_hop();
+    }
+
+    std::process::Command::new(wireguard_exe())
+        .args(["/installtunnelservice", config])
+        .status()
+        .is_ok_and(|status| status.success())
+}
+
+/// Stops and removes the tunnel service.
+pub unsafe fn stop_hop() -> bool {
+    std::process::Command::new(wireguard_exe())
+        .args(["/uninstalltunnelservice", multihop::TUNNEL])
+        .status()
+        .is_ok_and(|status| status.success())
+}
+
+pub unsafe fn is_hop_started() -> bool {
+    let sc_manager = match OpenSCManagerW(None, None, SC_MANAGER_ALL_ACCESS) {
+        Ok(sc_manager) => sc_manager,
+        Err(_) => return false
+    };
+
+    let service_name = HSTRING::from(format!("WireGuardTunnel${}", multihop::TUNNEL));
+    let service = match OpenServiceW(sc_manager, &service_name, SERVICE_ALL_ACCESS) {
+        Ok(service) => service,
+        Err(_) => return false
+    };
+
+    let mut status = SERVICE_STATUS::default();
+    let started = QueryServiceStatus(service, &mut status).is_ok() && status.dwCurrentState != SERVICE_STOPPED;
+
+    let _ = CloseServiceHandle(service);
+    started
+}
+
+/// Installs the service for the given protocol, along with the
+/// files and registry keys it needs.
+/// Requires admin privileges.
+pub unsafe fn install(protocol: &Protocol) -> Result<ServiceReport, PreflightError> {
+    let mut report = ServiceReport::new(*protocol);
+
+    are_files_present(protocol)?;
+    report.push(format!("Verified files in ~/.octovpn/{}", protocol.to_string().to_lowercase()));
 
     if protocol == &Protocol::OpenVPN {
-        // Patch config with credentials
-        config.push_str(format!("\n\n<auth-user-pass>\n{}\n{}\n</auth-user-pass>", credentials.username, credentials.password).as_str());
+        is_registry_set()?;
+        report.push("Set registry key HKLM\\Software\\OctoVPN");
+    }
 
-        // Windows-specific fix: remove these parameters as they break the CLI, for some reason
-        let parameters = vec!["route-method exe", "route-delay 2", "register-dns"];
-        for param in parameters {
-            config = config.replace(param, format!("# {}", param).as_str());
+    match open_service(protocol) {
+        Ok(service) => {
+            // Moved home or changed boot option, its binary path may be stale
+            let configured = configure_service(service, protocol);
+            let _ = CloseServiceHandle(service);
+            configured?;
+
+            report.push(format!("Service {} is already installed, updated its configuration", protocol.service_name()));
+        },
+        Err(_) => {
+            let service = install_service(protocol)?;
+            let _ = CloseServiceHandle(service);
+            report.push(format!("Created service {}", protocol.service_name()));
         }
-
-        // Add management interface and reduce verbosity
-        config.push_str("\n\nmanagement localhost 7505\nverb 2");
     }
 
-    std::fs::write(path, config).is_ok()
+    Ok(report)
 }
 
-pub fn write_config(config: &Configuration) {
-    let home = HOME.to_str().unwrap();
-    let path = format!("{}/.octovpn/config.json", home);
+/// How long a service gets to stop, or to go away once deleted.
+const SERVICE_TIMEOUT: Duration = Duration::from_secs(10);
 
-    std::fs::write(path, serde_json::to_string(config).unwrap()).unwrap();
+/// Polls `done` until it's true, giving up after `SERVICE_TIMEOUT`.
+fn wait_for(mut done: impl FnMut() -> bool) -> bool {
+    let start = Instant::now();
+
+    while !done() {
+        if start.elapsed() > SERVICE_TIMEOUT {
+            return false;
+        }
+        std::thread::sleep(Duration::from_millis(250));
+    }
+
+    true
 }
 
-pub fn read_config() -> Configuration {
-    let home = HOME.to_str().unwrap();
-    let path = format!("{}/.octovpn/config.json", home);
+/// Stops and removes the service for the given protocol.
+/// For OpenVPN, the `Software\OctoVPN` registry key is removed as well.
+/// Requires admin privileges.
+pub unsafe fn uninstall(protocol: &Protocol) -> Result<ServiceReport, PreflightError> {
+    let mut report = ServiceReport::new(*protocol);
+
+    let sc_manager = OpenSCManagerW(
+        None,
+        None,
+        SC_MANAGER_ALL_ACCESS
+    ).map_err(|_| PreflightError::ServiceNotRemoved)?;
+
+    let service_name = HSTRING::from(protocol.service_name());
+
+    let service = OpenServiceW(sc_manager, &service_name, SERVICE_ALL_ACCESS);
+    let _ = CloseServiceHandle(sc_manager);
+
+    match service {
+        Ok(service) => {
+            let mut status = SERVICE_STATUS::default();
+            let stopping = ControlService(service, SERVICE_CONTROL_STOP, &mut status).is_ok();
+
+            // Deleting a running service only marks it, and creating it again fails until it stops
+            let stopped = wait_for(|| QueryServiceStatus(service, &mut status).is_ok() && status.dwCurrentState == SERVICE_STOPPED);
+            if !stopped {
+                let _ = CloseServiceHandle(service);
+                log::error!("Service {} didn't stop in time", protocol.service_name());
+                return Err(PreflightError::ServiceNotRemoved);
+            }
+
+            if stopping {
+                *CONNECTED.lock().unwrap() = false;
+                report.push(format!("Stopped service {}", protocol.service_name()));
+            }
+
+            // The service is only removed once every handle to it is closed
+            let deleted = DeleteService(service);
+            let _ = CloseServiceHandle(service);
+            deleted.map_err(|_| PreflightError::ServiceNotRemoved)?;
+
+            let removed = wait_for(|| match open_service(protocol) {
+                Ok(service) => {
+                    let _ = CloseServiceHandle(service);
+                    false
+                },
+                Err(_) => true
+            });
+            if !removed {
+                log::error!("Service {} is still open elsewhere, it's removed once that's closed", protocol.service_name());
+                return Err(PreflightError::ServiceNotRemoved);
+            }
+
+            report.push(format!("Deleted service {}", protocol.service_name()));
+        },
+        Err(_) => report.push(format!("Service {} is not installed", protocol.service_name()))
+    }
 
-    if !std::path::Path::new(&path).exists() {
-        return Configuration::default();
+    // Wireguard does not use the registry
+    if protocol == &Protocol::OpenVPN {
+        if RegDeleteTreeW(HKEY_LOCAL_MACHINE, w!("Software\\OctoVPN")).is_ok() {
+            RegDeleteKeyW(HKEY_LOCAL_MACHINE, w!("Software\\OctoVPN"))
+                .map_err(|_| PreflightError::ServiceNotRemoved)?;
+            report.push("Removed registry key HKLM\\Software\\OctoVPN");
+        } else {
+            report.push("Registry key HKLM\\Software\\OctoVPN is not present");
+        }
     }
 
-    let config = std::fs::read_to_string(path).unwrap();
-    serde_json::from_str(config.as_str()).unwrap()
-}
\ No newline at end of file
+    Ok(report)
+}
+
+/// Reinstalls the service for the given protocol from scratch.
+/// Requires admin privileges.
+pub unsafe fn repair(protocol: &Protocol) -> Result<ServiceReport, PreflightError> {
+    let mut report = uninstall(protocol)?;
+    report.extend(install(protocol)?);
+
+    Ok(report)
+}
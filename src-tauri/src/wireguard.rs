@@ -0,0 +1,232 @@
+use std::{net::ToSocketAddrs, str::FromStr};
+
+use base64::{Engine, engine::general_purpose::STANDARD};
+use serde::{Serialize, Deserialize};
+
+use crate::{Protocol, config::config_path, secret::Secret, uapi::{self, UapiError}};
+
+/// A parsed `wg-quick` style config.
+#[derive(Debug, Clone)]
+pub struct WireGuardConfig {
+    pub interface: Interface,
+    pub peers: Vec<Peer>
+}
+
+#[derive(Debug, Clone)]
+pub struct Interface {
+    pub private_key: Secret<[u8; 32]>,
+    /// Tunnel addresses, in CIDR notation
+    pub addresses: Vec<String>,
+    pub dns: Vec<String>,
+    pub mtu: Option<u16>
+}
+
+#[derive(Debug, Clone)]
+pub struct Peer {
+    pub public_key: [u8; 32],
+    pub preshared_key: Option<Secret<[u8; 32]>>,
+    pub endpoint: Option<String>,
+    pub allowed_ips: Vec<String>,
+    pub persistent_keepalive: Option<u16>
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize)]
+pub enum ParseError {
+    MissingInterface,
+    MissingKey,
+    InvalidKey,
+    InvalidValue,
+}
+
+/// Live status of a peer, as reported by the running tunnel.
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+pub struct PeerStats {
+    pub public_key: String,
+    pub endpoint: Option<String>,
+    /// Unix timestamp of the latest handshake, `None` if there never was one
+    pub last_handshake: Option<u64>,
+    pub rx_bytes: u64,
+    pub tx_bytes: u64
+}
+
+fn parse_key(value: &str) -> Result<[u8; 32], ParseError> {
+    STANDARD.decode(value)
+        .ok()
+        .and_then(|key| key.try_into().ok())
+        .ok_or(ParseError::InvalidKey)
+}
+
+fn parse_list(value: &str) -> Vec<String> {
+    value.split(',')
+        .map(|item| item.trim().to_string())
+        .filter(|item| !item.is_empty())
+        .collect()
+}
+
+impl FromStr for WireGuardConfig {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut interface: Option<Interface> = None;
+        let mut peers: Vec<Peer> = vec![];
+        let mut section = "";
+
+        for line in s.lines() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            if line.starts_with('[') {
+                section = line;
+
+                match section {
+                    "[Interface]" => interface = Some(Interface {
+                        private_key: Secret::new([0; 32]),
+                        addresses: vec![],
+                        dns: vec![],
+                        mtu: None
+                    }),
+                    "[Peer]" => peers.push(Peer {
+                        public_key: [0; 32],
+                        preshared_key: None,
+                        endpoint: None,
+                        allowed_ips: vec![],
+                        persistent_keepalive: None
+                    }),
+                    _ => {}
+                }
+
+                continue;
+            }
+
+            // Keys are base64 and may end in `=`, so only the first one splits
+            let (key, value) = line.split_once('=').ok_or(ParseError::InvalidValue)?;
+            let (key, value) = (key.trim(), value.trim());
+
+            match (section, interface.as_mut(), peers.last_mut()) {
+                ("[Interface]", Some(interface), _) => match key {
+                    "PrivateKey" => interface.private_key = Secret::new(parse_key(value)?),
+                    "Address" => interface.addresses.extend(parse_list(value)),
+                    "DNS" => interface.dns.extend(parse_list(value)),
+                    "MTU" => interface.mtu = Some(value.parse().map_err(|_| ParseError::InvalidValue)?),
+                    // ListenPort, Table, PostUp, ... only matter to wg-quick
+                    _ => {}
+                },
+                ("[Peer]", _, Some(peer)) => match key {
+                    "PublicKey" => peer.public_key = parse_key(value)?,
+                    "PresharedKey" => peer.preshared_key = Some(Secret::new(parse_key(value)?)),
+                    "Endpoint" => peer.endpoint = Some(value.to_string()),
+                    "AllowedIPs" => peer.allowed_ips.extend(parse_list(value)),
+                    "PersistentKeepalive" => peer.persistent_keepalive = match value {
+                        "off" => None,
+                        value => Some(value.parse().map_err(|_| ParseError::InvalidValue)?)
+                    },
+                    _ => {}
+                },
+                _ => {}
+            }
+        }
+
+        let interface = interface.ok_or(ParseError::MissingInterface)?;
+        if interface.private_key.expose() == &[0; 32] || peers.iter().any(|peer| peer.public_key == [0; 32]) {
+            return Err(ParseError::MissingKey);
+        }
+
+        Ok(WireGuardConfig { interface, peers })
+    }
+}
+
+impl WireGuardConfig {
+    /// Reads the config written by `patch_config`.
+    pub fn load() -> Result<Self, ParseError> {
+        std::fs::read_to_string(config_path(&Protocol::WireGuard))
+            .map_err(|_| ParseError::MissingInterface)?
+            .parse()
+    }
+}
+
+/// Resolves the peers' endpoints again and points the running tunnel to them,
+/// the server may have moved, or be reached another way, from a new network.
+pub fn reresolve() -> Result<(), UapiError> {
+    let config = WireGuardConfig::load().map_err(|_| UapiError::NotRunning)?;
+
+    for peer in &config.peers {
+        let endpoint = peer.endpoint.as_ref()
+            .and_then(|endpoint| endpoint.to_socket_addrs().ok())
+            .and_then(|mut addresses| addresses.next());
+
+        match endpoint {
+            Some(endpoint) => uapi::set_endpoint(&peer.public_key, endpoint)?,
+            None => log::warn!("Could not resolve the WireGuard endpoint {:?}", peer.endpoint)
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(byte: u8) -> String {
+        STANDARD.encode([byte; 32])
+    }
+
+    fn config() -> String {
+        format!(
+            "[Interface]\nPrivateKey = {}\nAddress = 10.64.0.2/32, fd00::2/128\nDNS = 10.64.0.1\nMTU = 1420\nPostUp = echo up # wg-quick only\n\n\
+            [Peer]\nPublicKey = {}\nPresharedKey = {}\nEndpoint = 198.51.100.7:51820\nAllowedIPs = 0.0.0.0/0, ::/0\nPersistentKeepalive = 25\n\n\
+            [Peer]\n# Backup\nPublicKey = {}\nEndpoint = [2001:db8::7]:51820\nAllowedIPs = 10.0.0.0/8\nPersistentKeepalive = off\n",
+            key(1), key(2), key(3), key(4)
+        )
+    }
+
+    #[test]
+    fn parses_interface_and_peers() {
+        let config = config().parse::<WireGuardConfig>().unwrap();
+
+        assert_eq!(config.interface.private_key.expose(), &[1; 32]);
+        assert_eq!(config.interface.addresses, vec!["10.64.0.2/32", "fd00::2/128"]);
+        assert_eq!(config.interface.dns, vec!["10.64.0.1"]);
+        assert_eq!(config.interface.mtu, Some(1420));
+
+        assert_eq!(config.peers.len(), 2);
+        assert_eq!(config.peers[0].public_key, [2; 32]);
+        assert_eq!(config.peers[0].preshared_key.as_ref().map(|key| *key.expose()), Some([3; 32]));
+        assert_eq!(config.peers[0].endpoint.as_deref(), Some("198.51.100.7:51820"));
+        assert_eq!(config.peers[0].allowed_ips, vec!["0.0.0.0/0", "::/0"]);
+        assert_eq!(config.peers[0].persistent_keepalive, Some(25));
+
+        assert_eq!(config.peers[1].public_key, [4; 32]);
+        assert!(config.peers[1].preshared_key.is_none());
+        assert_eq!(config.peers[1].endpoint.as_deref(), Some("[2001:db8::7]:51820"));
+        assert_eq!(config.peers[1].persistent_keepalive, None);
+    }
+
+    #[test]
+    fn rejects_malformed_keys() {
+        let config = config();
+
+        // Not base64, and not 32 bytes
+        let invalid = config.replace(&key(2), "not a key!");
+        assert_eq!(invalid.parse::<WireGuardConfig>().unwrap_err(), ParseError::InvalidKey);
+        let short = config.replace(&key(1), &STANDARD.encode([1; 16]));
+        assert_eq!(short.parse::<WireGuardConfig>().unwrap_err(), ParseError::InvalidKey);
+
+        let missing = config.replace(&format!("PublicKey = {}\n", key(4)), "");
+        assert_eq!(missing.parse::<WireGuardConfig>().unwrap_err(), ParseError::MissingKey);
+        let missing = config.replace(&format!("PrivateKey = {}\n", key(1)), "");
+        assert_eq!(missing.parse::<WireGuardConfig>().unwrap_err(), ParseError::MissingKey);
+    }
+
+    #[test]
+    fn rejects_malformed_configs() {
+        let config = config();
+
+        assert_eq!(config.replace("[Interface]", "[Unknown]").parse::<WireGuardConfig>().unwrap_err(), ParseError::MissingInterface);
+        assert_eq!(config.replace("MTU = 1420", "MTU = large").parse::<WireGuardConfig>().unwrap_err(), ParseError::InvalidValue);
+        assert_eq!(config.replace("MTU = 1420", "MTU").parse::<WireGuardConfig>().unwrap_err(), ParseError::InvalidValue);
+        assert_eq!(config.replace("= 25", "= often").parse::<WireGuardConfig>().unwrap_err(), ParseError::InvalidValue);
+    }
+}
@@ -1,3 +1,4 @@
+import { invoke } from '@tauri-apps/api';
 import { Body, getClient } from '@tauri-apps/api/http';
 import {
     APIResponse,
@@ -9,7 +10,33 @@ import {
 } from './structs';
 import type { Credentials } from '../structs';
 
-const BASE_URL = 'https://617069.6f63746f76706e.com:8443/api/v1';
+let baseUrl: Promise<string> | undefined;
+
+/// The API's address, as the core knows it.
+/// @return The address, without a trailing slash.
+function apiUrl(): Promise<string> {
+    if (!baseUrl) {
+        baseUrl = invoke<string>('api_url');
+    }
+    return baseUrl;
+}
+
+/// Runs a request with a valid token from the core's session, renewing it once if the API refused it.
+/// @param request The request to run with the token.
+/// @return The request's response.
+export async function withSession<T>(request: (token: string) => Promise<APIResponse<T>>): Promise<APIResponse<T>> {
+    let token = await invoke<string>('session_token');
+    let response = await request(token);
+
+    if (!response.success && (response.error?.code == 401 || response.error?.code == 403)) {
+        let renewed = await invoke<string>('session_rejected', { token }).catch(() => token);
+        if (renewed != token) {
+            response = await request(renewed);
+        }
+    }
+
+    return response;
+}
 
 /// Logs in a user with the specified username and password.
 /// @param email The email address of the user.
@@ -18,7 +45,7 @@ const BASE_URL = 'https://617069.6f63746f76706e.com:8443/api/v1';
 export async function login(credentials: Credentials): Promise<APIResponse<LoginResponse>> {
     let client = await getClient();
     const response = await client.post<APIResponse<LoginResponse>>(
-        `${BASE_URL}/account/login`,
+        `${await apiUrl()}/account/login`,
         Body.json({ username: credentials.username, password: credentials.password }),
     );
     return response.data;
@@ -31,7 +58,7 @@ export async function login(credentials: Credentials): Promise<APIResponse<Login
 export async function logout(token: string): Promise<APIResponse<LogoutResponse>> {
     let client = await getClient();
     const response = await client.post<APIResponse<LogoutResponse>>(
-        `${BASE_URL}/account/logout`,
+        `${await apiUrl()}/account/logout`,
         Body.json({ token }),
     );
     return response.data;
@@ -42,7 +69,7 @@ export async function logout(token: string): Promise<APIResponse<LogoutResponse>
 /// @return The message returned by the server.
 export async function account(token: string): Promise<APIResponse<Account>> {
     let client = await getClient();
-    const response = await client.get<APIResponse<Account>>(`${BASE_URL}/account`, {
+    const response = await client.get<APIResponse<Account>>(`${await apiUrl()}/account`, {
         headers: {
             Authorization: `Bearer ${token}`,
         },
@@ -55,7 +82,7 @@ export async function account(token: string): Promise<APIResponse<Account>> {
 /// @return The list of locations.
 export async function locations(token: string): Promise<APIResponse<Location[]>> {
     let client = await getClient();
-    const response = await client.get<APIResponse<Location[]>>(`${BASE_URL}/locations`, {
+    const response = await client.get<APIResponse<Location[]>>(`${await apiUrl()}/locations`, {
         headers: {
             Authorization: `${token}`,
         },
@@ -75,7 +102,7 @@ export async function openvpn(
 ): Promise<APIResponse<ConfigResponse>> {
     let client = await getClient();
     const response = await client.get<APIResponse<ConfigResponse>>(
-        `${BASE_URL}/configs/openvpn/${location.id}/${protocol}`,
+        `${await apiUrl()}/configs/openvpn/${location.id}/${protocol}`,
         {
             headers: {
                 Authorization: `${token}`,
@@ -96,7 +123,7 @@ export async function wireguard(
     let client = await getClient();
 
     const response = await client.get<APIResponse<ConfigResponse>>(
-        `${BASE_URL}/configs/wireguard/${location.id}`,
+        `${await apiUrl()}/configs/wireguard/${location.id}`,
         {
             headers: {
                 Authorization: `${token}`,
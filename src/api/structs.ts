@@ -8,6 +8,8 @@ export class Location {
     hasWireGuardConfig: string;
     config: string;
     country_code: string;
+    /// Id of the imported config, if this isn't an API location
+    custom?: string;
 
     constructor(
         id: string,
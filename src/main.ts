@@ -1,18 +1,25 @@
 import './styles.css';
 import App from './App.svelte';
 import { invoke } from '@tauri-apps/api';
-import { Config, Credentials, Status, Protocol, OpenVPNStatus } from './structs';
+import { Config, Status, Protocol, OpenVPNStatus, WireGuardStatus, Diagnostic, VerifiedExit, Ipv6Mode, Hops } from './structs';
 import {
     authToken,
     locations as storeLocations,
+    location as storeLocation,
     config,
     account as storeAccount,
     status,
     ovpn_status,
+    wg_status,
+    diagnostic,
+    verifiedExit,
+    ipv6Status,
+    hopStatus,
     loggingIn,
     loggedIn,
 } from './stores';
-import { account, locations, login } from './api/client';
+import { account } from './api/client';
+import type { Location } from './api/structs';
 import { listen } from '@tauri-apps/api/event';
 import { get } from 'svelte/store';
 import { LogicalSize, appWindow } from '@tauri-apps/api/window';
@@ -30,9 +37,18 @@ loggedIn.subscribe((loggedIn) => {
 authToken.subscribe(async (token) => {
     if (!token) { return; }
 
-    let locs = await locations(token!);
-    if (locs.success) {
-        storeLocations.set(locs.data!);
+    // Fetched and cached by the core, falling back to the cached ones
+    let locs = await invoke<Location[]>('query_locations', { filter: null, refresh: true }).catch(() => null);
+    if (locs) {
+        let custom = await invoke<Location[]>('custom_locations');
+        storeLocations.set(locs.concat(custom));
+
+        // Restore the last selected location
+        let saved = get(config)?.location;
+        let selected = locs.find((loc) => Number(loc.id) == saved);
+        if (selected && !get(storeLocation)) {
+            storeLocation.set(selected);
+        }
     }
 
     let acc = await account(token!);
@@ -40,8 +56,25 @@ authToken.subscribe(async (token) => {
         storeAccount.set(acc.data!);
     }
 
-    loggedIn.set(acc.success && locs.success);
+    loggedIn.set(acc.success && locs != null);
     loggingIn.set(false);
+
+    // The locations are loaded, so auto-connect can find where to connect to
+    if (acc.success && locs) {
+        invoke('auto_connect');
+    }
+});
+
+/// Remembers the selected API location across restarts.
+storeLocation.subscribe((location) => {
+    if (location && !location.custom) {
+        config.update((value) => {
+            if (value) {
+                value.location = Number(location.id);
+            }
+            return value;
+        });
+    }
 });
 
 status.subscribe((status) => {
@@ -55,12 +88,12 @@ invoke<Config>('load_config').then(async (conf) => {
     if (conf.credentials) {
         loggingIn.set(true);
 
-        let creds = new Credentials(conf.credentials.username, conf.credentials.password);
-
-        // TODO: insert something to handle "logging in" phase
-        let log = await login(creds);
-        if (log.success) {
-            authToken.set(log.data!.token);
+        // The core logs in with the stored credentials
+        let token = await invoke<string>('session_token').catch(() => null);
+        if (token) {
+            authToken.set(token);
+        } else {
+            loggingIn.set(false);
         }
     }
 });
@@ -76,10 +109,9 @@ config.subscribe(async (config) => {
     let token = get(authToken);
 
     if (token) {
-        locations(token).then((locs) => {
-            if (locs.success) {
-                storeLocations.set(locs.data!);
-            }
+        invoke<Location[]>('query_locations', { filter: null, refresh: false }).then(async (locs) => {
+            let custom = await invoke<Location[]>('custom_locations');
+            storeLocations.set(locs.concat(custom));
         });
     }
 
@@ -94,17 +126,13 @@ config.subscribe(async (config) => {
 
 /// Listens for status events
 listen<Status>('status', (message) => {
-    // If WireGuard, wait a little for it to launch to prevent spam
-    if (get(config).protocol == Protocol.WireGuard) {
-        if (message.payload == Status.CONNECTED || message.payload == Status.DISCONNECTED) {
-            setTimeout(() => {
-                status.set(message.payload);
-            }, 1000);
-            return;
-        }
-    }
-
     status.set(message.payload);
+
+    // A new attempt, the previous failure and exit no longer apply
+    if (message.payload == Status.CONNECTING) {
+        diagnostic.set(null);
+        verifiedExit.set(null);
+    }
 });
 
 /// Listens for OpenVPN status events
@@ -112,6 +140,48 @@ listen<OpenVPNStatus>('openvpn_status', (message) => {
     ovpn_status.set(message.payload);
 });
 
+/// Listens for WireGuard status events
+listen<WireGuardStatus>('wireguard_status', (message) => {
+    wg_status.set(message.payload);
+});
+
+/// Listens for config changes made from the tray
+listen<Config>('config', (message) => {
+    config.set(message.payload);
+});
+
+/// Listens for tokens the core renewed
+listen<string>('session', (message) => {
+    authToken.set(message.payload);
+});
+
+/// Listens for the stored credentials being refused, they have to be entered again
+listen('session_expired', () => {
+    loggedIn.set(false);
+    loggingIn.set(false);
+    authToken.set(null);
+});
+
+/// Listens for where traffic leaves, checked once connected
+listen<VerifiedExit>('verified_exit', (message) => {
+    verifiedExit.set(message.payload);
+});
+
+/// Listens for both hops of a multi-hop connection
+listen<Hops | null>('hop_status', (message) => {
+    hopStatus.set(message.payload);
+});
+
+/// Listens for how the connection handles IPv6
+listen<Ipv6Mode | null>('ipv6_status', (message) => {
+    ipv6Status.set(message.payload);
+});
+
+/// Listens for known OpenVPN failures
+listen<Diagnostic>('diagnostic', (message) => {
+    diagnostic.set(message.payload);
+});
+
 const app = new App({
     target: document.getElementById('app')!,
 });
@@ -1,5 +1,5 @@
 import { writable, type Writable } from 'svelte/store';
-import { Config, OpenVPNStatus, Status } from './structs';
+import { Config, Diagnostic, Hops, Ipv6Mode, OpenVPNStatus, Status, VerifiedExit, WireGuardStatus } from './structs';
 import { Account, Location } from './api/structs';
 
 export const page = writable('locations');
@@ -10,6 +10,11 @@ export const config = writable<Config>();
 
 export const status: Writable<Status> = writable(Status.DISCONNECTED);
 export const ovpn_status: Writable<OpenVPNStatus | null> = writable(null);
+export const wg_status: Writable<WireGuardStatus | null> = writable(null);
+export const diagnostic: Writable<Diagnostic | null> = writable(null);
+export const verifiedExit: Writable<VerifiedExit | null> = writable(null);
+export const ipv6Status: Writable<Ipv6Mode | null> = writable(null);
+export const hopStatus: Writable<Hops | null> = writable(null);
 export const loggingIn = writable(false);
 export const loggedIn = writable(false);
 
@@ -57,13 +57,263 @@ export class Config {
     credentials: Credentials | null;
     theme: string;
     discordRPC: boolean;
+    discordPresence: PresenceConfig;
     protocol: Protocol;
+    /// OpenVPN transport for API locations, profiles have their own
+    transport: Transport;
+    /// Overrides the OpenVPN server port
+    port: number | null;
+    location: number | null;
+    profiles: Profile[];
+    /// Locations last connected to, newest first
+    recent: Recent[];
+    /// Ids of favourite API locations
+    favourites: number[];
+    notifications: NotificationConfig;
+    autoConnect: AutoConnect;
+    verification: VerifyConfig;
+    multiHop: MultiHop;
+    mtu: MtuConfig;
+    logLevel: string;
+    logFormat: LogFormat;
+    logMaxSize: number;
+    logRetention: number;
 
     constructor(credentials: Credentials, theme: string, discordRPC: boolean, protocol: Protocol) {
         this.credentials = credentials;
         this.theme = theme;
         this.discordRPC = discordRPC;
+        this.discordPresence = new PresenceConfig();
         this.protocol = protocol;
+        this.transport = Transport.UDP;
+        this.port = null;
+        this.location = null;
+        this.profiles = [];
+        this.recent = [];
+        this.favourites = [];
+        this.notifications = new NotificationConfig();
+        this.autoConnect = new AutoConnect();
+        this.verification = new VerifyConfig();
+        this.multiHop = new MultiHop();
+        this.mtu = new MtuConfig();
+        this.logLevel = 'info';
+        this.logFormat = LogFormat.Text;
+        this.logMaxSize = 5 * 1024 * 1024;
+        this.logRetention = 10;
+    }
+}
+
+export enum Privacy {
+    Full,
+    HideLocation,
+    ProtectedOnly,
+}
+
+/// Discord presence templates, see `PresenceConfig` in `discord.rs` for the placeholders.
+export class PresenceConfig {
+    clientId: string;
+    details: string;
+    state: string;
+    largeText: string;
+    disconnected: string;
+    privacy: Privacy;
+    showElapsed: boolean;
+
+    constructor() {
+        this.clientId = '743953368518492190';
+        this.details = 'Connected to {location}';
+        this.state = 'via {protocol}';
+        this.largeText = '{city}, {country}';
+        this.disconnected = 'Not connected';
+        this.privacy = Privacy.Full;
+        this.showElapsed = true;
+    }
+}
+
+/// Which desktop notifications are shown.
+export class NotificationConfig {
+    connected: boolean;
+    disconnected: boolean;
+    dropped: boolean;
+    reconnected: boolean;
+    failures: boolean;
+
+    constructor() {
+        this.connected = true;
+        this.disconnected = true;
+        this.dropped = true;
+        this.reconnected = true;
+        this.failures = true;
+    }
+}
+
+/// When to connect without being asked, see `AutoConnect` in `network.rs`.
+export class AutoConnect {
+    launch: boolean;
+    boot: boolean;
+    untrusted: boolean;
+    trustedSsids: string[];
+    /// IP or MAC addresses
+    trustedGateways: string[];
+
+    constructor() {
+        this.launch = false;
+        this.boot = false;
+        this.untrusted = false;
+        this.trustedSsids = [];
+        this.trustedGateways = [];
+    }
+}
+
+/// Where traffic leaves is checked after connecting, see `verify.rs`.
+export class VerifyConfig {
+    enabled: boolean;
+    /// Answers with the caller's IP, as JSON or plain text
+    endpoint: string;
+    /// Also asks while disconnected, to flag traffic still leaving from that address
+    compareHome: boolean;
+
+    constructor() {
+        this.enabled = false;
+        this.endpoint = 'https://ipinfo.io/json';
+        this.compareHome = false;
+    }
+}
+
+export class Exit {
+    ip: string;
+    country: string | null;
+
+    constructor(ip: string, country: string | null) {
+        this.ip = ip;
+        this.country = country;
+    }
+}
+
+/// The outcome of the check, `leak` when traffic may not go through the tunnel.
+export class VerifiedExit {
+    exit: Exit | null;
+    expected_ip: string | null;
+    expected_country: string | null;
+    ip_matches: boolean;
+    country_matches: boolean | null;
+    leak: boolean;
+    error: string | null;
+
+    constructor(exit: Exit | null, leak: boolean, error: string | null) {
+        this.exit = exit;
+        this.expected_ip = null;
+        this.expected_country = null;
+        this.ip_matches = false;
+        this.country_matches = null;
+        this.leak = leak;
+        this.error = error;
+    }
+}
+
+/// Traffic enters through a WireGuard tunnel to `entry`, then leaves from the selected location, see `multihop.rs`.
+export class MultiHop {
+    enabled: boolean;
+    /// API location id
+    entry: number | null;
+
+    constructor() {
+        this.enabled = false;
+        this.entry = null;
+    }
+}
+
+/// The tunnel's MTU, see `mtu.rs`.
+export class MtuConfig {
+    /// Probe the path to the server on networks without a known MTU
+    discovery: boolean;
+    /// Network MTU to use instead
+    manual: number | null;
+
+    constructor() {
+        this.discovery = true;
+        this.manual = null;
+    }
+}
+
+/// Where the tunnel goes, as shown to the user.
+export class Destination {
+    name: string;
+    city: string | null;
+    country: string;
+
+    constructor(name: string, city: string | null, country: string) {
+        this.name = name;
+        this.city = city;
+        this.country = country;
+    }
+}
+
+/// Both hops of a multi-hop connection.
+export class Hops {
+    entry: Destination | null;
+    entry_peer: WireGuardStatus | null;
+    exit: Destination | null;
+
+    constructor(entry: Destination | null, entry_peer: WireGuardStatus | null, exit: Destination | null) {
+        this.entry = entry;
+        this.entry_peer = entry_peer;
+        this.exit = exit;
+    }
+}
+
+export enum LogFormat {
+    Text,
+    Json,
+}
+
+export enum Transport {
+    UDP,
+    TCP,
+}
+
+/// What happens to IPv6 traffic while connected.
+export enum Ipv6Mode {
+    Block,
+    Tunnel,
+}
+
+export type ProfileTarget = { type: 'location'; id: number } | { type: 'custom'; id: string };
+
+export class Recent {
+    target: ProfileTarget;
+    name: string;
+
+    constructor(target: ProfileTarget, name: string) {
+        this.target = target;
+        this.name = name;
+    }
+}
+
+export class Profile {
+    id: string;
+    name: string;
+    target: ProfileTarget;
+    protocol: Protocol;
+    transport: Transport;
+    port: number | null;
+    killSwitch: boolean;
+    dns: string[];
+    splitTunnel: string[];
+    /// Left to the server's config when null, as in profiles saved before it could be chosen
+    ipv6: Ipv6Mode | null;
+
+    constructor(name: string, target: ProfileTarget, protocol: Protocol) {
+        this.id = '';
+        this.name = name;
+        this.target = target;
+        this.protocol = protocol;
+        this.transport = Transport.UDP;
+        this.port = null;
+        this.killSwitch = false;
+        this.dns = [];
+        this.splitTunnel = [];
+        this.ipv6 = Ipv6Mode.Block;
     }
 }
 
@@ -93,7 +343,7 @@ export class OpenVPNStatus {
     state: string;
     local_ip: string;
     remote_ip: string;
-    port: number;
+    port: number | null;
 
     constructor(
         start: number,
@@ -101,7 +351,7 @@ export class OpenVPNStatus {
         state: string,
         local_ip: string,
         remote_ip: string,
-        port: number,
+        port: number | null,
     ) {
         this.start = start;
         this.connected = connected;
@@ -122,3 +372,48 @@ export class OpenVPNStatus {
         );
     }
 }
+
+export class WireGuardStatus {
+    public_key: string;
+    endpoint: string | null;
+    last_handshake: number | null;
+    rx_bytes: number;
+    tx_bytes: number;
+
+    constructor(
+        public_key: string,
+        endpoint: string | null,
+        last_handshake: number | null,
+        rx_bytes: number,
+        tx_bytes: number,
+    ) {
+        this.public_key = public_key;
+        this.endpoint = endpoint;
+        this.last_handshake = last_handshake;
+        this.rx_bytes = rx_bytes;
+        this.tx_bytes = tx_bytes;
+    }
+}
+
+export enum DiagnosticCause {
+    AuthFailed,
+    TlsHandshake,
+    TapMissing,
+    CipherMismatch,
+    ResolveFailed,
+}
+
+/// A known OpenVPN failure, found in its log.
+export class Diagnostic {
+    cause: DiagnosticCause;
+    message: string;
+    fix: string;
+    line: string;
+
+    constructor(cause: DiagnosticCause, message: string, fix: string, line: string) {
+        this.cause = cause;
+        this.message = message;
+        this.fix = fix;
+        this.line = line;
+    }
+}